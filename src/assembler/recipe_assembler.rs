@@ -4,7 +4,7 @@ use crate::models::recipe::Recipe;
 
 pub fn to_recipe_dto(recipe: &Recipe) -> RecipeDto {
     RecipeDto {
-        id: recipe.id.clone(),
+        id: recipe.id,
         title: recipe.title.clone(),
         ingredients: recipe.ingredients.clone(),
         instructions: recipe.instructions.clone(),
@@ -15,7 +15,7 @@ pub fn to_recipe_dto(recipe: &Recipe) -> RecipeDto {
 
 pub fn from_recipe_dto(recipe_dto: &RecipeDto) -> Recipe {
     Recipe {
-        id: recipe_dto.id.clone(),
+        id: recipe_dto.id,
         title: recipe_dto.title.clone(),
         ingredients: recipe_dto.ingredients.clone(),
         instructions: recipe_dto.instructions.clone(),
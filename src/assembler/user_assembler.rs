@@ -3,7 +3,7 @@ use crate::dto::user_dto::UserDto;
 
 pub fn to_user_dto(user: &User) -> UserDto {
     UserDto {
-        id: user.id.clone(),
+        id: user.id,
         name: user.name.clone(),
         email: user.email.clone(),
     }
@@ -11,7 +11,7 @@ pub fn to_user_dto(user: &User) -> UserDto {
 
 pub fn from_user_dto(user_dto: &UserDto) -> User {
     User {
-        id: user_dto.id.clone(),
+        id: user_dto.id,
         name: user_dto.name.clone(),
         email: user_dto.email.clone(),
     }
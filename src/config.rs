@@ -0,0 +1,531 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::http::request_reader::RequestLimits;
+use crate::server::connection::{HttpsRedirect, KeepAlive};
+use crate::server::ServerMode;
+use crate::transport::tls::CertificateFiles;
+use crate::transport::{FileMode, ListenOptions};
+
+/// Config file which is used when no other file is given
+const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+/// Prefix of the environment variables, e.g. SERVER_PORT for `port`
+const ENV_PREFIX: &str = "SERVER_";
+
+const USAGE: &str = "Usage: rustWindowsTcpWebserver [OPTIONS]
+
+Options:
+  --config <path>             TOML config file (default: server.toml if present)
+  --listen <addrs>            Comma-separated addresses to listen on, e.g. 0.0.0.0:8080,[::]:8080
+  --address <ip>              Address to listen on, replaces the listen addresses with one
+  --port <port>               Port to listen on, replaces the listen addresses with one
+  --ipv6-only <bool>          Only accept IPv6 clients on IPv6 addresses (default: dual-stack)
+  --tls-listen <addrs>        Comma-separated addresses for HTTPS
+  --tls-cert <path>           PEM certificate chain of the default certificate
+  --tls-key <path>            PEM private key of the default certificate
+  --redirect-http <bool>      Redirect plaintext requests to the first HTTPS address
+  --unix-socket <path>        Also listen on a Unix domain socket at this path
+  --unix-socket-mode <mode>   Octal permissions of the socket file (default: 660)
+  --backlog <n>               Length of the pending connection queue
+  --mode <mode>               threaded or event-loop
+  --workers <n>               Worker threads in threaded mode
+  --queue-depth <n>           Accepted connections waiting for a worker
+  --idle-timeout <secs>       Keep-alive idle timeout
+  --max-requests <n>          Requests per connection
+  --drain-timeout <secs>      Time in-flight requests get on shutdown
+  --header-timeout <secs>     Time a client gets to send the request headers
+  --body-timeout <secs>       Time a client gets to send the request body
+  --handler-timeout <secs>    Time a handler gets before 503 is sent (default: 0, waits forever)
+  --write-timeout <secs>      Time a response write may stall before the connection is closed
+  --max-header-size <bytes>   Maximum size of the request headers
+  --max-body-size <bytes>     Maximum size of the request body
+  --print-config              Print the effective config and exit
+  --help                      Print this help
+
+Every option can also be set in the config file (e.g. max_body_size = 1048576)
+or as environment variable (e.g. SERVER_MAX_BODY_SIZE=1048576).
+The admin token can only be set in the file or as SERVER_ADMIN_TOKEN.
+Certificates selected by SNI can only be set in the file as [[tls_sni]] tables.
+Precedence: command line > environment > config file > defaults.";
+
+/// Settings of the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Every address gets its own listener
+    pub listen: Vec<SocketAddr>,
+    /// Shorthand for a single listen address, the part which is not set is taken from the first one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    pub ipv6_only: bool,
+    /// Addresses which serve HTTPS
+    pub tls_listen: Vec<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Answer plaintext requests with a redirect to HTTPS
+    pub redirect_http: bool,
+    /// Path of a Unix domain socket, e.g. for a reverse proxy on the same host
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_mode: FileMode,
+    pub backlog: i32,
+    pub mode: ServerMode,
+    pub workers: usize,
+    pub queue_depth: usize,
+    /// Seconds an idle keep-alive connection waits for the next request
+    pub idle_timeout: u64,
+    pub max_requests: usize,
+    /// Seconds in-flight requests get to finish on shutdown
+    pub drain_timeout: u64,
+    /// Seconds from the first byte of a request until its headers are complete
+    pub header_timeout: u64,
+    /// Seconds from the end of the headers until the body is complete
+    pub body_timeout: u64,
+    /// Seconds a handler gets before the request is answered with 503, 0 disables the limit.
+    /// With a limit the handlers run on handler threads, which costs a thread switch per request
+    pub handler_timeout: u64,
+    /// Seconds a write to a client may make no progress
+    pub write_timeout: u64,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    /// Enables the admin shutdown endpoint
+    pub admin_token: Option<String>,
+    /// Further certificates, selected by the server name the client asks for
+    pub tls_sni: Vec<CertificateFiles>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let limits = RequestLimits::default();
+        let keep_alive = KeepAlive::default();
+
+        ServerConfig {
+            listen: vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080))],
+            address: None,
+            port: None,
+            ipv6_only: false,
+            tls_listen: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            redirect_http: false,
+            unix_socket: None,
+            unix_socket_mode: FileMode(0o660),
+            backlog: 128,
+            mode: ServerMode::Threaded,
+            workers: 8,
+            queue_depth: 64,
+            idle_timeout: keep_alive.idle_timeout.as_secs(),
+            max_requests: keep_alive.max_requests,
+            drain_timeout: 10,
+            header_timeout: limits.header_timeout.as_secs(),
+            body_timeout: limits.body_timeout.as_secs(),
+            handler_timeout: 0,
+            write_timeout: 30,
+            max_header_size: limits.max_header_size,
+            max_body_size: limits.max_body_size,
+            admin_token: None,
+            tls_sni: Vec::new(),
+        }
+    }
+}
+
+/// What the command line asks the server to do.
+pub enum Command {
+    Run(ServerConfig),
+    PrintConfig(ServerConfig),
+    Help,
+}
+
+impl ServerConfig {
+    /// Loads the config from the config file, the environment and the command line arguments
+    pub fn load<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+        Self::load_from(args, |name| std::env::var(name).ok())
+    }
+
+    /// Like `load`, with the environment variables looked up by `env`
+    fn load_from<I, E>(mut args: I, env: E) -> Result<Command, String>
+    where
+        I: Iterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut config_path: Option<PathBuf> = None;
+        let mut print_config = false;
+        let mut overrides: Vec<(String, String)> = Vec::new();
+
+        // Collect the command line first, it decides which file is loaded
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => return Ok(Command::Help),
+                "--print-config" => print_config = true,
+                _ => {
+                    let flag = arg.strip_prefix("--").ok_or_else(|| format!("Unknown argument: {}", arg))?;
+                    let (flag, inline_value) = match flag.split_once('=') {
+                        Some((flag, value)) => (flag, Some(value.to_string())),
+                        None => (flag, None),
+                    };
+
+                    // The token is not accepted here, it would be visible in the process list
+                    let key = flag.replace('-', "_");
+                    if key != "config" && (key == "admin_token" || !Self::is_key(&key)) {
+                        return Err(format!("Unknown argument: --{}", flag));
+                    }
+
+                    let value = match inline_value {
+                        Some(value) => value,
+                        None => args.next().ok_or_else(|| format!("Missing value for --{}", flag))?,
+                    };
+
+                    if key == "config" {
+                        config_path = Some(PathBuf::from(value));
+                    } else {
+                        overrides.push((key, value));
+                    }
+                }
+            }
+        }
+
+        // Lowest precedence: the config file
+        let config_path = config_path.or_else(|| env(&format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from));
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None if PathBuf::from(DEFAULT_CONFIG_FILE).exists() => Self::from_file(&PathBuf::from(DEFAULT_CONFIG_FILE))?,
+            None => ServerConfig::default(),
+        };
+
+        // Then the environment
+        let env_name = |key: &str| format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase());
+        let from_env: Vec<(String, String)> = Self::KEYS
+            .iter()
+            .filter_map(|key| env(&env_name(key)).map(|value| (key.to_string(), value)))
+            .collect();
+        config.apply(&from_env, env_name)?;
+
+        // Highest precedence: the command line
+        config.apply(&overrides, |key| format!("--{}", key.replace('_', "-")))?;
+
+        config.validate()?;
+
+        if print_config {
+            Ok(Command::PrintConfig(config))
+        } else {
+            Ok(Command::Run(config))
+        }
+    }
+
+    /// Sets the values of one source, `name` tells the source of a key in errors.
+    /// Its `address` and `port` are resolved right away, so a later source can replace them with `listen`
+    fn apply<N: Fn(&str) -> String>(&mut self, values: &[(String, String)], name: N) -> Result<(), String> {
+        for (key, value) in values {
+            self.set(key, value).map_err(|error| format!("{}: {}", name(key), error))?;
+        }
+
+        let sets_listen = values.iter().any(|(key, _)| key == "listen");
+        self.apply_address_shorthand(sets_listen)
+            .map_err(|error| format!("{} and {}: {}", name("address"), name("port"), error))
+    }
+
+    /// Turns `address` and `port` into the one listen address they stand for.
+    /// `sets_listen` tells whether the same source also sets `listen`
+    fn apply_address_shorthand(&mut self, sets_listen: bool) -> Result<(), String> {
+        if self.address.is_none() && self.port.is_none() {
+            return Ok(());
+        }
+        if sets_listen {
+            return Err("cannot be combined with listen, they replace it".to_string());
+        }
+        if self.listen.len() > 1 {
+            return Err(format!("stand for a single listen address, but listen has {}", self.listen.len()));
+        }
+
+        let first = self.listen.first().copied().unwrap_or_else(|| ServerConfig::default().listen[0]);
+        let address = self.address.take().unwrap_or(first.ip());
+        let port = self.port.take().unwrap_or(first.port());
+        self.listen = vec![SocketAddr::new(address, port)];
+        Ok(())
+    }
+
+    /// Checks the settings which depend on each other
+    fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() && self.tls_listen.is_empty() && self.unix_socket.is_none() {
+            return Err("At least one listen address or a Unix socket is required".to_string());
+        }
+
+        // A socket timeout of zero is refused by the socket or would block forever
+        let timeouts = [self.idle_timeout, self.header_timeout, self.body_timeout, self.write_timeout];
+        if timeouts.contains(&0) {
+            return Err("idle_timeout, header_timeout, body_timeout and write_timeout must be at least 1 second".to_string());
+        }
+
+        if !self.tls_listen.is_empty() && (self.tls_cert.is_none() || self.tls_key.is_none()) {
+            return Err("tls_listen requires tls_cert and tls_key".to_string());
+        }
+
+        if self.redirect_http && self.tls_listen.is_empty() {
+            return Err("redirect_http requires a tls_listen address".to_string());
+        }
+
+        if let Some(files) = self.tls_sni.iter().find(|files| files.server_names.is_empty()) {
+            return Err(format!("tls_sni certificate {} has no server_names", files.cert.display()));
+        }
+
+        #[cfg(not(unix))]
+        if self.unix_socket.is_some() {
+            return Err("Unix sockets are not supported on this platform".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+
+    /// All settings, named like the fields of the config file
+    const KEYS: [&'static str; 24] = [
+        "listen",
+        "address",
+        "port",
+        "ipv6_only",
+        "tls_listen",
+        "tls_cert",
+        "tls_key",
+        "redirect_http",
+        "unix_socket",
+        "unix_socket_mode",
+        "backlog",
+        "mode",
+        "workers",
+        "queue_depth",
+        "idle_timeout",
+        "max_requests",
+        "drain_timeout",
+        "header_timeout",
+        "body_timeout",
+        "handler_timeout",
+        "write_timeout",
+        "max_header_size",
+        "max_body_size",
+        "admin_token",
+    ];
+
+    fn is_key(key: &str) -> bool {
+        Self::KEYS.contains(&key)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<ServerConfig, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|error| format!("Failed to read config file {}: {}", path.display(), error))?;
+        let mut config: ServerConfig =
+            toml::from_str(&content).map_err(|error| format!("Invalid config file {}: {}", path.display(), error))?;
+
+        // Missing fields are filled with defaults, only the keys tell whether the file sets listen
+        let sets_listen = toml::from_str::<toml::Table>(&content).is_ok_and(|table| table.contains_key("listen"));
+        config
+            .apply_address_shorthand(sets_listen)
+            .map_err(|error| format!("Invalid config file {}: address and port {}", path.display(), error))?;
+        Ok(config)
+    }
+
+    /// Sets one setting from its string value
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "listen" => {
+                self.listen = value.split(',').filter(|addr| !addr.trim().is_empty()).map(parse).collect::<Result<_, _>>()?
+            }
+            "address" => self.address = Some(parse(value)?),
+            "port" => self.port = Some(parse(value)?),
+            "ipv6_only" => self.ipv6_only = parse(value)?,
+            "tls_listen" => {
+                self.tls_listen = value.split(',').filter(|addr| !addr.trim().is_empty()).map(parse).collect::<Result<_, _>>()?
+            }
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty()),
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty()),
+            "redirect_http" => self.redirect_http = parse(value)?,
+            "unix_socket" => self.unix_socket = Some(PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty()),
+            "unix_socket_mode" => self.unix_socket_mode = parse(value)?,
+            "backlog" => self.backlog = parse(value)?,
+            "mode" => self.mode = parse(value)?,
+            "workers" => self.workers = parse(value)?,
+            "queue_depth" => self.queue_depth = parse(value)?,
+            "idle_timeout" => self.idle_timeout = parse(value)?,
+            "max_requests" => self.max_requests = parse(value)?,
+            "drain_timeout" => self.drain_timeout = parse(value)?,
+            "header_timeout" => self.header_timeout = parse(value)?,
+            "body_timeout" => self.body_timeout = parse(value)?,
+            "handler_timeout" => self.handler_timeout = parse(value)?,
+            "write_timeout" => self.write_timeout = parse(value)?,
+            "max_header_size" => self.max_header_size = parse(value)?,
+            "max_body_size" => self.max_body_size = parse(value)?,
+            "admin_token" => self.admin_token = Some(value.to_string()).filter(|token| !token.is_empty()),
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// The effective config as TOML, the admin token is masked
+    pub fn to_toml(&self) -> String {
+        let mut printable = self.clone();
+        if printable.admin_token.is_some() {
+            printable.admin_token = Some("********".to_string());
+        }
+        toml::to_string_pretty(&printable).unwrap_or_else(|error| format!("# Failed to print config: {}", error))
+    }
+
+    pub fn listen_options(&self) -> ListenOptions {
+        ListenOptions {
+            backlog: self.backlog,
+            ipv6_only: self.ipv6_only,
+        }
+    }
+
+    /// The default certificate and the certificates selected by SNI, if HTTPS is enabled
+    pub fn tls_certificates(&self) -> Option<(CertificateFiles, Vec<CertificateFiles>)> {
+        if self.tls_listen.is_empty() {
+            return None;
+        }
+
+        let default = CertificateFiles {
+            server_names: Vec::new(),
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+        };
+        Some((default, self.tls_sni.clone()))
+    }
+
+    /// Where plaintext requests are redirected to, if enabled
+    pub fn https_redirect(&self) -> Option<HttpsRedirect> {
+        match self.tls_listen.first() {
+            Some(tls_addr) if self.redirect_http => Some(HttpsRedirect { port: tls_addr.port() }),
+            _ => None,
+        }
+    }
+
+    pub fn limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_header_size: self.max_header_size,
+            max_body_size: self.max_body_size,
+            header_timeout: Duration::from_secs(self.header_timeout),
+            body_timeout: Duration::from_secs(self.body_timeout),
+        }
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            max_requests: self.max_requests,
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    /// `None` lets handlers take as long as they need
+    pub fn handler_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.handler_timeout)).filter(|timeout| !timeout.is_zero())
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout)
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value.trim().parse().map_err(|error| format!("Invalid value {:?}: {}", value, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Loads the config from `file`, the environment variables `env` and the arguments `args`
+    fn load(file: Option<&str>, env: &[(&str, &str)], args: &[&str]) -> Result<ServerConfig, String> {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        // Unique per test, the tests run in parallel
+        let path = std::env::temp_dir().join(format!("server-{}-{:?}.toml", std::process::id(), std::thread::current().id()));
+        if let Some(content) = file {
+            std::fs::write(&path, content).unwrap();
+            args.extend(["--config".to_string(), path.display().to_string()]);
+        }
+
+        let env: HashMap<String, String> = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let loaded = ServerConfig::load_from(args.into_iter(), |name| env.get(name).cloned());
+        let _ = std::fs::remove_file(&path);
+        match loaded? {
+            Command::Run(config) => Ok(config),
+            _ => panic!("expected a config to run"),
+        }
+    }
+
+    fn listen(config: &ServerConfig) -> Vec<String> {
+        config.listen.iter().map(SocketAddr::to_string).collect()
+    }
+
+    #[test]
+    fn command_line_wins_over_environment_over_file() {
+        let file = "workers = 2\nqueue_depth = 3\nbacklog = 4";
+        let config = load(Some(file), &[("SERVER_QUEUE_DEPTH", "30"), ("SERVER_BACKLOG", "40")], &["--backlog", "400"]).unwrap();
+        assert_eq!((config.workers, config.queue_depth, config.backlog), (2, 30, 400));
+    }
+
+    #[test]
+    fn the_shorthand_completes_the_listen_address() {
+        assert_eq!(listen(&load(None, &[], &["--port", "9000"]).unwrap()), ["0.0.0.0:9000"]);
+        assert_eq!(listen(&load(None, &[], &["--address", "::1"]).unwrap()), ["[::1]:8080"]);
+
+        let file = "listen = [\"127.0.0.1:1\"]";
+        assert_eq!(listen(&load(Some(file), &[("SERVER_PORT", "7")], &[]).unwrap()), ["127.0.0.1:7"]);
+    }
+
+    #[test]
+    fn listen_of_a_higher_source_wins_over_the_shorthand() {
+        let config = load(Some("port = 5"), &[("SERVER_PORT", "7")], &["--listen", "[::]:1"]).unwrap();
+        assert_eq!(listen(&config), ["[::]:1"]);
+
+        let config = load(Some("address = \"127.0.0.1\""), &[("SERVER_LISTEN", "[::1]:2")], &[]).unwrap();
+        assert_eq!(listen(&config), ["[::1]:2"]);
+    }
+
+    #[test]
+    fn the_shorthand_of_a_higher_source_wins_over_listen() {
+        let config = load(Some("listen = [\"127.0.0.1:1\"]"), &[("SERVER_LISTEN", "[::1]:2")], &["--port", "3"]).unwrap();
+        assert_eq!(listen(&config), ["[::1]:3"]);
+    }
+
+    #[test]
+    fn the_shorthand_and_listen_of_one_source_conflict() {
+        let error = load(None, &[], &["--listen", "[::]:1", "--port", "2"]).unwrap_err();
+        assert_eq!(error, "--address and --port: cannot be combined with listen, they replace it");
+
+        let error = load(None, &[("SERVER_LISTEN", "[::]:1"), ("SERVER_ADDRESS", "::1")], &[]).unwrap_err();
+        assert!(error.starts_with("SERVER_ADDRESS and SERVER_PORT: "), "{}", error);
+
+        let error = load(Some("listen = [\"[::]:1\"]\nport = 2"), &[], &[]).unwrap_err();
+        assert!(error.ends_with("address and port cannot be combined with listen, they replace it"), "{}", error);
+    }
+
+    #[test]
+    fn the_shorthand_does_not_drop_listen_addresses() {
+        let file = "listen = [\"0.0.0.0:1\", \"[::]:1\"]";
+        let error = load(Some(file), &[("SERVER_PORT", "2")], &[]).unwrap_err();
+        assert_eq!(error, "SERVER_ADDRESS and SERVER_PORT: stand for a single listen address, but listen has 2");
+    }
+
+    #[test]
+    fn timeouts_must_be_at_least_a_second() {
+        for key in ["--idle-timeout", "--header-timeout", "--body-timeout", "--write-timeout"] {
+            let error = load(None, &[], &[key, "0"]).unwrap_err();
+            assert_eq!(error, "idle_timeout, header_timeout, body_timeout and write_timeout must be at least 1 second");
+            assert!(load(None, &[], &[key, "1"]).is_ok(), "{}", key);
+        }
+    }
+}
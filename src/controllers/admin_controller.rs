@@ -0,0 +1,98 @@
+use crate::error::ApiError;
+use crate::http::middleware::Next;
+use crate::http::request::{HttpRequest, Method};
+use crate::http::response::{HttpResponse, StatusCode};
+use crate::http::router::Router;
+use crate::response_builder::create_http_unauthorized_response;
+use crate::server::shutdown;
+
+/// The administration routes, only available with an admin token
+pub fn routes() -> Router {
+    match shutdown::admin_token() {
+        Some(_) => Router::new()
+            .layer(require_admin_token)
+            .route(Method::Post, "/shutdown", shutdown),
+        None => Router::new(),
+    }
+}
+
+/// Lets only requests with `Authorization: Bearer <admin token>` through
+fn require_admin_token(request: &mut HttpRequest, next: Next) -> HttpResponse {
+    match shutdown::admin_token() {
+        Some(expected) => check_admin_token(expected, request, next),
+        None => create_http_unauthorized_response("Admin endpoint is disabled"),
+    }
+}
+
+/// Passes the request on if it carries the `expected` bearer token
+fn check_admin_token(expected: &str, request: &mut HttpRequest, next: Next) -> HttpResponse {
+    let provided = request.headers.get("Authorization").and_then(bearer_token);
+
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), expected.as_bytes()) => next.run(request),
+        _ => create_http_unauthorized_response("Invalid admin token")
+            .with_header("WWW-Authenticate", "Bearer"),
+    }
+}
+
+/// The token of a `Bearer` authorization, the scheme is case-insensitive
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim())
+}
+
+/// Starts a graceful shutdown
+pub fn shutdown(_request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    shutdown::request_shutdown("admin endpoint");
+    Ok(HttpResponse::new(StatusCode::Accepted).with_body("text/plain", "Shutting down"))
+}
+
+/// Compares without exiting early, so the timing does not reveal the token
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+
+    left.iter().zip(right).fold(0u8, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::HeaderMap;
+    use crate::http::request::HttpVersion;
+
+    fn admin_status(authorization: Option<&str>) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert("Authorization", authorization);
+        }
+        let mut request = HttpRequest::from_parts("POST", "/admin/shutdown", HttpVersion::Http11, headers, Vec::new()).unwrap();
+        let handler = |_: &mut HttpRequest| HttpResponse::new(StatusCode::Ok);
+        check_admin_token("secret", &mut request, Next::new(&[], &handler)).status
+    }
+
+    #[test]
+    fn the_admin_token_lets_the_request_through() {
+        assert_eq!(admin_status(Some("Bearer secret")), StatusCode::Ok);
+    }
+
+    #[test]
+    fn a_wrong_admin_token_is_unauthorized() {
+        assert_eq!(admin_status(Some("Bearer secreT")), StatusCode::Unauthorized);
+        assert_eq!(admin_status(Some("Bearer secret2")), StatusCode::Unauthorized);
+        assert_eq!(admin_status(Some("Basic secret")), StatusCode::Unauthorized);
+    }
+
+    #[test]
+    fn a_missing_admin_token_is_unauthorized() {
+        assert_eq!(admin_status(None), StatusCode::Unauthorized);
+        assert_eq!(admin_status(Some("Bearer")), StatusCode::Unauthorized);
+    }
+
+    #[test]
+    fn the_scheme_ignores_case() {
+        assert_eq!(admin_status(Some("bearer secret")), StatusCode::Ok);
+        assert_eq!(admin_status(Some("BEARER secret")), StatusCode::Ok);
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::mpsc::Receiver;
+use crate::error::ApiError;
+use crate::http::request::{HttpRequest, Method};
+use crate::http::response::HttpResponse;
+use crate::http::router::Router;
+use crate::http::sse;
+use crate::http::websocket::{self, HandshakeError, Notify};
+use crate::response_builder::{create_http_event_stream_response, create_http_switching_protocols_response, create_http_upgrade_required_response};
+use crate::services::recipe_events;
+
+/// The WebSocket channel and the event stream which push recipe changes
+pub fn routes() -> Router {
+    Router::new()
+        .route(Method::Get, "/Live", live)
+        .route(Method::Get, "/Events", events)
+}
+
+/// Opens a WebSocket which pushes every added, updated and deleted recipe as a JSON message
+pub fn live(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    match websocket::handshake(request) {
+        Ok(accept_key) => Ok(create_http_switching_protocols_response(&accept_key, subscribe_to_recipes)),
+        Err(HandshakeError::NotUpgrade) | Err(HandshakeError::UnsupportedVersion) => {
+            Ok(create_http_upgrade_required_response("This endpoint requires a WebSocket connection"))
+        }
+        Err(HandshakeError::BadRequest(message)) => Err(ApiError::BadRequest(message.to_string())),
+    }
+}
+
+/// Streams the same changes as server-sent events for clients without WebSocket.
+/// A reconnecting client receives the events it missed after its `Last-Event-ID`.
+pub fn events(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    if !request.query.is_empty() {
+        return Err(ApiError::BadRequest("This endpoint does not accept query parameters".to_string()));
+    }
+
+    Ok(create_http_event_stream_response(stream_recipe_events, sse::last_event_id(request)))
+}
+
+fn subscribe_to_recipes(notify: Notify) -> Receiver<String> {
+    recipe_events::subscribe(|_, event| event.to_json(), move || notify())
+}
+
+fn stream_recipe_events(last_event_id: Option<u64>, notify: Notify) -> Receiver<String> {
+    recipe_events::subscribe_after(
+        last_event_id,
+        |id, event| sse::format_event(id, event.name(), &event.to_json()),
+        move || notify(),
+    )
+}
@@ -1 +1,3 @@
+pub mod admin_controller;
+pub mod live_controller;
 pub mod recipe_controller;
\ No newline at end of file
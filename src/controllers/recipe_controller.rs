@@ -1,174 +1,238 @@
-use std::collections::HashMap;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
 use crate::assembler::recipe_assembler::from_recipe_dto;
 use crate::dto::recipe_dto::RecipeDto;
-use crate::response_builder::{create_http_bad_request_response, create_http_created_response, create_http_internal_server_error_response, create_http_not_found_response, create_http_success_response};
+use crate::error::ApiError;
+use crate::http::request::{HttpRequest, Method};
+use crate::http::response::HttpResponse;
+use crate::http::router::Router;
+use crate::response_builder::{create_http_created_at_response, create_http_created_response, create_http_no_content_response, create_http_success_response};
 use crate::services::recipe_service;
 
-pub fn get_recipe_by_id(_req_content_type: &str, query_params: Option<&str>, _req_body: &str) -> String {
-    match query_params {
-        Some(query_params) => {
-            // Parse query parameters into key-value pairs
-            let mut params_map = HashMap::new();
+/// The RPC-style routes, the id is passed as query parameter
+pub fn service_routes() -> Router {
+    Router::new()
+        .route(Method::Get, "/GetRecipeById", get_recipe_by_id)
+        .route(Method::Get, "/GetAllRecipes", get_all_recipes)
+        .route(Method::Put, "/UpdateRecipe", update_recipe)
+        .route(Method::Post, "/AddRecipe", add_recipe)
+        .route(Method::Delete, "/DeleteRecipeById", delete_recipe_by_id)
+}
 
-            for param in query_params.split('&') {
-                let mut parts = param.split('=');
-                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                    params_map.insert(key.to_string(), value.to_string());
-                }
-            }
+/// The RESTful routes, the id is part of the path
+pub fn routes() -> Router {
+    Router::new()
+        .route(Method::Get, "/", list_recipes)
+        .route(Method::Post, "/", create_recipe)
+        .route(Method::Get, "/{id}", get_recipe)
+        .route(Method::Put, "/{id}", replace_recipe)
+        .route(Method::Patch, "/{id}", patch_recipe)
+        .route(Method::Delete, "/{id}", delete_recipe)
+        .route(Method::Get, "/{id}/comments", get_recipe_comments)
+}
 
-            if let Some(id_str) = params_map.get("id") {
-                if let Ok(id) = id_str.parse::<i32>() {
-                    match recipe_service::get_recipe_by_id(id) {
-                        Some(recipe) => {
-                            // Serialize the Recipe to JSON
-                            let serialized = serde_json::to_string(&recipe).unwrap_or_else(|_| "".to_string());
-                            create_http_success_response(&serialized)
-                        }
-                        None => {
-                            create_http_not_found_response(Some("Could not find recipe"))
-                        }
-                    }
-                } else {
-                    create_http_bad_request_response("Invalid ID format")
-                }
-            } else {
-                create_http_bad_request_response("Id missing in query parameters")
-            }
-        }
-        None => {
-            create_http_bad_request_response("Query parameters are missing")
-        }
-    }
+pub fn get_recipe_by_id(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let id = query_id(request)?;
+    let recipe = recipe_service::get_recipe_by_id(id)?;
+
+    // Serialize the Recipe to JSON
+    Ok(create_http_success_response(&to_json(&recipe)?))
 }
 
-pub fn get_all_recipes(_req_content_type: &str, query_params: Option<&str>, req_body: &str) -> String {
+pub fn get_all_recipes(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
     // Ensure the request has no unnecessary query parameters
-    if query_params.is_some() {
-        return create_http_bad_request_response("This endpoint does not accept query parameters");
+    if !request.query.is_empty() {
+        return Err(ApiError::BadRequest("This endpoint does not accept query parameters".to_string()));
     }
 
     // Check if the content type is valid
-    if req_body.is_empty() == false {
-        return create_http_bad_request_response("Request body must be empty");
+    if !request.body.is_empty() {
+        return Err(ApiError::BadRequest("Request body must be empty".to_string()));
     }
 
     // Call the service to get all recipes
-    match recipe_service::get_all_recipes() {
-        Some(recipes) => {
-            // Serialize the list of recipes to JSON
-            let serialized = serde_json::to_string(&recipes).unwrap_or_else(|_| "".to_string());
-            create_http_success_response(&serialized)
-        }
-        None => {
-            create_http_not_found_response(Some("No recipes found"))
-        }
+    let recipes = recipe_service::get_all_recipes().ok_or_else(|| ApiError::NotFound("No recipes found".to_string()))?;
+
+    // Serialize the list of recipes to JSON
+    Ok(create_http_success_response(&to_json(&recipes)?))
+}
+
+pub fn update_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    if !request.query.is_empty() {
+        return Err(ApiError::BadRequest("Can't update the recipe with query parameters".to_string()));
     }
+
+    // Deserialize the JSON request body and convert it to the Recipe model
+    let dto = parse_recipe_body(request)?;
+    let updated_recipe = recipe_service::update_recipe(from_recipe_dto(&dto));
+
+    Ok(create_http_success_response(&to_json(&updated_recipe)?))
 }
 
+pub fn add_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    if !request.query.is_empty() {
+        return Err(ApiError::BadRequest("Can't add the recipe with query parameters".to_string()));
+    }
 
-pub fn update_recipe(req_content_type: &str, query_params: Option<&str>, req_body: &str) -> String {
-    match query_params {
-        Some(_) => {
-            create_http_bad_request_response("Can't update the recipe with query parameters")
-        }
-        None => {
-            match req_content_type {
-                "application/json" => {
-                    // Deserialize the JSON request body
-                    let recipe_dto: Result<RecipeDto, _> = serde_json::from_str(req_body);
-
-                    match recipe_dto {
-                        Ok(dto) => {
-                            // Convert RecipeDto to Recipe model
-                            let recipe = from_recipe_dto(&dto);
-
-                            let updated_recipe = recipe_service::update_recipe(recipe);
-                            let serialized = serde_json::to_string(&updated_recipe).unwrap_or_else(|_| "".to_string());
-                            create_http_success_response(&serialized)
-
-                        }
-                        Err(_) => {
-                            create_http_internal_server_error_response("Failed to deserialize the recipe")
-                        }
-                    }
-                }
-                _ => {
-                    create_http_bad_request_response("Body must contain JSON")
-                }
-            }
-        }
+    // Deserialize the JSON request body and convert it to the Recipe model
+    let dto = parse_recipe_body(request)?;
+
+    // Call the service to add the recipe, an existing recipe is not replaced
+    let added = recipe_service::create_recipe(from_recipe_dto(&dto))?;
+    Ok(create_http_created_response(&added.id.to_string(), "Recipe added successfully"))
+}
+
+pub fn delete_recipe_by_id(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let id = query_id(request)?;
+
+    // Call the service to delete the recipe
+    recipe_service::delete_recipe(id)?;
+    Ok(create_http_success_response("Recipe deleted successfully"))
+}
+
+// RESTful routes, the recipe id is part of the path
+
+/// GET /recipes
+pub fn list_recipes(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    if !request.query.is_empty() {
+        return Err(ApiError::BadRequest("This endpoint does not accept query parameters".to_string()));
     }
+
+    // An empty collection is still a collection
+    let recipes = recipe_service::get_all_recipes().unwrap_or_default();
+    Ok(create_http_success_response(&to_json(&recipes)?))
 }
 
-pub fn add_recipe(req_content_type: &str, query_params: Option<&str>, req_body: &str) -> String {
-    match query_params {
-        Some(_) => {
-            create_http_bad_request_response("Can't add the recipe with query parameters")
-        }
-        None => {
-            match req_content_type {
-                "application/json" => {
-                    // Deserialize the JSON request body into a RecipeDto
-                    let recipe_dto: Result<RecipeDto, _> = serde_json::from_str(req_body);
-
-                    match recipe_dto {
-                        Ok(dto) => {
-                            // Convert RecipeDto to Recipe model
-                            let recipe = from_recipe_dto(&dto);
-
-                            let recipe_id = recipe.id.clone().to_string();
-
-                            // Call the service to add the recipe
-                            if recipe_service::add_recipe(recipe) {
-                                create_http_created_response(&*recipe_id, "Recipe added successfully")
-                            } else {
-                                create_http_internal_server_error_response("Failed to add the recipe")
-                            }
-                        }
-                        Err(_) => {
-                            create_http_internal_server_error_response("Failed to deserialize the recipe")
-                        }
-                    }
-                }
-                _ => {
-                    create_http_bad_request_response("Body must contain JSON")
-                }
-            }
+/// POST /recipes
+pub fn create_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let dto = parse_recipe_body(request)?;
+
+    let created = recipe_service::create_recipe(from_recipe_dto(&dto))?;
+    Ok(create_http_created_at_response(&format!("/recipes/{}", created.id), &to_json(&created)?))
+}
+
+/// GET /recipes/{id}
+pub fn get_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let recipe = recipe_service::get_recipe_by_id(path_id(request)?)?;
+    Ok(create_http_success_response(&to_json(&recipe)?))
+}
+
+/// GET /recipes/{id}/comments
+pub fn get_recipe_comments(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let recipe = recipe_service::get_recipe_by_id(path_id(request)?)?;
+    Ok(create_http_success_response(&to_json(&recipe.comments)?))
+}
+
+/// PUT /recipes/{id}, replaces the recipe or creates it
+pub fn replace_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let id = path_id(request)?;
+    let dto = parse_recipe_body(request)?;
+
+    if dto.id != id {
+        return Err(ApiError::invalid_field("id", &format!("must be {} like the path", id)));
+    }
+
+    let serialized = to_json(&dto)?;
+    if recipe_service::add_recipe(from_recipe_dto(&dto)) {
+        Ok(create_http_created_at_response(&format!("/recipes/{}", id), &serialized))
+    } else {
+        Ok(create_http_success_response(&serialized))
+    }
+}
+
+/// PATCH /recipes/{id}, applies a JSON merge patch (RFC 7396) to the recipe
+pub fn patch_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    let id = path_id(request)?;
+
+    if !matches!(request.content_type(), "application/merge-patch+json" | "application/json") {
+        return Err(ApiError::UnsupportedMediaType("Body must contain a JSON merge patch".to_string()));
+    }
+    let patch: Value = serde_json::from_slice(&request.body)?;
+    if !patch.is_object() {
+        return Err(ApiError::BadRequest("The patch must be a JSON object".to_string()));
+    }
+
+    let patched = recipe_service::modify_recipe(id, |current| {
+        let mut merged = serde_json::to_value(current).map_err(|error| ApiError::Internal(error.to_string()))?;
+        merge_patch(&mut merged, &patch);
+
+        let dto: RecipeDto = serde_path_to_error::deserialize(merged)?;
+        if dto.id != id {
+            return Err(ApiError::invalid_field("id", "cannot change"));
         }
+        Ok(dto)
+    })?;
+
+    Ok(create_http_success_response(&to_json(&patched)?))
+}
+
+/// DELETE /recipes/{id}
+pub fn delete_recipe(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+    recipe_service::delete_recipe(path_id(request)?)?;
+    Ok(create_http_no_content_response())
+}
+
+/// The `id` query parameter of the RPC-style routes
+fn query_id(request: &HttpRequest) -> Result<i32, ApiError> {
+    if request.query.is_empty() {
+        return Err(ApiError::BadRequest("Query parameters are missing".to_string()));
     }
+
+    let id = request
+        .query_param("id")
+        .ok_or_else(|| ApiError::BadRequest("Id missing in query parameters".to_string()))?;
+    id.parse().map_err(|_| ApiError::BadRequest("Invalid ID format".to_string()))
 }
 
-pub fn delete_recipe_by_id(_req_content_type: &str, query_params: Option<&str>, _req_body: &str) -> String {
-    match query_params {
-        Some(query_params) => {
-            // Parse query parameters into key-value pairs
-            let mut params_map = HashMap::new();
+/// The `{id}` of the RESTful routes
+fn path_id(request: &HttpRequest) -> Result<i32, ApiError> {
+    request
+        .path_param::<i32>("id")
+        .ok_or_else(|| ApiError::BadRequest("Invalid ID format".to_string()))
+}
 
-            for param in query_params.split('&') {
-                let mut parts = param.split('=');
-                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                    params_map.insert(key.to_string(), value.to_string());
-                }
-            }
+/// Reads the recipe of a JSON body
+fn parse_recipe_body(request: &HttpRequest) -> Result<RecipeDto, ApiError> {
+    if request.content_type() != "application/json" {
+        return Err(ApiError::UnsupportedMediaType("Body must contain JSON".to_string()));
+    }
+
+    parse_json(&request.body)
+}
 
-            if let Some(id_str) = params_map.get("id") {
-                if let Ok(id) = id_str.parse::<i32>() {
-                    // Call the service to delete the recipe
-                    if recipe_service::delete_recipe(id) {
-                        create_http_success_response("Recipe deleted successfully")
-                    } else {
-                        create_http_not_found_response(Some("Recipe not found"))
-                    }
-                } else {
-                    create_http_bad_request_response("Invalid ID format")
+/// Broken JSON is a bad request, a field with the wrong type is reported by its path
+fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    let value: Value = serde_json::from_slice(body)?;
+    Ok(serde_path_to_error::deserialize(value)?)
+}
+
+/// Serializes a response body, failing to do so is an error of the server
+fn to_json<T: Serialize>(value: &T) -> Result<String, ApiError> {
+    serde_json::to_string(value).map_err(|error| ApiError::Internal(format!("Failed to serialize the response: {}", error)))
+}
+
+/// Merges `patch` into `target`, null removes a field and objects are merged recursively
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let fields = match patch {
+        Value::Object(fields) => fields,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target_fields) = target {
+        for (name, value) in fields {
+            match value {
+                Value::Null => {
+                    target_fields.remove(name);
                 }
-            } else {
-                create_http_bad_request_response("id missing in query parameters")
+                _ => merge_patch(target_fields.entry(name.clone()).or_insert(Value::Null), value),
             }
         }
-        None => {
-            create_http_bad_request_response("Query parameters are missing")
-        }
     }
-}
\ No newline at end of file
+}
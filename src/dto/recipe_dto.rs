@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use crate::dto::user_dto::UserDto;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RecipeDto {
     pub id: i32,
     pub title: String,
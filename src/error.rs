@@ -0,0 +1,189 @@
+use serde_json::error::Category;
+use crate::http::problem::FieldError;
+use crate::http::response::HttpResponse;
+use crate::response_builder::{create_http_bad_request_response, create_http_conflict_response, create_http_internal_server_error_response, create_http_not_found_response, create_http_unprocessable_entity_response, create_http_unsupported_media_type_response};
+
+/// Why a request failed, returned by handlers and services and turned into a response once.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request is malformed, e.g. broken JSON or an id which is not a number
+    BadRequest(String),
+    /// The request is well-formed, but its content is invalid. Lists the invalid fields, if known
+    Validation(String, Vec<FieldError>),
+    NotFound(String),
+    /// The request contradicts the current state, e.g. an id which is already taken
+    Conflict(String),
+    /// The body has a media type the endpoint does not read
+    UnsupportedMediaType(String),
+    /// Something on the server went wrong, the client cannot fix it
+    Internal(String),
+}
+
+impl ApiError {
+    /// A validation error of a single field
+    pub fn invalid_field(field: &str, message: &str) -> Self {
+        ApiError::Validation(format!("Invalid {}", field), vec![FieldError::new(field, message)])
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        match self {
+            ApiError::BadRequest(message) => create_http_bad_request_response(&message),
+            ApiError::Validation(message, errors) => create_http_unprocessable_entity_response(&message, errors),
+            ApiError::NotFound(message) => create_http_not_found_response(Some(&message)),
+            ApiError::Conflict(message) => create_http_conflict_response(&message),
+            ApiError::UnsupportedMediaType(message) => create_http_unsupported_media_type_response(&message),
+            ApiError::Internal(message) => {
+                eprintln!("Internal error: {}", message);
+                create_http_internal_server_error_response("The server failed to handle the request")
+            }
+        }
+    }
+}
+
+/// Broken JSON is a bad request, JSON with missing or mistyped fields is invalid content
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        match error.classify() {
+            Category::Syntax | Category::Eof => ApiError::BadRequest(format!("Malformed JSON: {}", error)),
+            Category::Data => ApiError::Validation(format!("Invalid JSON content: {}", error), Vec::new()),
+            Category::Io => ApiError::Internal(format!("Failed to read JSON: {}", error)),
+        }
+    }
+}
+
+/// Like the plain JSON error, but names the field which did not deserialize, e.g. `created_by.email`
+impl From<serde_path_to_error::Error<serde_json::Error>> for ApiError {
+    fn from(error: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        if error.inner().classify() != Category::Data {
+            return ApiError::from(error.into_inner());
+        }
+
+        let message = error.inner().to_string();
+        let path = error.path().to_string();
+        // A missing field is reported at the object which lacks it
+        let field = match message.strip_prefix("missing field `").and_then(|name| name.strip_suffix('`')) {
+            Some(name) if path == "." => name.to_string(),
+            Some(name) => format!("{}.{}", path, name),
+            None => path,
+        };
+
+        ApiError::Validation("The request body has invalid fields".to_string(), vec![FieldError { field, message }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dto::recipe_dto::RecipeDto;
+    use crate::http::problem::PROBLEM_JSON;
+    use crate::http::response::StatusCode;
+    use serde_json::{json, Value};
+
+    fn problem_of(response: &HttpResponse) -> Value {
+        assert_eq!(response.headers.get("Content-Type"), Some(PROBLEM_JSON));
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn every_error_is_a_problem_with_its_status() {
+        for (error, status, detail) in [
+            (ApiError::BadRequest("Malformed id".to_string()), StatusCode::BadRequest, "Malformed id"),
+            (ApiError::Validation("Invalid title".to_string(), Vec::new()), StatusCode::UnprocessableEntity, "Invalid title"),
+            (ApiError::NotFound("No recipe 7".to_string()), StatusCode::NotFound, "No recipe 7"),
+            (ApiError::Conflict("Id 7 is taken".to_string()), StatusCode::Conflict, "Id 7 is taken"),
+            (ApiError::UnsupportedMediaType("Body must contain JSON".to_string()), StatusCode::UnsupportedMediaType, "Body must contain JSON"),
+            // The cause of an internal error stays on the server
+            (ApiError::Internal("lock poisoned".to_string()), StatusCode::InternalServerError, "The server failed to handle the request"),
+        ] {
+            let response = error.into_response();
+            assert_eq!(response.status, status);
+
+            let problem = problem_of(&response);
+            assert_eq!(problem["type"], "about:blank");
+            assert_eq!(problem["title"], status.reason());
+            assert_eq!(problem["status"], status.code());
+            assert_eq!(problem["detail"], detail);
+            assert_eq!(problem.get("instance"), None);
+            assert_eq!(problem.get("errors"), None);
+        }
+    }
+
+    #[test]
+    fn the_body_has_exactly_the_problem_members() {
+        let response = ApiError::NotFound("No recipe 7".to_string()).into_response();
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            r#"{"type":"about:blank","title":"Not Found","status":404,"detail":"No recipe 7"}"#
+        );
+    }
+
+    #[test]
+    fn invalid_fields_are_listed_in_the_problem() {
+        let response = ApiError::invalid_field("title", "must not be empty").into_response();
+        assert_eq!(response.status, StatusCode::UnprocessableEntity);
+
+        let problem = problem_of(&response);
+        assert_eq!(problem["detail"], "Invalid title");
+        assert_eq!(problem["errors"], json!([{ "field": "title", "message": "must not be empty" }]));
+    }
+
+    #[test]
+    fn json_errors_are_bad_requests_or_validation_errors() {
+        let syntax = serde_json::from_str::<Value>("{\"id\":").unwrap_err();
+        assert!(matches!(ApiError::from(syntax), ApiError::BadRequest(_)));
+
+        let data = serde_json::from_str::<u32>("\"seven\"").unwrap_err();
+        assert!(matches!(ApiError::from(data), ApiError::Validation(_, _)));
+    }
+
+    fn recipe(created_by: Value) -> Value {
+        json!({
+            "id": 1,
+            "title": "Soup",
+            "ingredients": ["water"],
+            "instructions": "Boil",
+            "comments": [],
+            "created_by": created_by
+        })
+    }
+
+    /// The field and message of the 422 a recipe body gets
+    fn field_error(body: Value) -> (String, String) {
+        let error = serde_path_to_error::deserialize::<_, RecipeDto>(body).unwrap_err();
+        let response = ApiError::from(error).into_response();
+        assert_eq!(response.status, StatusCode::UnprocessableEntity);
+
+        let problem = problem_of(&response);
+        assert_eq!(problem["detail"], "The request body has invalid fields");
+        let errors = problem["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        (errors[0]["field"].as_str().unwrap().to_string(), errors[0]["message"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn a_nested_field_of_the_wrong_type_is_named_by_its_path() {
+        let (field, message) = field_error(recipe(json!({ "id": 1, "name": "Ann", "email": 5 })));
+        assert_eq!(field, "created_by.email");
+        assert!(message.starts_with("invalid type: integer `5`"), "{}", message);
+    }
+
+    #[test]
+    fn a_missing_nested_field_is_named_by_its_path() {
+        let (field, message) = field_error(recipe(json!({ "id": 1, "name": "Ann" })));
+        assert_eq!(field, "created_by.email");
+        assert_eq!(message, "missing field `email`");
+    }
+
+    #[test]
+    fn a_missing_top_level_field_is_named_alone() {
+        let mut body = recipe(json!({ "id": 1, "name": "Ann", "email": "ann@example.com" }));
+        body.as_object_mut().unwrap().remove("title");
+        assert_eq!(field_error(body).0, "title");
+    }
+
+    #[test]
+    fn broken_json_stays_a_bad_request_with_a_path() {
+        let error = serde_path_to_error::deserialize::<_, RecipeDto>(&mut serde_json::Deserializer::from_str("{\"id\":")).unwrap_err();
+        assert!(matches!(ApiError::from(error), ApiError::BadRequest(_)));
+    }
+}
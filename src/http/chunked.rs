@@ -0,0 +1,315 @@
+use crate::http::headers::{is_field_value, is_token, trim_whitespace};
+use crate::http::request_reader::{ReadError, RequestLimits};
+
+/// Maximum length of a single chunk size line, including extensions
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// A chunk size has at most 16 hex digits, which fits into 64 bits
+const MAX_CHUNK_SIZE_DIGITS: usize = 16;
+
+/// A decoded chunked body together with its trailer fields.
+#[derive(Debug, Default)]
+pub struct ChunkedBody {
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, String)>,
+}
+
+/// Where the decoder stands in the chunked body
+#[derive(Debug, Default, Clone, Copy)]
+enum State {
+    /// Waiting for the next chunk size line
+    #[default]
+    Size,
+    /// Inside the chunk data, with the number of bytes still missing
+    Data(usize),
+    /// Waiting for the CRLF after the chunk data
+    DataEnd,
+    /// Reading the trailer section after the last chunk
+    Trailers,
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked` while it arrives.
+/// The progress is kept between reads, so every received byte is decoded only once.
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    state: State,
+    /// Number of body bytes used so far
+    position: usize,
+    decoded: ChunkedBody,
+    trailer_size: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        ChunkedDecoder::default()
+    }
+
+    /// Continues with the bytes received after the headers, the buffer always starts at the body.
+    /// Returns the body and the number of bytes it used, `None` if the body is not complete yet.
+    pub fn decode(&mut self, buffer: &[u8], limits: &RequestLimits) -> Result<Option<(ChunkedBody, usize)>, ReadError> {
+        let mut source = Source { buffer, position: self.position };
+        let result = self.decode_from(&mut source, limits);
+        self.position = source.position;
+        result
+    }
+
+    fn decode_from(&mut self, source: &mut Source, limits: &RequestLimits) -> Result<Option<(ChunkedBody, usize)>, ReadError> {
+        loop {
+            match self.state {
+                // chunk-size [ chunk-ext ] CRLF
+                State::Size => {
+                    let line = match source.read_line(MAX_CHUNK_LINE, ReadError::BadRequest("Chunk size line is too long"))? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    let size = parse_chunk_size(line)?;
+
+                    // The last chunk is followed by the trailer section
+                    if size == 0 {
+                        self.state = State::Trailers;
+                        continue;
+                    }
+
+                    // Compared without adding, a huge size would overflow
+                    if size > limits.max_body_size.saturating_sub(self.decoded.body.len()) {
+                        return Err(ReadError::BodyTooLarge);
+                    }
+                    self.state = State::Data(size);
+                }
+                // chunk-data, taken as far as it arrived
+                State::Data(missing) => {
+                    let data = source.read_up_to(missing);
+                    if data.is_empty() {
+                        return Ok(None);
+                    }
+                    self.decoded.body.extend_from_slice(data);
+                    self.state = match missing - data.len() {
+                        0 => State::DataEnd,
+                        missing => State::Data(missing),
+                    };
+                }
+                State::DataEnd => match source.read_exact(2) {
+                    Some(b"\r\n") => self.state = State::Size,
+                    Some(_) => return Err(ReadError::BadRequest("Missing CRLF after chunk data")),
+                    None => return Ok(None),
+                },
+                // trailer-section CRLF
+                State::Trailers => {
+                    let line = match source.read_line(limits.max_header_size, ReadError::HeadersTooLarge)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    if line.is_empty() {
+                        return Ok(Some((std::mem::take(&mut self.decoded), source.position)));
+                    }
+
+                    self.trailer_size += line.len();
+                    if self.trailer_size > limits.max_header_size {
+                        return Err(ReadError::HeadersTooLarge);
+                    }
+
+                    let line = std::str::from_utf8(line).map_err(|_| ReadError::BadRequest("Trailer fields must be valid UTF-8"))?;
+                    match line.split_once(':') {
+                        Some((key, value)) if is_token(key) && is_field_value(value) => {
+                            self.decoded.trailers.push((key.to_string(), trim_whitespace(value).to_string()));
+                        }
+                        _ => return Err(ReadError::BadRequest("Malformed trailer field")),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parses the hexadecimal chunk size and validates the chunk extensions
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ReadError> {
+    let line = std::str::from_utf8(line).map_err(|_| ReadError::BadRequest("Invalid chunk size line"))?;
+
+    // Extensions are allowed but not used
+    let (size, extensions) = match line.split_once(';') {
+        Some((size, extensions)) => (size, Some(extensions)),
+        None => (line, None),
+    };
+
+    if let Some(extensions) = extensions {
+        for extension in extensions.split(';') {
+            let name = extension.split_once('=').map_or(extension, |(name, _)| name);
+            if !is_token(name.trim()) {
+                return Err(ReadError::BadRequest("Malformed chunk extension"));
+            }
+        }
+    }
+
+    let size = size.trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ReadError::BadRequest("Invalid chunk size"));
+    }
+    if size.len() > MAX_CHUNK_SIZE_DIGITS {
+        return Err(ReadError::BadRequest("Chunk size is too large"));
+    }
+
+    usize::from_str_radix(size, 16).map_err(|_| ReadError::BadRequest("Chunk size is too large"))
+}
+
+/// Cursor over the bytes received so far
+struct Source<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Source<'a> {
+    /// Takes the next `count` bytes, `None` if they did not arrive yet
+    fn read_exact(&mut self, count: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(count)?;
+        let bytes = self.buffer.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    /// Takes up to `count` of the bytes which arrived so far
+    fn read_up_to(&mut self, count: usize) -> &'a [u8] {
+        let end = self.buffer.len().min(self.position.saturating_add(count));
+        let bytes = &self.buffer[self.position..end];
+        self.position = end;
+        bytes
+    }
+
+    /// Takes a line terminated by CRLF without the terminator, `None` if it did not arrive yet
+    fn read_line(&mut self, max_length: usize, too_long: ReadError) -> Result<Option<&'a [u8]>, ReadError> {
+        let remaining = &self.buffer[self.position..];
+
+        let end = match remaining.iter().position(|&byte| byte == b'\n') {
+            Some(end) => end,
+            None if remaining.len() > max_length => return Err(too_long),
+            None => return Ok(None),
+        };
+
+        // A bare LF is not valid framing
+        if end == 0 || remaining[end - 1] != b'\r' {
+            return Err(ReadError::BadRequest("Chunk lines must end with CRLF"));
+        }
+
+        let line = &remaining[..end - 1];
+        if line.len() > max_length {
+            return Err(too_long);
+        }
+
+        self.position += end + 1;
+        Ok(Some(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RequestLimits {
+        RequestLimits { max_body_size: 64, max_header_size: 128, ..RequestLimits::default() }
+    }
+
+    fn decode(input: &[u8]) -> Result<Option<(ChunkedBody, usize)>, ReadError> {
+        ChunkedDecoder::new().decode(input, &limits())
+    }
+
+    #[test]
+    fn decodes_chunks_and_reports_used_bytes() {
+        let input = b"5\r\nHello\r\n7\r\n, World\r\n0\r\n\r\nNEXT";
+        let (decoded, used) = decode(input).unwrap().unwrap();
+        assert_eq!(decoded.body, b"Hello, World");
+        assert!(decoded.trailers.is_empty());
+        assert_eq!(&input[used..], b"NEXT");
+    }
+
+    #[test]
+    fn accepts_extensions() {
+        let (decoded, _) = decode(b"3;name=value;flag\r\nabc\r\n0;last\r\n\r\n").unwrap().unwrap();
+        assert_eq!(decoded.body, b"abc");
+    }
+
+    #[test]
+    fn rejects_malformed_extensions() {
+        assert!(matches!(decode(b"3;=value\r\nabc\r\n0\r\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"3;a b\r\nabc\r\n0\r\n\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn collects_trailers() {
+        let (decoded, _) = decode(b"1\r\nx\r\n0\r\nChecksum: \tabc \r\nExpires: never\r\n\r\n").unwrap().unwrap();
+        assert_eq!(
+            decoded.trailers,
+            vec![("Checksum".to_string(), "abc".to_string()), ("Expires".to_string(), "never".to_string())]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_trailers() {
+        assert!(matches!(decode(b"0\r\nNo colon\r\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"0\r\nBad name: x\r\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"0\r\nName: a\x01b\r\n\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_bare_lf() {
+        assert!(matches!(decode(b"3\nabc\r\n0\r\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"3\r\nabc\r\n0\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"3\r\nabc\r\n0\r\nName: x\n\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_missing_crlf_after_data() {
+        assert!(matches!(decode(b"3\r\nabcd\r\n0\r\n\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(matches!(decode(b"\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"-1\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"0x3\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn waits_for_incomplete_input() {
+        let input = b"5\r\nHello\r\n0\r\nName: x\r\n\r\n";
+        for end in 0..input.len() {
+            assert!(decode(&input[..end]).unwrap().is_none(), "complete after {} bytes", end);
+        }
+        assert!(decode(input).unwrap().is_some());
+    }
+
+    #[test]
+    fn continues_where_the_last_read_stopped() {
+        let input = b"5\r\nHello\r\n7;ext=1\r\n, World\r\n0\r\nName: x\r\n\r\nNEXT";
+        let mut decoder = ChunkedDecoder::new();
+        for end in 0..input.len() - 4 {
+            assert!(decoder.decode(&input[..end], &limits()).unwrap().is_none(), "complete after {} bytes", end);
+        }
+        let (decoded, used) = decoder.decode(input, &limits()).unwrap().unwrap();
+        assert_eq!(decoded.body, b"Hello, World");
+        assert_eq!(decoded.trailers, vec![("Name".to_string(), "x".to_string())]);
+        assert_eq!(&input[used..], b"NEXT");
+    }
+
+    #[test]
+    fn rejects_oversize_input() {
+        // The body limit applies to all chunks together
+        assert!(matches!(decode(b"41\r\n"), Err(ReadError::BodyTooLarge)));
+        let mut input = b"20\r\n".to_vec();
+        input.extend_from_slice(&[b'a'; 32]);
+        input.extend_from_slice(b"\r\n21\r\n");
+        assert!(matches!(decode(&input), Err(ReadError::BodyTooLarge)));
+
+        let long_line = [b'1'; MAX_CHUNK_LINE + 1];
+        assert!(matches!(decode(&long_line), Err(ReadError::BadRequest(_))));
+
+        let mut trailers = b"0\r\n".to_vec();
+        trailers.extend_from_slice(&[b'a'; 200]);
+        assert!(matches!(decode(&trailers), Err(ReadError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn rejects_overflowing_sizes() {
+        assert!(matches!(decode(b"1\r\nA\r\nFFFFFFFFFFFFFFFF\r\n"), Err(ReadError::BodyTooLarge)));
+        assert!(matches!(decode(b"10000000000000000\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"00000000000000001\r\n"), Err(ReadError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,167 @@
+/// Length of the header in front of every frame
+pub const FRAME_HEADER_SIZE: usize = 9;
+
+/// Frame types of RFC 9113 section 6.
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const PRIORITY: u8 = 0x2;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+/// Frame flags, their meaning depends on the frame type.
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+/// Settings identifiers.
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Error codes sent in RST_STREAM and GOAWAY frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+}
+
+/// The fixed part of a frame.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    pub length: usize,
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+}
+
+impl FrameHeader {
+    /// Reads a frame header, `None` until all 9 bytes are there
+    pub fn parse(buffer: &[u8]) -> Option<FrameHeader> {
+        if buffer.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+
+        Some(FrameHeader {
+            length: u32::from_be_bytes([0, buffer[0], buffer[1], buffer[2]]) as usize,
+            kind: buffer[3],
+            flags: buffer[4],
+            // The highest bit is reserved and ignored
+            stream_id: read_u32(&buffer[5..9]) & 0x7fff_ffff,
+        })
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Appends a frame to the output
+pub fn write_frame(output: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let length = (payload.len() as u32).to_be_bytes();
+    output.extend_from_slice(&length[1..]);
+    output.push(kind);
+    output.push(flags);
+    output.extend_from_slice(&stream_id.to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+pub fn write_settings(output: &mut Vec<u8>, settings: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for (id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    write_frame(output, SETTINGS, 0, 0, &payload);
+}
+
+pub fn write_window_update(output: &mut Vec<u8>, stream_id: u32, increment: u32) {
+    write_frame(output, WINDOW_UPDATE, 0, stream_id, &increment.to_be_bytes());
+}
+
+pub fn write_rst_stream(output: &mut Vec<u8>, stream_id: u32, error: ErrorCode) {
+    write_frame(output, RST_STREAM, 0, stream_id, &(error as u32).to_be_bytes());
+}
+
+pub fn write_goaway(output: &mut Vec<u8>, last_stream_id: u32, error: ErrorCode) {
+    let mut payload = last_stream_id.to_be_bytes().to_vec();
+    payload.extend_from_slice(&(error as u32).to_be_bytes());
+    write_frame(output, GOAWAY, 0, 0, &payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_frames_parse_again() {
+        let mut output = Vec::new();
+        write_frame(&mut output, HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 3, b"block");
+        assert_eq!(output[..FRAME_HEADER_SIZE], [0, 0, 5, HEADERS, 0x5, 0, 0, 0, 3]);
+
+        let header = FrameHeader::parse(&output).unwrap();
+        assert_eq!(header.length, 5);
+        assert_eq!(header.kind, HEADERS);
+        assert_eq!(header.stream_id, 3);
+        assert!(header.has_flag(FLAG_END_HEADERS));
+        assert!(header.has_flag(FLAG_END_STREAM));
+        assert!(!header.has_flag(FLAG_PADDED));
+        assert_eq!(&output[FRAME_HEADER_SIZE..], b"block");
+    }
+
+    #[test]
+    fn length_uses_24_bits() {
+        let mut output = Vec::new();
+        write_frame(&mut output, DATA, 0, 1, &vec![0; 70_000]);
+        assert_eq!(output[..3], [0x01, 0x11, 0x70]);
+        assert_eq!(FrameHeader::parse(&output).unwrap().length, 70_000);
+    }
+
+    #[test]
+    fn incomplete_header_is_none() {
+        assert!(FrameHeader::parse(&[0, 0, 0, DATA, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn reserved_bit_is_ignored() {
+        let header = FrameHeader::parse(&[0, 0, 0, DATA, 0, 0x80, 0, 0, 7]).unwrap();
+        assert_eq!(header.stream_id, 7);
+    }
+
+    #[test]
+    fn control_frame_payloads() {
+        let mut output = Vec::new();
+        write_settings(&mut output, &[(SETTINGS_MAX_CONCURRENT_STREAMS, 100), (SETTINGS_ENABLE_PUSH, 0)]);
+        assert_eq!(output, [0, 0, 12, SETTINGS, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100, 0, 2, 0, 0, 0, 0]);
+
+        output.clear();
+        write_window_update(&mut output, 1, 1000);
+        assert_eq!(output, [0, 0, 4, WINDOW_UPDATE, 0, 0, 0, 0, 1, 0, 0, 0x03, 0xe8]);
+
+        output.clear();
+        write_rst_stream(&mut output, 5, ErrorCode::RefusedStream);
+        assert_eq!(output, [0, 0, 4, RST_STREAM, 0, 0, 0, 0, 5, 0, 0, 0, 7]);
+
+        output.clear();
+        write_goaway(&mut output, 9, ErrorCode::CompressionError);
+        assert_eq!(output, [0, 0, 8, GOAWAY, 0, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 9]);
+    }
+}
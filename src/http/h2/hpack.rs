@@ -0,0 +1,589 @@
+use std::collections::VecDeque;
+use crate::http::h2::huffman;
+
+/// Header fields every HPACK peer knows, RFC 7541 appendix A. Index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Size of the dynamic table the decoder allows, the default of the protocol
+pub const HEADER_TABLE_SIZE: usize = 4096;
+
+/// Every dynamic table entry counts with this overhead
+const ENTRY_OVERHEAD: usize = 32;
+
+/// A decoded header field, names and values are not necessarily UTF-8
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Why a header block was not decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The block is malformed, the connection cannot continue after it
+    Compression,
+    /// The block was decoded but its fields exceed the size limit
+    TooLarge,
+}
+
+/// A malformed header block
+#[derive(Debug)]
+struct CompressionError;
+
+impl From<CompressionError> for DecodeError {
+    fn from(_: CompressionError) -> Self {
+        DecodeError::Compression
+    }
+}
+
+/// Decodes header blocks, keeps the dynamic table between the blocks of one connection.
+pub struct Decoder {
+    dynamic_table: VecDeque<Field>,
+    table_size: usize,
+    max_table_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            dynamic_table: VecDeque::new(),
+            table_size: 0,
+            max_table_size: HEADER_TABLE_SIZE,
+        }
+    }
+
+    /// Decodes a complete header block into name and value pairs.
+    /// Fields beyond `max_size` are dropped, the block is still decoded to keep the table in sync.
+    pub fn decode(&mut self, block: &[u8], max_size: usize) -> Result<Vec<Field>, DecodeError> {
+        let mut input = Input { data: block, position: 0 };
+        let mut fields = Vec::new();
+        let mut decoded_size = 0;
+
+        while !input.is_empty() {
+            let first = input.peek()?;
+
+            let (name, value) = if first & 0x80 != 0 {
+                // Indexed field
+                let index = input.integer(7)?;
+                self.entry(index)?
+            } else if first & 0x40 != 0 {
+                // Literal which is added to the dynamic table
+                let (name, value) = self.literal(&mut input, 6)?;
+                self.insert(name.clone(), value.clone());
+                (name, value)
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update, only allowed at the start of a block
+                if decoded_size > 0 {
+                    return Err(DecodeError::Compression);
+                }
+                let size = input.integer(5)?;
+                if size > HEADER_TABLE_SIZE {
+                    return Err(DecodeError::Compression);
+                }
+                self.max_table_size = size;
+                self.evict();
+                continue;
+            } else {
+                // Literal without indexing or never indexed
+                self.literal(&mut input, 4)?
+            };
+
+            decoded_size += name.len() + value.len() + ENTRY_OVERHEAD;
+            if decoded_size <= max_size {
+                fields.push((name, value));
+            }
+        }
+
+        if decoded_size > max_size {
+            return Err(DecodeError::TooLarge);
+        }
+        Ok(fields)
+    }
+
+    fn literal(&self, input: &mut Input, prefix: u8) -> Result<Field, CompressionError> {
+        let index = input.integer(prefix)?;
+        let name = match index {
+            0 => input.string()?,
+            index => self.entry(index)?.0,
+        };
+        let value = input.string()?;
+        Ok((name, value))
+    }
+
+    /// Looks up an index of the static or dynamic table
+    fn entry(&self, index: usize) -> Result<Field, CompressionError> {
+        if index == 0 {
+            return Err(CompressionError);
+        }
+        if let Some(&(name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.dynamic_table
+            .get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .ok_or(CompressionError)
+    }
+
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+
+        // An entry larger than the table empties it
+        if size > self.max_table_size {
+            self.dynamic_table.clear();
+            self.table_size = 0;
+            return;
+        }
+
+        self.dynamic_table.push_front((name, value));
+        self.table_size += size;
+        self.evict();
+    }
+
+    /// Drops the oldest entries until the table fits its maximum size
+    fn evict(&mut self) {
+        while self.table_size > self.max_table_size {
+            match self.dynamic_table.pop_back() {
+                Some((name, value)) => self.table_size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Cursor over a header block
+struct Input<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Input<'_> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn peek(&self) -> Result<u8, CompressionError> {
+        self.data.get(self.position).copied().ok_or(CompressionError)
+    }
+
+    fn next(&mut self) -> Result<u8, CompressionError> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    /// Integer with an N-bit prefix, RFC 7541 section 5.1
+    fn integer(&mut self, prefix: u8) -> Result<usize, CompressionError> {
+        let mask = (1u8 << prefix) - 1;
+        let mut value = (self.next()? & mask) as usize;
+        if value < mask as usize {
+            return Ok(value);
+        }
+
+        let mut shift = 0;
+        loop {
+            let byte = self.next()?;
+            // Anything larger than this is an attack, not a header
+            if shift > 28 {
+                return Err(CompressionError);
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// String literal, optionally Huffman encoded
+    fn string(&mut self) -> Result<Vec<u8>, CompressionError> {
+        let huffman_encoded = self.peek()? & 0x80 != 0;
+        let length = self.integer(7)?;
+        let end = self.position.checked_add(length).filter(|&end| end <= self.data.len()).ok_or(CompressionError)?;
+        let raw = &self.data[self.position..end];
+        self.position = end;
+
+        if huffman_encoded {
+            huffman::decode(raw).ok_or(CompressionError)
+        } else {
+            Ok(raw.to_vec())
+        }
+    }
+}
+
+/// Encodes header fields as literals without indexing.
+/// The server never uses the dynamic table, so the client's table size does not matter.
+pub fn encode<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(fields: I) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        block.push(0x00);
+        encode_string(&mut block, name.as_bytes());
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+fn encode_string(block: &mut Vec<u8>, value: &[u8]) {
+    encode_integer(block, value.len(), 7, 0x00);
+    block.extend_from_slice(value);
+}
+
+fn encode_integer(block: &mut Vec<u8>, mut value: usize, prefix: u8, flags: u8) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        block.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes written as hex like in RFC 7541 Appendix C, spaces are ignored
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(|byte| !byte.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(expected: &[(&str, &str)]) -> Vec<Field> {
+        expected.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+    }
+
+    /// The dynamic table from the newest to the oldest entry
+    fn table(decoder: &Decoder) -> Vec<(String, String)> {
+        decoder
+            .dynamic_table
+            .iter()
+            .map(|(name, value)| (String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(value).into_owned()))
+            .collect()
+    }
+
+    fn decode(decoder: &mut Decoder, block: &[u8]) -> Vec<Field> {
+        decoder.decode(block, usize::MAX).unwrap()
+    }
+
+    #[test]
+    fn integers_c1_1_and_c1_2() {
+        let mut block = Vec::new();
+        encode_integer(&mut block, 10, 5, 0);
+        encode_integer(&mut block, 1337, 5, 0);
+        assert_eq!(block, hex("0a 1f9a0a"));
+
+        let mut input = Input { data: &block, position: 0 };
+        assert_eq!(input.integer(5).unwrap(), 10);
+        assert_eq!(input.integer(5).unwrap(), 1337);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn literal_with_indexing_c2_1() {
+        let mut decoder = Decoder::new();
+        let block = hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        assert_eq!(decode(&mut decoder, &block), fields(&[("custom-key", "custom-header")]));
+        assert_eq!(decoder.table_size, 55);
+    }
+
+    #[test]
+    fn literal_without_indexing_c2_2() {
+        let mut decoder = Decoder::new();
+        let block = hex("040c 2f73 616d 706c 652f 7061 7468");
+        assert_eq!(decode(&mut decoder, &block), fields(&[(":path", "/sample/path")]));
+        assert_eq!(decoder.table_size, 0);
+    }
+
+    #[test]
+    fn literal_never_indexed_c2_3() {
+        let mut decoder = Decoder::new();
+        let block = hex("1008 7061 7373 776f 7264 0673 6563 7265 74");
+        assert_eq!(decode(&mut decoder, &block), fields(&[("password", "secret")]));
+        assert_eq!(decoder.table_size, 0);
+    }
+
+    #[test]
+    fn indexed_field_c2_4() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decode(&mut decoder, &hex("82")), fields(&[(":method", "GET")]));
+        assert_eq!(decoder.table_size, 0);
+    }
+
+    /// C.3 and C.4 send the same requests, without and with Huffman coding
+    fn check_requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new();
+
+        let first = decode(&mut decoder, &hex(blocks[0]));
+        assert_eq!(
+            first,
+            fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")])
+        );
+        assert_eq!(table(&decoder), [(":authority".to_string(), "www.example.com".to_string())]);
+        assert_eq!(decoder.table_size, 57);
+
+        let second = decode(&mut decoder, &hex(blocks[1]));
+        assert_eq!(
+            second,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_eq!(decoder.table_size, 110);
+
+        let third = decode(&mut decoder, &hex(blocks[2]));
+        assert_eq!(
+            third,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(
+            table(&decoder),
+            [
+                ("custom-key".to_string(), "custom-value".to_string()),
+                ("cache-control".to_string(), "no-cache".to_string()),
+                (":authority".to_string(), "www.example.com".to_string()),
+            ]
+        );
+        assert_eq!(decoder.table_size, 164);
+    }
+
+    #[test]
+    fn requests_without_huffman_c3() {
+        check_requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn requests_with_huffman_c4() {
+        check_requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    /// C.5 and C.6 send the same responses to a decoder with a 256 byte table, which has to evict
+    fn check_responses(blocks: [Vec<u8>; 3]) {
+        let mut decoder = Decoder::new();
+        decoder.max_table_size = 256;
+
+        let first = decode(&mut decoder, &blocks[0]);
+        assert_eq!(
+            first,
+            fields(&[
+                (":status", "302"),
+                ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com"),
+            ])
+        );
+        assert_eq!(decoder.table_size, 222);
+
+        // ":status: 302" is evicted
+        let second = decode(&mut decoder, &blocks[1]);
+        assert_eq!(
+            second,
+            fields(&[
+                (":status", "307"),
+                ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com"),
+            ])
+        );
+        assert_eq!(decoder.table_size, 222);
+        assert_eq!(table(&decoder)[0], (":status".to_string(), "307".to_string()));
+
+        let cookie = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+        let third = decode(&mut decoder, &blocks[2]);
+        assert_eq!(
+            third,
+            fields(&[
+                (":status", "200"),
+                ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+                ("location", "https://www.example.com"),
+                ("content-encoding", "gzip"),
+                ("set-cookie", cookie),
+            ])
+        );
+        assert_eq!(
+            table(&decoder),
+            [
+                ("set-cookie".to_string(), cookie.to_string()),
+                ("content-encoding".to_string(), "gzip".to_string()),
+                ("date".to_string(), "Mon, 21 Oct 2013 20:13:22 GMT".to_string()),
+            ]
+        );
+        assert_eq!(decoder.table_size, 215);
+    }
+
+    #[test]
+    fn responses_without_huffman_c5() {
+        check_responses([
+            hex("4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133
+                 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"),
+            hex("4803 3330 37c1 c0bf"),
+            hex("88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d 54c0 5a04 677a
+                 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d
+                 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e 3d31"),
+        ]);
+    }
+
+    #[test]
+    fn responses_with_huffman_c6() {
+        check_responses([
+            hex("4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6 2d1b ff6e 919d
+                 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3"),
+            hex("4883 640e ffc1 c0bf"),
+            hex("88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab 77ad 94e7 821d
+                 d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 0fb5 291f 9587 3160 65c0 03ed 4ee5 b106
+                 3d50 07"),
+        ]);
+    }
+
+    #[test]
+    fn encoded_fields_decode_again() {
+        let long_value = "x".repeat(300);
+        let sent = [(":status", "200"), ("content-type", "application/json"), ("x-long", long_value.as_str())];
+        let block = encode(sent);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decode(&mut decoder, &block), fields(&sent));
+        // Literals without indexing leave the table alone
+        assert_eq!(decoder.table_size, 0);
+    }
+
+    #[test]
+    fn table_size_update_at_the_start_evicts() {
+        let mut decoder = Decoder::new();
+        decode(&mut decoder, &hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572"));
+        assert_eq!(decoder.table_size, 55);
+
+        // Size 0 empties the table, then size 4096 allows new entries again
+        assert_eq!(decode(&mut decoder, &hex("20 3fe1 1f 82")), fields(&[(":method", "GET")]));
+        assert_eq!(decoder.table_size, 0);
+        assert_eq!(decoder.max_table_size, 4096);
+
+        // The entry is gone, so its index is invalid now
+        assert!(matches!(decoder.decode(&hex("be"), usize::MAX), Err(DecodeError::Compression)));
+    }
+
+    #[test]
+    fn table_size_update_after_a_field_is_an_error() {
+        let mut decoder = Decoder::new();
+        assert!(matches!(decoder.decode(&hex("82 20"), usize::MAX), Err(DecodeError::Compression)));
+    }
+
+    #[test]
+    fn table_size_update_above_the_limit_is_an_error() {
+        let mut decoder = Decoder::new();
+        // 4097 with a 5-bit prefix
+        assert!(matches!(decoder.decode(&hex("3fe2 1f"), usize::MAX), Err(DecodeError::Compression)));
+    }
+
+    #[test]
+    fn malformed_blocks_are_errors() {
+        for block in [
+            // Index 0 does not exist
+            "80",
+            // Beyond the static table while the dynamic table is empty
+            "be",
+            // The string is shorter than announced
+            "400a 6375 7374",
+            // The integer never ends
+            "ff ff ff ff ff ff ff",
+            // Huffman string which ends with EOS
+            "4084 ffff ffff 00",
+        ] {
+            let mut decoder = Decoder::new();
+            assert!(matches!(decoder.decode(&hex(block), usize::MAX), Err(DecodeError::Compression)), "{}", block);
+        }
+    }
+
+    #[test]
+    fn fields_beyond_the_size_limit_keep_the_table_in_sync() {
+        let mut decoder = Decoder::new();
+        let block = hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        assert!(matches!(decoder.decode(&block, 40), Err(DecodeError::TooLarge)));
+        // The entry was still added, the next block can refer to it
+        assert_eq!(decode(&mut decoder, &hex("be")), fields(&[("custom-key", "custom-header")]));
+    }
+}
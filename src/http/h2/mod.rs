@@ -0,0 +1,1076 @@
+pub mod frame;
+pub mod hpack;
+mod huffman;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use crate::http::h2::frame::*;
+use crate::http::h2::hpack::{DecodeError, Decoder};
+use crate::http::headers::{is_field_value, is_token, trim_whitespace, HeaderMap};
+use crate::http::request::{HttpRequest, HttpVersion, Method, ParseError};
+use crate::http::request_reader::{parse_length, RequestLimits};
+use crate::http::response::HttpResponse;
+
+/// Every HTTP/2 connection starts with these bytes, over TLS and in cleartext (h2c)
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Streams one client may have open at the same time
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Window size and frame size every connection starts with
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// Header fields which only mean something for HTTP/1 connections
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// The request half of a stream, which is complete once the client ends the stream.
+struct Stream {
+    method: String,
+    target: String,
+    headers: HeaderMap,
+    trailers: HeaderMap,
+    body: Vec<u8>,
+    /// The client has sent END_STREAM
+    remote_closed: bool,
+    /// Received more than the body limit, the rest of the body is dropped
+    body_too_large: bool,
+    /// The request was handed out and waits for its response
+    dispatched: bool,
+    /// The response headers were sent, the body follows
+    responded: bool,
+    /// Flow control window for the response body
+    send_window: i64,
+    /// Response body which waits for the flow control window
+    pending: Vec<u8>,
+    sent: usize,
+    /// The response body is streamed, the stream stays open until `end_stream`
+    streaming: bool,
+}
+
+/// A header block split over HEADERS and CONTINUATION frames.
+struct PartialHeaders {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// The server side of a HTTP/2 connection without any I/O.
+/// Received bytes go into `receive`, complete requests come out of `next_request`,
+/// responses go into `respond` and everything to send is collected in `take_output`.
+pub struct Http2Session {
+    limits: RequestLimits,
+    peer_addr: Option<SocketAddr>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    preface_received: bool,
+    settings_received: bool,
+    decoder: Decoder,
+    streams: HashMap<u32, Stream>,
+    partial_headers: Option<PartialHeaders>,
+    ready: VecDeque<(u32, Result<HttpRequest, ParseError>)>,
+    /// Highest stream the client opened
+    last_stream_id: u32,
+    /// Flow control window of the whole connection for sending
+    send_window: i64,
+    /// Settings of the client which apply to what the server sends
+    initial_window_size: i64,
+    max_frame_size: usize,
+    /// A GOAWAY was sent or received, no new streams are accepted
+    going_away: bool,
+    /// A connection error ended the session
+    failed: bool,
+}
+
+impl Http2Session {
+    /// Starts a session, the server settings are the first output
+    pub fn new(limits: RequestLimits, peer_addr: Option<SocketAddr>) -> Self {
+        let mut output = Vec::new();
+        write_settings(
+            &mut output,
+            &[
+                (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+                (SETTINGS_ENABLE_PUSH, 0),
+                (SETTINGS_MAX_HEADER_LIST_SIZE, limits.max_header_size as u32),
+            ],
+        );
+
+        Http2Session {
+            limits,
+            peer_addr,
+            input: Vec::new(),
+            output,
+            preface_received: false,
+            settings_received: false,
+            decoder: Decoder::new(),
+            streams: HashMap::new(),
+            partial_headers: None,
+            ready: VecDeque::new(),
+            last_stream_id: 0,
+            send_window: DEFAULT_WINDOW_SIZE,
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            going_away: false,
+            failed: false,
+        }
+    }
+
+    /// Processes received bytes, incomplete frames wait for the next call
+    pub fn receive(&mut self, data: &[u8]) {
+        if self.failed {
+            return;
+        }
+        self.input.extend_from_slice(data);
+
+        if !self.preface_received {
+            let length = self.input.len().min(PREFACE.len());
+            if self.input[..length] != PREFACE[..length] {
+                return self.connection_error(ErrorCode::ProtocolError);
+            }
+            if length < PREFACE.len() {
+                return;
+            }
+            self.input.drain(..PREFACE.len());
+            self.preface_received = true;
+        }
+
+        let mut position = 0;
+        while let Some(header) = FrameHeader::parse(&self.input[position..]) {
+            if header.length > DEFAULT_MAX_FRAME_SIZE {
+                return self.connection_error(ErrorCode::FrameSizeError);
+            }
+
+            let end = position + FRAME_HEADER_SIZE + header.length;
+            if self.input.len() < end {
+                break;
+            }
+
+            let payload = self.input[position + FRAME_HEADER_SIZE..end].to_vec();
+            position = end;
+
+            if let Err(error) = self.handle_frame(header, &payload) {
+                return self.connection_error(error);
+            }
+        }
+        self.input.drain(..position);
+    }
+
+    /// The next request whose stream the client has ended
+    pub fn next_request(&mut self) -> Option<(u32, Result<HttpRequest, ParseError>)> {
+        self.ready.pop_front()
+    }
+
+    /// Queues the response of a stream, the body is sent as the flow control windows allow
+    pub fn respond(&mut self, stream_id: u32, response: HttpResponse) {
+        self.send_response(stream_id, response, false);
+    }
+
+    /// Sends the response head and keeps the stream open, the body follows with `send_data`
+    pub fn respond_streaming(&mut self, stream_id: u32, response: HttpResponse) {
+        self.send_response(stream_id, response, true);
+    }
+
+    /// Appends to a streamed response body, `false` once the stream is gone
+    pub fn send_data(&mut self, stream_id: u32, data: &[u8]) -> bool {
+        match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.streaming => stream.pending.extend_from_slice(data),
+            _ => return false,
+        }
+        self.send_pending(stream_id);
+        true
+    }
+
+    /// Ends a streamed response once the data queued so far is sent
+    pub fn end_stream(&mut self, stream_id: u32) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.streaming => stream,
+            _ => return,
+        };
+        stream.streaming = false;
+
+        if stream.sent == stream.pending.len() {
+            write_frame(&mut self.output, DATA, FLAG_END_STREAM, stream_id, &[]);
+            if !stream.remote_closed {
+                write_rst_stream(&mut self.output, stream_id, ErrorCode::NoError);
+            }
+            self.streams.remove(&stream_id);
+        } else {
+            self.send_pending(stream_id);
+        }
+    }
+
+    fn send_response(&mut self, stream_id: u32, response: HttpResponse, streaming: bool) {
+        let max_frame_size = self.max_frame_size;
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            // The client reset the stream in the meantime
+            None => return,
+        };
+
+        // HEAD responses announce the length of the body without sending it
+        let send_body = stream.method != Method::Head.as_str() && response.status.allows_body();
+        let content_length = response.body.len().to_string();
+
+        let mut fields: Vec<(String, &str)> = Vec::new();
+        let status = response.status.code().to_string();
+        fields.push((":status".to_string(), &status));
+        for (name, value) in response.headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_HEADERS.contains(&name.as_str()) && name != "content-length" {
+                fields.push((name, value));
+            }
+        }
+        if response.status.allows_body() && !streaming {
+            fields.push(("content-length".to_string(), &content_length));
+        }
+
+        let block = hpack::encode(fields.iter().map(|(name, value)| (name.as_str(), *value)));
+        let streaming = streaming && send_body;
+        let end_stream = !streaming && (!send_body || response.body.is_empty());
+
+        // The header block may need CONTINUATION frames
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let last = chunks.peek().is_none();
+            let mut flags = if last { FLAG_END_HEADERS } else { 0 };
+            if kind == HEADERS && end_stream {
+                flags |= FLAG_END_STREAM;
+            }
+            write_frame(&mut self.output, kind, flags, stream_id, chunk);
+            kind = CONTINUATION;
+            if last {
+                break;
+            }
+        }
+
+        if send_body {
+            stream.pending = response.body;
+        }
+        stream.sent = 0;
+        stream.responded = true;
+        stream.streaming = streaming;
+        self.send_pending(stream_id);
+    }
+
+    /// Sends GOAWAY, the streams already started are still answered
+    pub fn go_away(&mut self) {
+        if !self.going_away && !self.failed {
+            self.going_away = true;
+            write_goaway(&mut self.output, self.last_stream_id, ErrorCode::NoError);
+        }
+    }
+
+    /// Everything which should be written to the connection
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// The connection can be closed once the output is written
+    pub fn is_finished(&self) -> bool {
+        self.failed || (self.going_away && self.streams.is_empty())
+    }
+
+    /// Streams which are still receiving or wait for their response
+    pub fn has_open_streams(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    fn connection_error(&mut self, error: ErrorCode) {
+        eprintln!("HTTP/2 connection error: {:?}", error);
+        write_goaway(&mut self.output, self.last_stream_id, error);
+        self.failed = true;
+        self.input.clear();
+        self.streams.clear();
+        self.ready.clear();
+    }
+
+    fn stream_error(&mut self, stream_id: u32, error: ErrorCode) {
+        write_rst_stream(&mut self.output, stream_id, error);
+        self.streams.remove(&stream_id);
+    }
+
+    fn handle_frame(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        // The client preface ends with its settings
+        if !self.settings_received && (header.kind != SETTINGS || header.has_flag(FLAG_ACK)) {
+            return Err(ErrorCode::ProtocolError);
+        }
+
+        // A header block may not be interrupted by other frames
+        if let Some(partial) = &self.partial_headers {
+            if header.kind != CONTINUATION || header.stream_id != partial.stream_id {
+                return Err(ErrorCode::ProtocolError);
+            }
+        }
+
+        match header.kind {
+            DATA => self.handle_data(header, payload),
+            HEADERS => self.handle_headers(header, payload),
+            CONTINUATION => self.handle_continuation(header, payload),
+            PRIORITY => match payload.len() {
+                // Priorities are ignored, every stream is answered in order
+                5 => Ok(()),
+                _ => Err(ErrorCode::FrameSizeError),
+            },
+            RST_STREAM => {
+                if payload.len() != 4 {
+                    return Err(ErrorCode::FrameSizeError);
+                }
+                if header.stream_id == 0 || header.stream_id > self.last_stream_id {
+                    return Err(ErrorCode::ProtocolError);
+                }
+                self.streams.remove(&header.stream_id);
+                self.ready.retain(|(stream_id, _)| *stream_id != header.stream_id);
+                Ok(())
+            }
+            SETTINGS => self.handle_settings(header, payload),
+            PUSH_PROMISE => Err(ErrorCode::ProtocolError),
+            PING => {
+                if payload.len() != 8 {
+                    return Err(ErrorCode::FrameSizeError);
+                }
+                if header.stream_id != 0 {
+                    return Err(ErrorCode::ProtocolError);
+                }
+                if !header.has_flag(FLAG_ACK) {
+                    write_frame(&mut self.output, PING, FLAG_ACK, 0, payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if header.stream_id != 0 {
+                    return Err(ErrorCode::ProtocolError);
+                }
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.handle_window_update(header, payload),
+            // Unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_data(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        if header.stream_id == 0 {
+            return Err(ErrorCode::ProtocolError);
+        }
+        let data = unpadded(&header, payload).ok_or(ErrorCode::ProtocolError)?;
+
+        // Give the whole frame back to the connection window right away
+        if !payload.is_empty() {
+            write_window_update(&mut self.output, 0, payload.len() as u32);
+        }
+
+        let max_body_size = self.limits.max_body_size;
+        let stream = match self.streams.get_mut(&header.stream_id) {
+            Some(stream) if !stream.remote_closed => stream,
+            Some(_) => {
+                self.stream_error(header.stream_id, ErrorCode::StreamClosed);
+                return Ok(());
+            }
+            None if header.stream_id > self.last_stream_id => return Err(ErrorCode::ProtocolError),
+            // Data of a stream which was already answered or reset
+            None => return Ok(()),
+        };
+
+        if stream.body.len() + data.len() > max_body_size {
+            stream.body_too_large = true;
+            stream.body.clear();
+        } else if !stream.body_too_large {
+            stream.body.extend_from_slice(data);
+        }
+
+        if header.has_flag(FLAG_END_STREAM) {
+            self.finish_request(header.stream_id);
+        } else if !payload.is_empty() {
+            write_window_update(&mut self.output, header.stream_id, payload.len() as u32);
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        if header.stream_id == 0 || header.stream_id.is_multiple_of(2) {
+            return Err(ErrorCode::ProtocolError);
+        }
+
+        let mut fragment = unpadded(&header, payload).ok_or(ErrorCode::ProtocolError)?;
+        if header.has_flag(FLAG_PRIORITY) {
+            fragment = fragment.get(5..).ok_or(ErrorCode::ProtocolError)?;
+        }
+
+        let partial = PartialHeaders {
+            stream_id: header.stream_id,
+            end_stream: header.has_flag(FLAG_END_STREAM),
+            block: fragment.to_vec(),
+        };
+
+        if header.has_flag(FLAG_END_HEADERS) {
+            self.handle_header_block(partial)
+        } else {
+            self.partial_headers = Some(partial);
+            Ok(())
+        }
+    }
+
+    fn handle_continuation(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        let mut partial = self.partial_headers.take().ok_or(ErrorCode::ProtocolError)?;
+        partial.block.extend_from_slice(payload);
+
+        // A header block which never ends only costs memory
+        if partial.block.len() > self.limits.max_header_size.max(DEFAULT_MAX_FRAME_SIZE) * 4 {
+            return Err(ErrorCode::ProtocolError);
+        }
+
+        if header.has_flag(FLAG_END_HEADERS) {
+            self.handle_header_block(partial)
+        } else {
+            self.partial_headers = Some(partial);
+            Ok(())
+        }
+    }
+
+    fn handle_header_block(&mut self, partial: PartialHeaders) -> Result<(), ErrorCode> {
+        let stream_id = partial.stream_id;
+
+        // Always decode, the block changes the decoder state even if the stream is refused
+        let fields = match self.decoder.decode(&partial.block, self.limits.max_header_size) {
+            Ok(fields) => Some(fields),
+            Err(DecodeError::TooLarge) => None,
+            Err(DecodeError::Compression) => return Err(ErrorCode::CompressionError),
+        };
+
+        // A second header block on an open stream carries the trailers
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.remote_closed || !partial.end_stream {
+                self.stream_error(stream_id, ErrorCode::ProtocolError);
+                return Ok(());
+            }
+            for (name, value) in fields.unwrap_or_default() {
+                if name.starts_with(b":") {
+                    self.stream_error(stream_id, ErrorCode::ProtocolError);
+                    return Ok(());
+                }
+                stream.trailers.insert(&String::from_utf8_lossy(&name), &String::from_utf8_lossy(&value));
+            }
+            self.finish_request(stream_id);
+            return Ok(());
+        }
+
+        // A late trailer block of a stream which was already answered or reset, only that stream is closed
+        if stream_id <= self.last_stream_id {
+            write_rst_stream(&mut self.output, stream_id, ErrorCode::StreamClosed);
+            return Ok(());
+        }
+        self.last_stream_id = stream_id;
+
+        if self.going_away || self.streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            self.stream_error(stream_id, ErrorCode::RefusedStream);
+            return Ok(());
+        }
+
+        let mut stream = Stream {
+            method: String::new(),
+            target: String::new(),
+            headers: HeaderMap::new(),
+            trailers: HeaderMap::new(),
+            body: Vec::new(),
+            remote_closed: false,
+            body_too_large: false,
+            dispatched: false,
+            responded: false,
+            send_window: self.initial_window_size,
+            pending: Vec::new(),
+            sent: 0,
+            streaming: false,
+        };
+
+        match fields {
+            Some(fields) => {
+                if read_request_head(&mut stream, fields).is_err() {
+                    // Malformed requests are reset, RFC 9113 section 8.1.1
+                    write_rst_stream(&mut self.output, stream_id, ErrorCode::ProtocolError);
+                    return Ok(());
+                }
+            }
+            None => {
+                // Still answered, like a HTTP/1 request with too large headers
+                stream.dispatched = true;
+                stream.remote_closed = partial.end_stream;
+                self.streams.insert(stream_id, stream);
+                self.ready.push_back((stream_id, Err(ParseError::HeadersTooLarge)));
+                return Ok(());
+            }
+        }
+
+        self.streams.insert(stream_id, stream);
+        if partial.end_stream {
+            self.finish_request(stream_id);
+        }
+        Ok(())
+    }
+
+    /// The client ended the stream, the request is handed out
+    fn finish_request(&mut self, stream_id: u32) {
+        let peer_addr = self.peer_addr;
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        stream.remote_closed = true;
+        if stream.dispatched {
+            return;
+        }
+        stream.dispatched = true;
+
+        if stream.body_too_large {
+            self.ready.push_back((stream_id, Err(ParseError::BodyTooLarge)));
+            return;
+        }
+
+        // The announced length has to match the DATA frames, every repeated value alike
+        let length_matches = stream
+            .headers
+            .get_all("content-length")
+            .all(|value| parse_length(value) == Some(stream.body.len()));
+        if !length_matches {
+            self.stream_error(stream_id, ErrorCode::ProtocolError);
+            return;
+        }
+
+        let headers = std::mem::take(&mut stream.headers);
+        let body = std::mem::take(&mut stream.body);
+        let result = HttpRequest::from_parts(&stream.method, &stream.target, HttpVersion::Http2, headers, body).map(|mut request| {
+            request.trailers = std::mem::take(&mut stream.trailers);
+            request.peer_addr = peer_addr;
+            request
+        });
+        self.ready.push_back((stream_id, result));
+    }
+
+    fn handle_settings(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        if header.stream_id != 0 {
+            return Err(ErrorCode::ProtocolError);
+        }
+        if header.has_flag(FLAG_ACK) {
+            return match payload.len() {
+                0 => Ok(()),
+                _ => Err(ErrorCode::FrameSizeError),
+            };
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(ErrorCode::FrameSizeError);
+        }
+
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = read_u32(&setting[2..]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(ErrorCode::ProtocolError),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(ErrorCode::FlowControlError);
+                    }
+                    // Open streams move by the difference, RFC 9113 section 6.9.2
+                    let delta = value - self.initial_window_size;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(ErrorCode::FlowControlError);
+                        }
+                    }
+                    self.initial_window_size = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=16_777_215).contains(&value) {
+                        return Err(ErrorCode::ProtocolError);
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // The encoder never uses the dynamic table, the other settings do not limit the server
+                SETTINGS_HEADER_TABLE_SIZE | SETTINGS_MAX_CONCURRENT_STREAMS | SETTINGS_MAX_HEADER_LIST_SIZE => {}
+                _ => {}
+            }
+        }
+
+        self.settings_received = true;
+        write_frame(&mut self.output, SETTINGS, FLAG_ACK, 0, &[]);
+        self.send_all_pending();
+        Ok(())
+    }
+
+    fn handle_window_update(&mut self, header: FrameHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        if payload.len() != 4 {
+            return Err(ErrorCode::FrameSizeError);
+        }
+        let increment = (read_u32(payload) & 0x7fff_ffff) as i64;
+
+        if header.stream_id == 0 {
+            if increment == 0 {
+                return Err(ErrorCode::ProtocolError);
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(ErrorCode::FlowControlError);
+            }
+            self.send_all_pending();
+            return Ok(());
+        }
+
+        if let Some(stream) = self.streams.get_mut(&header.stream_id) {
+            stream.send_window += increment;
+            if increment == 0 {
+                self.stream_error(header.stream_id, ErrorCode::ProtocolError);
+            } else if stream.send_window > MAX_WINDOW_SIZE {
+                self.stream_error(header.stream_id, ErrorCode::FlowControlError);
+            } else {
+                self.send_pending(header.stream_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_all_pending(&mut self) {
+        let mut stream_ids: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.sent < stream.pending.len())
+            .map(|(&stream_id, _)| stream_id)
+            .collect();
+        stream_ids.sort_unstable();
+
+        for stream_id in stream_ids {
+            self.send_pending(stream_id);
+        }
+    }
+
+    /// Sends as much of the response body as the windows allow, closes the stream once it is sent
+    /// unless the body is streamed
+    fn send_pending(&mut self, stream_id: u32) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.responded => stream,
+            _ => return,
+        };
+
+        while stream.sent < stream.pending.len() {
+            let window = self.send_window.min(stream.send_window).max(0) as usize;
+            let length = (stream.pending.len() - stream.sent).min(window).min(self.max_frame_size);
+            if length == 0 {
+                return;
+            }
+
+            let end = stream.sent + length;
+            let flags = if end == stream.pending.len() && !stream.streaming { FLAG_END_STREAM } else { 0 };
+            write_frame(&mut self.output, DATA, flags, stream_id, &stream.pending[stream.sent..end]);
+            stream.sent = end;
+            self.send_window -= length as i64;
+            stream.send_window -= length as i64;
+        }
+
+        // More of the body follows later
+        if stream.streaming {
+            stream.pending.clear();
+            stream.sent = 0;
+            return;
+        }
+
+        // The response is complete, the client does not need to send the rest of the request
+        if !stream.remote_closed {
+            write_rst_stream(&mut self.output, stream_id, ErrorCode::NoError);
+        }
+        self.streams.remove(&stream_id);
+    }
+}
+
+/// Reads the pseudo-headers and fields of a request, `Err` if the request is malformed
+fn read_request_head(stream: &mut Stream, fields: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), ()> {
+    let mut scheme = None;
+    let mut authority = None;
+    let mut regular_fields = false;
+
+    for (name, value) in fields {
+        let name = String::from_utf8(name).map_err(|_| ())?;
+        let value = String::from_utf8(value).map_err(|_| ())?;
+
+        // Field names are lowercase, values may not smuggle in other fields or carry whitespace around them
+        let field_name = name.strip_prefix(':').unwrap_or(&name);
+        if !is_token(field_name) || name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            return Err(());
+        }
+        if !is_field_value(&value) || trim_whitespace(&value) != value {
+            return Err(());
+        }
+
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // Pseudo-headers come first and only once
+            let slot = match pseudo {
+                "method" => &mut stream.method,
+                "path" => &mut stream.target,
+                "scheme" => scheme.get_or_insert_with(String::new),
+                "authority" => authority.get_or_insert_with(String::new),
+                _ => return Err(()),
+            };
+            if regular_fields || !slot.is_empty() {
+                return Err(());
+            }
+            *slot = value;
+            continue;
+        }
+        regular_fields = true;
+
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return Err(());
+        }
+
+        // Repeated fields keep all their values, split cookies are combined again (RFC 9113 section 8.2.3)
+        match stream.headers.get("cookie") {
+            Some(existing) if name == "cookie" => {
+                let combined = format!("{}; {}", existing, value);
+                stream.headers.insert(&name, &combined);
+            }
+            _ => stream.headers.append(&name, &value),
+        }
+    }
+
+    if stream.method.is_empty() || stream.target.is_empty() || scheme.is_none() {
+        return Err(());
+    }
+
+    // Handlers look for the host like in HTTP/1
+    if let Some(authority) = authority {
+        if !stream.headers.contains("host") {
+            stream.headers.insert("host", &authority);
+        }
+    }
+    Ok(())
+}
+
+/// The payload of a DATA or HEADERS frame without its padding
+fn unpadded<'a>(header: &FrameHeader, payload: &'a [u8]) -> Option<&'a [u8]> {
+    if !header.has_flag(FLAG_PADDED) {
+        return Some(payload);
+    }
+    let padding = *payload.first()? as usize;
+    // The pad length byte and the padding have to fit into the payload
+    payload.get(1..payload.len().checked_sub(padding)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::response::StatusCode;
+
+    /// GET / as an HPACK block: :method GET, :scheme http, :path /, :authority www.example.com
+    const GET_BLOCK: &[u8] = b"\x82\x86\x84\x41\x0fwww.example.com";
+
+    /// A session which received the preface and the client settings, with the server settings taken
+    fn session() -> Http2Session {
+        let mut session = Http2Session::new(RequestLimits::default(), None);
+        let mut input = PREFACE.to_vec();
+        write_settings(&mut input, &[]);
+        session.receive(&input);
+        session.take_output();
+        session
+    }
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_frame(&mut output, kind, flags, stream_id, payload);
+        output
+    }
+
+    /// Splits output into frames
+    fn frames(mut output: &[u8]) -> Vec<(FrameHeader, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while let Some(header) = FrameHeader::parse(output) {
+            let end = FRAME_HEADER_SIZE + header.length;
+            frames.push((header, output[FRAME_HEADER_SIZE..end].to_vec()));
+            output = &output[end..];
+        }
+        assert!(output.is_empty());
+        frames
+    }
+
+    /// The error code of the GOAWAY the session ended with
+    fn goaway(session: &mut Http2Session) -> u32 {
+        assert!(session.is_finished());
+        let frames = frames(&session.take_output());
+        let (header, payload) = frames.last().unwrap();
+        assert_eq!(header.kind, GOAWAY);
+        read_u32(&payload[4..])
+    }
+
+    #[test]
+    fn starts_with_the_server_settings() {
+        let mut session = Http2Session::new(RequestLimits::default(), None);
+        let frames = frames(&session.take_output());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, SETTINGS);
+    }
+
+    #[test]
+    fn acknowledges_the_client_settings() {
+        let mut session = Http2Session::new(RequestLimits::default(), None);
+        session.take_output();
+        let mut input = PREFACE.to_vec();
+        write_settings(&mut input, &[(SETTINGS_INITIAL_WINDOW_SIZE, 1000)]);
+        session.receive(&input);
+
+        let frames = frames(&session.take_output());
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.kind, SETTINGS);
+        assert!(frames[0].0.has_flag(FLAG_ACK));
+    }
+
+    #[test]
+    fn preface_may_arrive_in_pieces() {
+        let mut session = Http2Session::new(RequestLimits::default(), None);
+        session.receive(&PREFACE[..10]);
+        session.receive(&PREFACE[10..]);
+        session.receive(&frame(SETTINGS, 0, 0, &[]));
+        assert!(!session.is_finished());
+    }
+
+    #[test]
+    fn wrong_preface_is_a_protocol_error() {
+        let mut session = Http2Session::new(RequestLimits::default(), None);
+        session.take_output();
+        session.receive(b"GET / HTTP/1.1\r\n");
+        assert_eq!(goaway(&mut session), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn first_frame_has_to_be_settings() {
+        let mut session = Http2Session::new(RequestLimits::default(), None);
+        session.take_output();
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(PING, 0, 0, &[0; 8]));
+        session.receive(&input);
+        assert_eq!(goaway(&mut session), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn headers_make_a_request() {
+        let mut session = session();
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, GET_BLOCK));
+
+        let (stream_id, request) = session.next_request().unwrap();
+        let request = request.unwrap();
+        assert_eq!(stream_id, 1);
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/");
+        assert_eq!(request.headers.get("host"), Some("www.example.com"));
+        assert!(session.next_request().is_none());
+    }
+
+    #[test]
+    fn frames_may_arrive_in_pieces() {
+        let mut session = session();
+        let input = frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, GET_BLOCK);
+        for byte in input {
+            session.receive(&[byte]);
+        }
+        assert!(session.next_request().unwrap().1.is_ok());
+    }
+
+    #[test]
+    fn header_block_continues_in_continuation_frames() {
+        let mut session = session();
+        let mut input = frame(HEADERS, FLAG_END_STREAM, 1, &GET_BLOCK[..5]);
+        input.extend(frame(CONTINUATION, 0, 1, &GET_BLOCK[5..10]));
+        input.extend(frame(CONTINUATION, FLAG_END_HEADERS, 1, &GET_BLOCK[10..]));
+        session.receive(&input);
+
+        let request = session.next_request().unwrap().1.unwrap();
+        assert_eq!(request.headers.get("host"), Some("www.example.com"));
+    }
+
+    #[test]
+    fn interrupted_header_block_is_a_protocol_error() {
+        let mut session = session();
+        let mut input = frame(HEADERS, FLAG_END_STREAM, 1, &GET_BLOCK[..5]);
+        input.extend(frame(PING, 0, 0, &[0; 8]));
+        session.receive(&input);
+        assert_eq!(goaway(&mut session), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn continuation_on_another_stream_is_a_protocol_error() {
+        let mut session = session();
+        let mut input = frame(HEADERS, FLAG_END_STREAM, 1, &GET_BLOCK[..5]);
+        input.extend(frame(CONTINUATION, FLAG_END_HEADERS, 3, &GET_BLOCK[5..]));
+        session.receive(&input);
+        assert_eq!(goaway(&mut session), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn continuation_without_headers_is_a_protocol_error() {
+        let mut session = session();
+        session.receive(&frame(CONTINUATION, FLAG_END_HEADERS, 1, GET_BLOCK));
+        assert_eq!(goaway(&mut session), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn padding_is_removed() {
+        let mut session = session();
+        let mut payload = vec![3];
+        payload.extend_from_slice(GET_BLOCK);
+        payload.extend_from_slice(&[0; 3]);
+        let mut input = frame(HEADERS, FLAG_END_HEADERS | FLAG_PADDED, 1, &payload);
+        input.extend(frame(DATA, FLAG_END_STREAM | FLAG_PADDED, 1, b"\x02body\0\0"));
+        session.receive(&input);
+
+        let request = session.next_request().unwrap().1.unwrap();
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn padding_longer_than_the_frame_is_a_protocol_error() {
+        for (kind, flags, stream_id) in [(HEADERS, FLAG_END_HEADERS, 1), (DATA, 0, 1)] {
+            let mut session = session();
+            if kind == DATA {
+                session.receive(&frame(HEADERS, FLAG_END_HEADERS, 1, GET_BLOCK));
+            }
+            // The pad length counts 4 bytes, only 3 follow
+            session.receive(&frame(kind, flags | FLAG_PADDED, stream_id, &[4, 0, 0, 0]));
+            assert_eq!(goaway(&mut session), ErrorCode::ProtocolError as u32, "frame type {}", kind);
+        }
+    }
+
+    #[test]
+    fn table_size_update_is_allowed_at_the_start_of_a_block() {
+        let mut session = session();
+        let mut block = vec![0x20];
+        block.extend_from_slice(GET_BLOCK);
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block));
+        assert!(session.next_request().unwrap().1.is_ok());
+    }
+
+    #[test]
+    fn table_size_update_inside_a_block_is_a_compression_error() {
+        let mut session = session();
+        let mut block = GET_BLOCK.to_vec();
+        block.push(0x20);
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block));
+        assert_eq!(goaway(&mut session), ErrorCode::CompressionError as u32);
+    }
+
+    #[test]
+    fn table_size_update_above_the_setting_is_a_compression_error() {
+        let mut session = session();
+        let mut block = vec![0x3f, 0xe2, 0x1f];
+        block.extend_from_slice(GET_BLOCK);
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block));
+        assert_eq!(goaway(&mut session), ErrorCode::CompressionError as u32);
+    }
+
+    #[test]
+    fn content_length_has_to_match_the_body() {
+        let mut session = session();
+        let mut block = GET_BLOCK.to_vec();
+        block.extend(hpack::encode([("content-length", "+4")]));
+        let mut input = frame(HEADERS, FLAG_END_HEADERS, 1, &block);
+        input.extend(frame(DATA, FLAG_END_STREAM, 1, b"body"));
+        session.receive(&input);
+
+        assert!(session.next_request().is_none());
+        let frames = frames(&session.take_output());
+        let (header, payload) = frames.last().unwrap();
+        assert_eq!((header.kind, header.stream_id), (RST_STREAM, 1));
+        assert_eq!(read_u32(payload), ErrorCode::ProtocolError as u32);
+        assert!(!session.is_finished());
+    }
+
+    #[test]
+    fn uppercase_field_names_reset_the_stream() {
+        let mut session = session();
+        let mut block = GET_BLOCK.to_vec();
+        block.extend(hpack::encode([("X-Upper", "1")]));
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block));
+
+        assert!(session.next_request().is_none());
+        let frames = frames(&session.take_output());
+        assert_eq!(frames.last().unwrap().0.kind, RST_STREAM);
+    }
+
+    #[test]
+    fn headers_on_a_closed_stream_reset_only_that_stream() {
+        let mut session = session();
+        let mut block = GET_BLOCK.to_vec();
+        block.extend(hpack::encode([("X-Upper", "1")]));
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block));
+        session.take_output();
+
+        // Trailers which were already on their way when the stream was reset
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &hpack::encode([("x-trailer", "1")])));
+        let frames = frames(&session.take_output());
+        let (header, payload) = frames.last().unwrap();
+        assert_eq!((header.kind, header.stream_id), (RST_STREAM, 1));
+        assert_eq!(read_u32(payload), ErrorCode::StreamClosed as u32);
+        assert!(!session.is_finished());
+
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 3, GET_BLOCK));
+        assert_eq!(session.next_request().unwrap().0, 3);
+    }
+
+    #[test]
+    fn response_is_sent_as_headers_and_data() {
+        let mut session = session();
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, GET_BLOCK));
+        let (stream_id, _) = session.next_request().unwrap();
+
+        session.respond(stream_id, HttpResponse::new(StatusCode::Ok).with_body("text/plain", "hello"));
+        let frames = frames(&session.take_output());
+        assert_eq!(frames.len(), 2);
+
+        let (headers, block) = &frames[0];
+        assert_eq!((headers.kind, headers.stream_id), (HEADERS, 1));
+        assert!(headers.has_flag(FLAG_END_HEADERS));
+        assert!(!headers.has_flag(FLAG_END_STREAM));
+        let fields = Decoder::new().decode(block, usize::MAX).unwrap();
+        assert_eq!(fields[0], (b":status".to_vec(), b"200".to_vec()));
+        assert!(fields.contains(&(b"content-length".to_vec(), b"5".to_vec())));
+
+        let (data, body) = &frames[1];
+        assert_eq!(data.kind, DATA);
+        assert!(data.has_flag(FLAG_END_STREAM));
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn streamed_response_keeps_the_stream_open() {
+        let mut session = session();
+        session.receive(&frame(HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, GET_BLOCK));
+        let (stream_id, _) = session.next_request().unwrap();
+
+        let response = HttpResponse::new(StatusCode::Ok).with_header("Content-Type", "text/event-stream");
+        session.respond_streaming(stream_id, response);
+        assert!(session.send_data(stream_id, b"data: 1\n\n"));
+        let sent = frames(&session.take_output());
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0.kind, HEADERS);
+        assert!(!sent[0].0.has_flag(FLAG_END_STREAM));
+        let fields = Decoder::new().decode(&sent[0].1, usize::MAX).unwrap();
+        assert!(!fields.iter().any(|(name, _)| name == b"content-length"));
+        assert_eq!((sent[1].0.kind, sent[1].1.as_slice()), (DATA, &b"data: 1\n\n"[..]));
+        assert!(!sent[1].0.has_flag(FLAG_END_STREAM));
+
+        session.end_stream(stream_id);
+        let sent = frames(&session.take_output());
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].0.has_flag(FLAG_END_STREAM));
+        assert!(sent[0].1.is_empty());
+
+        // The stream is gone, later data is refused
+        assert!(!session.send_data(stream_id, b"late"));
+    }
+
+    #[test]
+    fn oversized_frame_is_a_frame_size_error() {
+        let mut session = session();
+        session.receive(&frame(DATA, 0, 1, &vec![0; DEFAULT_MAX_FRAME_SIZE + 1]));
+        assert_eq!(goaway(&mut session), ErrorCode::FrameSizeError as u32);
+    }
+}
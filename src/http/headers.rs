@@ -0,0 +1,209 @@
+/// Header map with case-insensitive names, a name may have several values.
+/// The original spelling and insertion order are kept for serialization.
+#[derive(Debug, Default, Clone)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        HeaderMap::default()
+    }
+
+    /// Inserts a header, replacing all existing values with the same name
+    pub fn insert(&mut self, name: &str, value: &str) {
+        if !is_valid_field(name, value) {
+            return;
+        }
+
+        // The first value is replaced in place, so the header keeps its position
+        let mut replaced = false;
+        self.entries.retain_mut(|(key, existing)| {
+            if !key.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if replaced {
+                return false;
+            }
+            *existing = value.to_string();
+            replaced = true;
+            true
+        });
+
+        if !replaced {
+            self.entries.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    /// Adds a header, existing values with the same name are kept, e.g. for repeated header lines
+    pub fn append(&mut self, name: &str, value: &str) {
+        if is_valid_field(name, value) {
+            self.entries.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    /// Gets the first value of a header, the name is matched case-insensitively
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Gets all values of a header in the order they were added
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The elements of a comma-separated list header over all its values, e.g. the options of `Connection`
+    pub fn list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .map(trim_whitespace)
+            .filter(|element| !element.is_empty())
+    }
+
+    /// Checks if a list header contains the element, compared case-insensitively
+    pub fn has_element(&self, name: &str, element: &str) -> bool {
+        self.list(name).any(|candidate| candidate.eq_ignore_ascii_case(element))
+    }
+
+    /// Removes all values of a header, the name is matched case-insensitively
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over all headers in insertion order, a repeated header once per value
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// A field which could end its line early or smuggle in another field is never stored,
+/// so nothing taken from a request can inject headers into a response
+fn is_valid_field(name: &str, value: &str) -> bool {
+    let valid = is_token(name) && is_field_value(value);
+    if !valid {
+        eprintln!("Ignoring header {:?} with an invalid name or value", name);
+    }
+    valid
+}
+
+/// Checks if the value is a non-empty RFC 9110 token, as header names are
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Field values may contain any visible character, spaces and tabs, but no other control characters
+/// like CR or LF (RFC 9110 section 5.5)
+pub fn is_field_value(value: &str) -> bool {
+    value.bytes().all(|byte| byte == b'\t' || (byte >= b' ' && byte != 0x7f))
+}
+
+/// Strips the optional whitespace around a field value, which is only spaces and tabs
+pub fn trim_whitespace(value: &str) -> &str {
+    value.trim_matches([' ', '\t'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.append("content-type", "application/json");
+        assert_eq!(headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("application/json"));
+        assert!(headers.contains("Content-type"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn repeated_headers_keep_every_value_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Accept", "text/html");
+        headers.append("X-Other", "1");
+        headers.append("accept", "application/json");
+
+        assert_eq!(headers.get("Accept"), Some("text/html"));
+        assert_eq!(headers.get_all("ACCEPT").collect::<Vec<_>>(), ["text/html", "application/json"]);
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("Accept", "text/html"), ("X-Other", "1"), ("accept", "application/json")]
+        );
+    }
+
+    #[test]
+    fn list_joins_the_elements_of_all_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Connection", "keep-alive, ,Upgrade");
+        headers.append("connection", "\tclose ");
+
+        assert_eq!(headers.list("Connection").collect::<Vec<_>>(), ["keep-alive", "Upgrade", "close"]);
+        assert!(headers.has_element("connection", "upgrade"));
+        assert!(headers.has_element("Connection", "CLOSE"));
+        assert!(!headers.has_element("Connection", "keep"));
+    }
+
+    #[test]
+    fn insert_replaces_every_value_in_place() {
+        let mut headers = HeaderMap::new();
+        headers.append("Vary", "Accept");
+        headers.append("Cache-Control", "no-cache");
+        headers.append("vary", "Origin");
+        headers.insert("VARY", "*");
+
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("Vary", "*"), ("Cache-Control", "no-cache")]);
+
+        headers.remove("cache-control");
+        assert!(!headers.contains("Cache-Control"));
+        assert!(!headers.is_empty());
+    }
+
+    #[test]
+    fn fields_which_could_inject_headers_are_ignored() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Location", "/next\r\nSet-Cookie: session=stolen");
+        headers.append("X-Value", "a\nb");
+        headers.append("X-Value", "a\0b");
+        headers.append("Bad Name", "value");
+        headers.append("Bad:Name", "value");
+        headers.append("", "value");
+        assert!(headers.is_empty());
+
+        // Tabs and visible characters are fine
+        headers.append("X-Value", "a\tb ~");
+        assert_eq!(headers.get("x-value"), Some("a\tb ~"));
+    }
+
+    #[test]
+    fn tokens() {
+        for valid in ["Content-Type", "x_custom", "!#$%&'*+-.^_`|~09az"] {
+            assert!(is_token(valid), "{}", valid);
+        }
+        for invalid in ["", "Content Type", "Content-Type ", "a:b", "a/b", "(comment)", "\"quoted\"", "caf\u{e9}"] {
+            assert!(!is_token(invalid), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn whitespace_is_only_spaces_and_tabs() {
+        assert_eq!(trim_whitespace(" \t value \t"), "value");
+        assert_eq!(trim_whitespace("\u{a0}value"), "\u{a0}value");
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use crate::http::request::HttpRequest;
+use crate::http::response::HttpResponse;
+
+/// Runs around the handlers, e.g. for logging, authentication or CORS.
+/// Passes the request on with `next.run(request)`, or answers it itself.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse;
+}
+
+/// Plain functions and closures can be used as middleware
+impl<F> Middleware for F
+where
+    F: Fn(&mut HttpRequest, Next) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, request: &mut HttpRequest, next: Next) -> HttpResponse {
+        self(request, next)
+    }
+}
+
+/// The middleware which has not run yet, followed by the handler.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&mut HttpRequest) -> HttpResponse,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(middleware: &'a [Arc<dyn Middleware>], endpoint: &'a dyn Fn(&mut HttpRequest) -> HttpResponse) -> Self {
+        Next { middleware, endpoint }
+    }
+
+    /// Passes the request to the next middleware, after the last one to the handler
+    pub fn run(self, request: &mut HttpRequest) -> HttpResponse {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::HeaderMap;
+    use crate::http::request::HttpVersion;
+    use crate::http::response::StatusCode;
+
+    /// Adds its name to the `trail` of the request before passing it on
+    fn mark(name: &'static str) -> Arc<dyn Middleware> {
+        Arc::new(move |request: &mut HttpRequest, next: Next| {
+            request.path_params.entry("trail".to_string()).or_default().push_str(name);
+            next.run(request)
+        })
+    }
+
+    fn refuse(_: &mut HttpRequest, _: Next) -> HttpResponse {
+        HttpResponse::new(StatusCode::Unauthorized).with_body("text/plain", "refused")
+    }
+
+    /// Answers with the trail, with `handler` appended
+    fn handler(request: &mut HttpRequest) -> HttpResponse {
+        let trail = format!("{}handler", request.path_params.get("trail").map_or("", String::as_str));
+        HttpResponse::new(StatusCode::Ok).with_body("text/plain", trail)
+    }
+
+    fn run(middleware: &[Arc<dyn Middleware>]) -> HttpResponse {
+        let mut request = HttpRequest::from_parts("GET", "/", HttpVersion::Http11, HeaderMap::new(), Vec::new()).unwrap();
+        Next::new(middleware, &handler).run(&mut request)
+    }
+
+    #[test]
+    fn middleware_runs_in_order_before_the_handler() {
+        let response = run(&[mark("a "), mark("b "), mark("c ")]);
+        assert_eq!(response.body, b"a b c handler");
+    }
+
+    #[test]
+    fn middleware_can_change_the_response_on_the_way_back() {
+        let wrap: Arc<dyn Middleware> = Arc::new(|request: &mut HttpRequest, next: Next| {
+            next.run(request).with_header("X-Wrapped", "yes")
+        });
+        let response = run(&[wrap, mark("a ")]);
+        assert_eq!(response.body, b"a handler");
+        assert_eq!(response.headers.get("X-Wrapped"), Some("yes"));
+    }
+
+    #[test]
+    fn answering_middleware_skips_the_rest() {
+        let response = run(&[mark("a "), Arc::new(refuse), mark("b ")]);
+        assert_eq!(response.status, StatusCode::Unauthorized);
+        assert_eq!(response.body, b"refused");
+    }
+
+    #[test]
+    fn without_middleware_the_handler_runs_directly() {
+        assert_eq!(run(&[]).body, b"handler");
+    }
+}
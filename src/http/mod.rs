@@ -0,0 +1,11 @@
+pub mod chunked;
+pub mod h2;
+pub mod headers;
+pub mod middleware;
+pub mod problem;
+pub mod request;
+pub mod request_reader;
+pub mod response;
+pub mod router;
+pub mod sse;
+pub mod websocket;
@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use serde::Serialize;
+use crate::http::response::StatusCode;
+
+/// The media type of problem details, RFC 7807 section 3
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+thread_local! {
+    /// The path of the request handled on this thread, the problems built for it name it as their instance
+    static INSTANCE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// One invalid field of the request body, e.g. `created_by.email`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The body of every error response, RFC 7807 problem details.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    /// The problems carry no meaning beyond their status, so the type is always `about:blank`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// The reason phrase of the status
+    pub title: String,
+    pub status: u16,
+    /// What went wrong with this request
+    pub detail: String,
+    /// The path of the request, if the problem was built while it was handled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Extension member with the invalid fields of the body
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: &str, errors: Vec<FieldError>) -> Self {
+        Problem {
+            problem_type: "about:blank".to_string(),
+            title: status.reason().to_string(),
+            status: status.code(),
+            detail: detail.to_string(),
+            instance: INSTANCE.with(|instance| instance.borrow().clone()),
+            errors,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "".to_string())
+    }
+}
+
+/// Handles the request at `path`, the problems built meanwhile on this thread name it as their instance
+pub fn with_instance<R>(path: &str, handle: impl FnOnce() -> R) -> R {
+    let outer = INSTANCE.with(|instance| instance.replace(Some(path.to_string())));
+    let result = handle();
+    INSTANCE.with(|instance| *instance.borrow_mut() = outer);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use crate::response_builder::create_http_not_found_response;
+
+    fn instance_of(problem: &Problem) -> Option<serde_json::Value> {
+        let json: serde_json::Value = serde_json::from_str(&problem.to_json()).unwrap();
+        json.get("instance").cloned()
+    }
+
+    #[test]
+    fn problems_built_while_handling_name_the_request() {
+        let response = with_instance("/recipes/7", || ApiError::NotFound("No recipe 7".to_string()).into_response());
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["instance"], "/recipes/7");
+        assert_eq!(problem["detail"], "No recipe 7");
+    }
+
+    #[test]
+    fn problems_built_outside_a_request_have_no_instance() {
+        let problem = Problem::new(StatusCode::NotFound, "No recipe 7", Vec::new());
+        assert_eq!(instance_of(&problem), None);
+
+        let response = create_http_not_found_response(None);
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem.get("instance"), None);
+    }
+
+    #[test]
+    fn the_outer_request_is_restored_after_a_nested_one() {
+        with_instance("/outer", || {
+            let inner = with_instance("/inner", || Problem::new(StatusCode::Conflict, "", Vec::new()));
+            assert_eq!(instance_of(&inner), Some("/inner".into()));
+            assert_eq!(instance_of(&Problem::new(StatusCode::Conflict, "", Vec::new())), Some("/outer".into()));
+        });
+        assert_eq!(instance_of(&Problem::new(StatusCode::Conflict, "", Vec::new())), None);
+    }
+}
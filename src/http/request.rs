@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::http::headers::{is_field_value, is_token, trim_whitespace, HeaderMap};
+use crate::http::request_reader::RawRequest;
+
+/// The HTTP request methods supported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+}
+
+impl Method {
+    /// Parses a method token, methods are case-sensitive
+    pub fn parse(token: &str) -> Option<Method> {
+        match token {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "PATCH" => Some(Method::Patch),
+            "DELETE" => Some(Method::Delete),
+            "OPTIONS" => Some(Method::Options),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The HTTP versions supported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+            HttpVersion::Http2 => "HTTP/2",
+        }
+    }
+}
+
+/// Numbers the requests in the order they are parsed
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A parsed HTTP request as passed to the handlers.
+#[derive(Debug)]
+pub struct HttpRequest {
+    /// Identifies the request in the logs
+    pub id: u64,
+    pub method: Method,
+    /// The request target as sent by the client, path and query string
+    pub target: String,
+    /// Path of the request target without the query string
+    pub path: String,
+    /// Percent-decoded query parameters
+    pub query: HashMap<String, String>,
+    /// Percent-decoded parameters of the matched route, e.g. `id` of `/recipes/{id}`
+    pub path_params: HashMap<String, String>,
+    pub headers: HeaderMap,
+    /// Trailer fields sent after a chunked body
+    pub trailers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Address of the client, if known
+    pub peer_addr: Option<SocketAddr>,
+    pub version: HttpVersion,
+}
+
+/// Reasons why a request could not be parsed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request is malformed
+    BadRequest(&'static str),
+    /// The method is not supported by the server
+    MethodNotImplemented,
+    /// The HTTP version is not supported by the server
+    VersionNotSupported,
+    /// The header fields exceed the size limit
+    HeadersTooLarge,
+    /// The body exceeds the size limit
+    BodyTooLarge,
+}
+
+impl HttpRequest {
+    /// Parses the request line and headers of a raw request
+    pub fn parse(raw_request: RawRequest, peer_addr: Option<SocketAddr>) -> Result<HttpRequest, ParseError> {
+        let head = std::str::from_utf8(&raw_request.head)
+            .map_err(|_| ParseError::BadRequest("Request headers must be valid UTF-8"))?;
+
+        // Parse request line
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or("");
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => (method, target, version),
+            _ => return Err(ParseError::BadRequest("Malformed request line")),
+        };
+
+        let version = match version {
+            "HTTP/1.1" => HttpVersion::Http11,
+            "HTTP/1.0" => HttpVersion::Http10,
+            _ if version.starts_with("HTTP/") => return Err(ParseError::VersionNotSupported),
+            _ => return Err(ParseError::BadRequest("Malformed HTTP version")),
+        };
+
+        // Parse headers, a repeated header keeps all its values
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            // A line continuing the previous header (obs-fold) is obsolete, RFC 9112 section 5.2
+            if line.starts_with([' ', '\t']) {
+                return Err(ParseError::BadRequest("Folded header lines are not supported"));
+            }
+
+            let (name, value) = line.split_once(':').ok_or(ParseError::BadRequest("Malformed header line"))?;
+            // Whitespace before the colon is not allowed, RFC 9112 section 5.1
+            if !is_token(name) {
+                return Err(ParseError::BadRequest("Invalid header name"));
+            }
+            // A bare CR or LF would end the header in some other parser
+            if !is_field_value(value) {
+                return Err(ParseError::BadRequest("Invalid header value"));
+            }
+            headers.append(name, trim_whitespace(value));
+        }
+
+        // Several hosts could be read differently by a proxy in front, RFC 9112 section 3.2
+        if headers.get_all("Host").count() > 1 {
+            return Err(ParseError::BadRequest("Multiple Host headers"));
+        }
+
+        let mut request = HttpRequest::from_parts(method, target, version, headers, raw_request.body)?;
+        for (name, value) in &raw_request.trailers {
+            request.trailers.append(name, value);
+        }
+        request.peer_addr = peer_addr;
+        Ok(request)
+    }
+
+    /// Builds a request from the method, target, header fields and body.
+    /// Shared by HTTP/1 and HTTP/2, which transport these parts differently.
+    pub fn from_parts(
+        method: &str,
+        target: &str,
+        version: HttpVersion,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> Result<HttpRequest, ParseError> {
+        let method = Method::parse(method).ok_or(ParseError::MethodNotImplemented)?;
+
+        if !target.starts_with('/') {
+            return Err(ParseError::BadRequest("Request target must be an absolute path"));
+        }
+        // The target ends up in redirects, so it may not carry control characters or whitespace
+        if target.bytes().any(|byte| byte <= b' ' || byte == 0x7f) {
+            return Err(ParseError::BadRequest("Invalid request target"));
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (target, HashMap::new()),
+        };
+
+        Ok(HttpRequest {
+            id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            method,
+            target: target.to_string(),
+            path: path.to_string(),
+            query,
+            path_params: HashMap::new(),
+            headers,
+            trailers: HeaderMap::new(),
+            body,
+            peer_addr: None,
+            version,
+        })
+    }
+
+    /// The media type of the body without parameters, defaults to text/plain
+    pub fn content_type(&self) -> &str {
+        self.headers
+            .get("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(str::trim)
+            .unwrap_or("text/plain")
+    }
+
+    /// Gets a query parameter by name
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|value| value.as_str())
+    }
+
+    /// Gets a path parameter by name converted to `T`, None if it is missing or does not parse
+    pub fn path_param<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.path_params.get(name).and_then(|value| value.parse().ok())
+    }
+}
+
+/// Splits the query string into percent-decoded key-value pairs
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+/// Decodes the %XX escapes of a path segment, a '+' stays as it is
+pub fn decode_path_segment(segment: &str) -> String {
+    percent_decode(segment, false)
+}
+
+/// Decodes %XX escapes, query strings also encode spaces as '+'
+fn percent_decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                }
+                // Keep invalid escapes as they are
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Result<HttpRequest, ParseError> {
+        let raw_request = RawRequest {
+            head: head.as_bytes().to_vec(),
+            body: Vec::new(),
+            trailers: Vec::new(),
+        };
+        HttpRequest::parse(raw_request, None)
+    }
+
+    fn bad_request(head: &str) -> &'static str {
+        match parse(head) {
+            Err(ParseError::BadRequest(message)) => message,
+            Err(error) => panic!("{:?} for {:?}", error, head),
+            Ok(_) => panic!("accepted {:?}", head),
+        }
+    }
+
+    #[test]
+    fn header_names_match_in_any_case() {
+        let request = parse("POST /recipes HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json").unwrap();
+        assert_eq!(request.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(request.headers.get("Host"), Some("localhost"));
+    }
+
+    #[test]
+    fn optional_whitespace_is_trimmed() {
+        let request = parse("GET / HTTP/1.1\r\nA:value\r\nB: \t value \t\r\nC:\r\nD: inner  space").unwrap();
+        assert_eq!(request.headers.get("A"), Some("value"));
+        assert_eq!(request.headers.get("B"), Some("value"));
+        assert_eq!(request.headers.get("C"), Some(""));
+        assert_eq!(request.headers.get("D"), Some("inner  space"));
+    }
+
+    #[test]
+    fn repeated_headers_are_kept() {
+        let request = parse("GET / HTTP/1.1\r\nAccept: text/html\r\nX-Id: 1\r\naccept: application/json").unwrap();
+        assert_eq!(request.headers.get_all("Accept").collect::<Vec<_>>(), ["text/html", "application/json"]);
+    }
+
+    #[test]
+    fn rejects_folded_header_lines() {
+        assert_eq!(
+            bad_request("GET / HTTP/1.1\r\nX-Long: first\r\n second"),
+            "Folded header lines are not supported"
+        );
+        assert_eq!(
+            bad_request("GET / HTTP/1.1\r\nX-Long: first\r\n\tsecond"),
+            "Folded header lines are not supported"
+        );
+    }
+
+    #[test]
+    fn rejects_whitespace_before_the_colon() {
+        assert_eq!(bad_request("GET / HTTP/1.1\r\nHost : localhost"), "Invalid header name");
+        assert_eq!(bad_request("GET / HTTP/1.1\r\nHost\t: localhost"), "Invalid header name");
+    }
+
+    #[test]
+    fn rejects_invalid_header_names() {
+        for name in ["", "X Name", "X\"Name", "X(Name)", "X/Name", "X@Name", "X\u{e9}"] {
+            let head = format!("GET / HTTP/1.1\r\n{}: value", name);
+            assert_eq!(bad_request(&head), "Invalid header name", "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_control_characters_in_values() {
+        for value in ["a\rb", "a\0b", "a\x7fb"] {
+            let head = format!("GET / HTTP/1.1\r\nX-Value: {}", value);
+            assert_eq!(bad_request(&head), "Invalid header value", "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_lines_without_a_colon() {
+        assert_eq!(bad_request("GET / HTTP/1.1\r\nJust text"), "Malformed header line");
+    }
+
+    #[test]
+    fn rejects_several_hosts() {
+        assert_eq!(bad_request("GET / HTTP/1.1\r\nHost: a\r\nhost: b"), "Multiple Host headers");
+    }
+
+    #[test]
+    fn parses_the_request_line() {
+        let request = parse("GET /recipes?page=2&q=a+b HTTP/1.0").unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.version, HttpVersion::Http10);
+        assert_eq!(request.path, "/recipes");
+        assert_eq!(request.query.get("q").map(String::as_str), Some("a b"));
+
+        assert_eq!(bad_request("GET  / HTTP/1.1"), "Malformed request line");
+        assert_eq!(bad_request("GET / HTTP/1.1 extra"), "Malformed request line");
+        assert!(matches!(parse("GET / HTTP/2.0"), Err(ParseError::VersionNotSupported)));
+        assert!(matches!(parse("BREW / HTTP/1.1"), Err(ParseError::MethodNotImplemented)));
+    }
+}
@@ -0,0 +1,526 @@
+use std::io;
+use std::time::{Duration, Instant};
+use crate::http::chunked::ChunkedDecoder;
+use crate::http::h2::PREFACE;
+use crate::http::headers::trim_whitespace;
+use crate::transport::Connection;
+
+/// Size and time limits applied while reading a request.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Maximum size of the request line and headers in bytes
+    pub max_header_size: usize,
+    /// Maximum size of the request body in bytes
+    pub max_body_size: usize,
+    /// Time from the first byte of a request until its headers are complete
+    pub header_timeout: Duration,
+    /// Time from the end of the headers until the body is complete
+    pub body_timeout: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A request as read from the connection, split into head and body.
+#[derive(Debug)]
+pub struct RawRequest {
+    /// Request line and headers without the terminating empty line
+    pub head: Vec<u8>,
+    pub body: Vec<u8>,
+    /// Trailer fields sent after a chunked body
+    pub trailers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// Reading from the connection failed
+    Io(io::Error),
+    /// The connection was closed or timed out before the next request started
+    NoRequest,
+    /// The client closed the connection before sending a complete request
+    ConnectionClosed,
+    /// The request line and headers exceed the header limit
+    HeadersTooLarge,
+    /// The announced body exceeds the body limit
+    BodyTooLarge,
+    /// The request framing is invalid
+    BadRequest(&'static str),
+    /// The client was too slow to send the headers or the body
+    Timeout,
+    /// The client starts a HTTP/2 connection, the received bytes stay in the buffer
+    Http2Preface,
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        ReadError::Io(error)
+    }
+}
+
+const HEADER_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+/// Reads requests from one connection.
+/// Bytes received after a request are kept for the next one, so pipelined requests are not lost.
+pub struct RequestReader {
+    buffer: Vec<u8>,
+    parser: RequestParser,
+    limits: RequestLimits,
+}
+
+impl RequestReader {
+    pub fn new(limits: RequestLimits) -> Self {
+        RequestReader {
+            buffer: Vec::with_capacity(1024),
+            parser: RequestParser::default(),
+            limits,
+        }
+    }
+
+    /// Hands out the bytes received but not parsed yet, e.g. for another protocol
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Reads the next complete request from the connection.
+    /// Keeps reading until the parser finds a complete request. The client gets
+    /// `idle_timeout` to start the request, then the header and body timeouts of the limits.
+    pub fn read_request<C: Connection>(&mut self, connection: &mut C, idle_timeout: Duration) -> Result<RawRequest, ReadError> {
+        let mut chunk = [0u8; 8192];
+        let mut timer = RequestTimer::default();
+
+        loop {
+            if let Some((request, used)) = self.parser.parse(&self.buffer, &self.limits)? {
+                self.buffer.drain(..used);
+                return Ok(request);
+            }
+
+            // The socket timeout is the time left until the next deadline
+            timer.update(&self.buffer);
+            let timeout = match timer.deadline(&self.limits) {
+                Some(deadline) => deadline
+                    .checked_duration_since(Instant::now())
+                    .filter(|left| !left.is_zero())
+                    .ok_or(ReadError::Timeout)?,
+                None => idle_timeout,
+            };
+            connection.set_read_timeout(Some(timeout))?;
+
+            let bytes_read = match connection.read(&mut chunk) {
+                Ok(bytes_read) => bytes_read,
+                // Nothing of the next request arrived within the idle timeout
+                Err(error) if self.buffer.is_empty() && is_timeout(&error) => return Err(ReadError::NoRequest),
+                Err(error) if is_timeout(&error) => return Err(ReadError::Timeout),
+                Err(error) => return Err(ReadError::Io(error)),
+            };
+
+            if bytes_read == 0 {
+                return Err(if self.buffer.is_empty() { ReadError::NoRequest } else { ReadError::ConnectionClosed });
+            }
+            self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        }
+    }
+}
+
+/// Tracks how far a partly received request got, so slow clients cannot hold a connection forever.
+/// Shared by the blocking and the event-driven server.
+#[derive(Debug, Default)]
+pub struct RequestTimer {
+    /// When the first byte of the request arrived
+    started: Option<Instant>,
+    /// When the headers were complete
+    head_received: Option<Instant>,
+}
+
+impl RequestTimer {
+    /// Notes the progress of the request at the start of `buffer`
+    pub fn update(&mut self, buffer: &[u8]) {
+        if self.started.is_none() && !buffer.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        if self.started.is_some() && self.head_received.is_none() && find_header_terminator(buffer).is_some() {
+            self.head_received = Some(Instant::now());
+        }
+    }
+
+    /// The request is complete, the next one starts from scratch
+    pub fn reset(&mut self) {
+        *self = RequestTimer::default();
+    }
+
+    /// A request has started but is not complete yet
+    pub fn is_started(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// When the headers or the body have to be complete, `None` before the request started
+    pub fn deadline(&self, limits: &RequestLimits) -> Option<Instant> {
+        match (self.started, self.head_received) {
+            (_, Some(head_received)) => Some(head_received + limits.body_timeout),
+            (Some(started), None) => Some(started + limits.header_timeout),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Checks if a read failed because the socket timeout expired
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Parses requests from the bytes received so far, the buffer always starts at the request.
+/// A chunked body is decoded while it arrives, so each read only decodes the new bytes.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    /// The request whose chunked body is still arriving
+    chunked: Option<ChunkedRequest>,
+}
+
+/// The head of a request with a chunked body and the progress of its body
+#[derive(Debug)]
+struct ChunkedRequest {
+    head: Vec<u8>,
+    body_start: usize,
+    decoder: ChunkedDecoder,
+}
+
+/// What the headers say about the request
+enum Framing {
+    /// The whole request with its Content-Length body and the number of bytes it used
+    Complete(RawRequest, usize),
+    Chunked(ChunkedRequest),
+}
+
+impl RequestParser {
+    /// Returns the request and the number of bytes it used once the headers are terminated
+    /// and the whole body announced by Content-Length or chunked transfer encoding is present,
+    /// `None` if more bytes are needed.
+    pub fn parse(&mut self, buffer: &[u8], limits: &RequestLimits) -> Result<Option<(RawRequest, usize)>, ReadError> {
+        let result = self.parse_next(buffer, limits);
+
+        // The next request starts from scratch, also after an error
+        if !matches!(result, Ok(None)) {
+            self.reset();
+        }
+        result
+    }
+
+    /// Forgets the partly received request, e.g. when its bytes are dropped
+    pub fn reset(&mut self) {
+        self.chunked = None;
+    }
+
+    fn parse_next(&mut self, buffer: &[u8], limits: &RequestLimits) -> Result<Option<(RawRequest, usize)>, ReadError> {
+        let pending = match &mut self.chunked {
+            Some(pending) => pending,
+            None => match parse_head(buffer, limits)? {
+                Some(Framing::Complete(request, used)) => return Ok(Some((request, used))),
+                Some(Framing::Chunked(pending)) => self.chunked.insert(pending),
+                None => return Ok(None),
+            },
+        };
+
+        let body = &buffer[pending.body_start..];
+        Ok(pending.decoder.decode(body, limits)?.map(|(chunked, used)| {
+            let request = RawRequest {
+                head: std::mem::take(&mut pending.head),
+                body: chunked.body,
+                trailers: chunked.trailers,
+            };
+            (request, pending.body_start + used)
+        }))
+    }
+}
+
+/// Parses the request line and headers and finds out how the body is framed,
+/// `None` if the headers or the Content-Length body are not complete yet
+fn parse_head(buffer: &[u8], limits: &RequestLimits) -> Result<Option<Framing>, ReadError> {
+    // HTTP/2 with prior knowledge or ALPN, its preface looks like a HTTP/1 request at first
+    let length = buffer.len().min(PREFACE.len());
+    if length > 0 && buffer[..length] == PREFACE[..length] {
+        return match length == PREFACE.len() {
+            true => Err(ReadError::Http2Preface),
+            false => Ok(None),
+        };
+    }
+
+    // Find the end of the headers
+    let head_end = match find_header_terminator(buffer) {
+        Some(position) => position,
+        // No terminator within the limit, the headers are too large
+        None if buffer.len() > limits.max_header_size => return Err(ReadError::HeadersTooLarge),
+        None => return Ok(None),
+    };
+
+    if head_end > limits.max_header_size {
+        return Err(ReadError::HeadersTooLarge);
+    }
+
+    let head = &buffer[..head_end];
+    let body_start = head_end + HEADER_TERMINATOR.len();
+    let received_body = &buffer[body_start..];
+
+    let head_str = std::str::from_utf8(head).map_err(|_| ReadError::BadRequest("Request headers must be valid UTF-8"))?;
+    let transfer_encoding = header_values(head_str, "Transfer-Encoding");
+    let content_length = header_values(head_str, "Content-Length");
+
+    if !transfer_encoding.is_empty() {
+        // Both headers at once is a request smuggling vector
+        if !content_length.is_empty() {
+            return Err(ReadError::BadRequest("Content-Length is not allowed together with Transfer-Encoding"));
+        }
+
+        // Only chunked is supported, so it has to be the one and only coding
+        let mut codings = transfer_encoding
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty());
+        match (codings.next(), codings.next()) {
+            (Some(coding), None) if coding.eq_ignore_ascii_case("chunked") => {}
+            _ => return Err(ReadError::BadRequest("Only chunked transfer encoding is supported")),
+        }
+
+        return Ok(Some(Framing::Chunked(ChunkedRequest {
+            head: head.to_vec(),
+            body_start,
+            decoder: ChunkedDecoder::new(),
+        })));
+    }
+
+    // Check the announced body size before waiting for it
+    let content_length = parse_content_length(&content_length)?;
+    if content_length > limits.max_body_size {
+        return Err(ReadError::BodyTooLarge);
+    }
+
+    if received_body.len() < content_length {
+        return Ok(None);
+    }
+
+    // Anything after the announced body belongs to the next request
+    let request = RawRequest {
+        head: head.to_vec(),
+        body: received_body[..content_length].to_vec(),
+        trailers: Vec::new(),
+    };
+    Ok(Some(Framing::Complete(request, body_start + content_length)))
+}
+
+/// Returns the position of the empty line ending the headers
+fn find_header_terminator(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(HEADER_TERMINATOR.len())
+        .position(|window| window == HEADER_TERMINATOR)
+}
+
+/// Collects the values of all headers with the given name.
+/// Names are matched like the request parser does, which rejects malformed lines afterwards
+fn header_values<'a>(head: &'a str, name: &str) -> Vec<&'a str> {
+    // Skip the request line
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| trim_whitespace(value))
+        .collect()
+}
+
+/// Parses the Content-Length values, a missing header means there is no body
+fn parse_content_length(values: &[&str]) -> Result<usize, ReadError> {
+    let mut content_length = None;
+
+    for value in values {
+        let length = parse_length(value).ok_or(ReadError::BadRequest("Invalid Content-Length header"))?;
+
+        // Repeated headers must agree
+        if content_length.is_some_and(|existing| existing != length) {
+            return Err(ReadError::BadRequest("Conflicting Content-Length headers"));
+        }
+        content_length = Some(length);
+    }
+
+    Ok(content_length.unwrap_or(0))
+}
+
+/// Parses a Content-Length value, which is only ASCII digits (RFC 9110 section 8.6).
+/// `str::parse` would also take a leading `+`, which a proxy in front may read differently.
+pub fn parse_length(value: &str) -> Option<usize> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::transport::std_net::loopback_pair;
+
+    fn limits(header_timeout: Duration, body_timeout: Duration) -> RequestLimits {
+        RequestLimits {
+            header_timeout,
+            body_timeout,
+            ..RequestLimits::default()
+        }
+    }
+
+    #[test]
+    fn the_timer_starts_with_the_first_byte_and_switches_to_the_body() {
+        let limits = limits(Duration::from_secs(10), Duration::from_secs(30));
+        let mut timer = RequestTimer::default();
+
+        timer.update(b"");
+        assert!(!timer.is_started());
+        assert_eq!(timer.deadline(&limits), None);
+
+        timer.update(b"POST / HTTP/1.1\r\n");
+        assert!(timer.is_started());
+        let header_deadline = timer.deadline(&limits).unwrap();
+        assert!(header_deadline <= Instant::now() + limits.header_timeout);
+
+        timer.update(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhe");
+        let body_deadline = timer.deadline(&limits).unwrap();
+        assert!(body_deadline > header_deadline);
+
+        timer.reset();
+        assert!(!timer.is_started());
+        assert_eq!(timer.deadline(&limits), None);
+    }
+
+    /// Sends `input` and leaves the connection open, the result of reading a request from it
+    fn read_stalled(input: &'static [u8], limits: RequestLimits, idle_timeout: Duration) -> Result<RawRequest, ReadError> {
+        let (mut connection, mut client) = loopback_pair();
+        client.write_all(input).unwrap();
+        RequestReader::new(limits).read_request(&mut connection, idle_timeout)
+    }
+
+    #[test]
+    fn slow_headers_time_out() {
+        let started = Instant::now();
+        let limits = limits(Duration::from_millis(100), Duration::from_secs(10));
+        let result = read_stalled(b"GET / HTTP/1.1\r\nHost: a", limits, Duration::from_secs(10));
+        assert!(matches!(result, Err(ReadError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_slow_body_times_out() {
+        let started = Instant::now();
+        let limits = limits(Duration::from_secs(10), Duration::from_millis(100));
+        let result = read_stalled(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhe", limits, Duration::from_secs(10));
+        assert!(matches!(result, Err(ReadError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn an_idle_connection_has_no_request() {
+        let limits = limits(Duration::from_secs(10), Duration::from_secs(10));
+        let result = read_stalled(b"", limits, Duration::from_millis(100));
+        assert!(matches!(result, Err(ReadError::NoRequest)));
+    }
+
+    #[test]
+    fn a_complete_request_is_read_in_time() {
+        let limits = limits(Duration::from_millis(500), Duration::from_millis(500));
+        let request = read_stalled(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", limits, Duration::from_secs(10)).unwrap();
+        assert_eq!(request.head, b"GET / HTTP/1.1\r\nHost: a");
+    }
+
+    #[test]
+    fn pipelined_requests_are_read_one_after_another() {
+        let (mut connection, mut client) = loopback_pair();
+        client
+            .write_all(b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n")
+            .unwrap();
+
+        let limits = limits(Duration::from_secs(10), Duration::from_secs(10));
+        let mut reader = RequestReader::new(limits);
+        let idle_timeout = Duration::from_millis(100);
+
+        let first = reader.read_request(&mut connection, idle_timeout).unwrap();
+        assert_eq!((first.head.as_slice(), first.body.as_slice()), (&b"POST /a HTTP/1.1\r\nContent-Length: 2"[..], &b"hi"[..]));
+        assert_eq!(reader.read_request(&mut connection, idle_timeout).unwrap().head, b"GET /b HTTP/1.1");
+        assert_eq!(reader.read_request(&mut connection, idle_timeout).unwrap().head, b"GET /c HTTP/1.1");
+        assert!(matches!(reader.read_request(&mut connection, idle_timeout), Err(ReadError::NoRequest)));
+    }
+
+    fn parse(input: &[u8]) -> Result<Option<(RawRequest, usize)>, ReadError> {
+        RequestParser::default().parse(input, &RequestLimits::default())
+    }
+
+    #[test]
+    fn reads_the_content_length_body() {
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET";
+        let (request, used) = parse(input).unwrap().unwrap();
+        assert_eq!(request.head, b"POST / HTTP/1.1\r\nContent-Length: 5");
+        assert_eq!(request.body, b"hello");
+        assert_eq!(&input[used..], b"GET");
+        assert!(parse(&input[..input.len() - 4]).unwrap().is_none());
+    }
+
+    #[test]
+    fn content_length_is_only_digits() {
+        for value in ["+5", "-5", "5 5", "0x5", "", "5.0", "99999999999999999999999"] {
+            let input = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello", value);
+            assert!(matches!(parse(input.as_bytes()), Err(ReadError::BadRequest(_))), "accepted {:?}", value);
+        }
+        assert_eq!(parse_length("0005"), Some(5));
+    }
+
+    #[test]
+    fn repeated_content_length_must_agree() {
+        let same = b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 1\r\n\r\nx";
+        assert_eq!(parse(same).unwrap().unwrap().0.body, b"x");
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nxy";
+        assert!(matches!(parse(conflicting), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(input), Err(ReadError::BadRequest(_))));
+        let gzip = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(gzip), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let limits = RequestLimits { max_header_size: 48, max_body_size: 4, ..RequestLimits::default() };
+        let large_body = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert!(matches!(RequestParser::default().parse(large_body, &limits), Err(ReadError::BodyTooLarge)));
+        let large_head = [b'a'; 60];
+        assert!(matches!(RequestParser::default().parse(&large_head, &limits), Err(ReadError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn decodes_a_chunked_body_across_reads() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\nGET";
+        let limits = RequestLimits::default();
+        let mut parser = RequestParser::default();
+        for end in 0..input.len() - 3 {
+            assert!(parser.parse(&input[..end], &limits).unwrap().is_none(), "complete after {} bytes", end);
+        }
+        let (request, used) = parser.parse(input, &limits).unwrap().unwrap();
+        assert_eq!(request.body, b"abcde");
+        assert_eq!(&input[used..], b"GET");
+
+        // The parser starts over for the next request
+        let next = b"GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(parser.parse(next, &limits).unwrap().unwrap().1, next.len());
+    }
+
+    #[test]
+    fn detects_the_http2_preface() {
+        assert!(parse(&PREFACE[..10]).unwrap().is_none());
+        assert!(matches!(parse(PREFACE), Err(ReadError::Http2Preface)));
+    }
+}
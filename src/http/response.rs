@@ -0,0 +1,221 @@
+use crate::http::headers::HeaderMap;
+use crate::http::sse;
+use crate::http::websocket;
+
+/// The HTTP status codes used by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    MovedPermanently,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    UnprocessableEntity,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::Conflict => 409,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::UnprocessableEntity => 422,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::UnprocessableEntity => "Unprocessable Entity",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    /// Responses with these codes never carry a body
+    pub fn allows_body(&self) -> bool {
+        !matches!(self, StatusCode::SwitchingProtocols | StatusCode::NoContent)
+    }
+}
+
+/// What the connection is used for once the response head is sent.
+#[derive(Debug, Clone, Copy)]
+pub enum Upgrade {
+    /// WebSocket connection which receives the messages of the subscription
+    WebSocket(websocket::Subscribe),
+    /// The body is an endless stream of server-sent events, starting after the given event id
+    EventStream(sse::Subscribe, Option<u64>),
+}
+
+/// A HTTP response which is serialized once it is sent.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Set on `101 Switching Protocols` and event stream responses
+    pub upgrade: Option<Upgrade>,
+}
+
+impl HttpResponse {
+    /// Creates a response without headers and body
+    pub fn new(status: StatusCode) -> Self {
+        HttpResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            upgrade: None,
+        }
+    }
+
+    /// Adds a header, replacing an existing one with the same name
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Sets the body together with its content type
+    pub fn with_body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.headers.insert("Content-Type", content_type);
+        self.body = body.into();
+        self
+    }
+
+    /// Serializes the response into its HTTP/1.1 wire format.
+    /// Content-Length is always derived from the body, a streamed body ends with the connection.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_to_bytes();
+        if self.status.allows_body() {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+
+    /// Serializes only the status line and headers, as the answer to a HEAD request
+    pub fn head_to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.code(), self.status.reason());
+
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+
+        if self.status.allows_body() && self.upgrade.is_none() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+
+        // Empty line between the headers and the body
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_bytes_writes_status_line_headers_and_body() {
+        let response = HttpResponse::new(StatusCode::Created)
+            .with_header("Location", "/recipes/1")
+            .with_body("application/json", r#"{"id":1}"#);
+
+        assert_eq!(
+            response.to_bytes(),
+            b"HTTP/1.1 201 Created\r\nLocation: /recipes/1\r\nContent-Type: application/json\r\nContent-Length: 8\r\n\r\n{\"id\":1}"
+        );
+    }
+
+    #[test]
+    fn content_length_is_derived_from_the_body() {
+        let response = HttpResponse::new(StatusCode::Ok)
+            .with_header("Content-Length", "999")
+            .with_body("text/plain", "hello");
+
+        assert_eq!(
+            response.to_bytes(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello"
+        );
+    }
+
+    #[test]
+    fn head_to_bytes_leaves_out_the_body() {
+        let response = HttpResponse::new(StatusCode::Ok).with_body("text/plain", "hello");
+
+        assert_eq!(
+            response.head_to_bytes(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn empty_body_has_a_zero_content_length() {
+        assert_eq!(
+            HttpResponse::new(StatusCode::NotFound).to_bytes(),
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn no_content_has_neither_body_nor_content_length() {
+        let response = HttpResponse::new(StatusCode::NoContent).with_body("text/plain", "ignored");
+
+        assert_eq!(response.to_bytes(), b"HTTP/1.1 204 No Content\r\nContent-Type: text/plain\r\n\r\n");
+    }
+}
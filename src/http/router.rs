@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::error::ApiError;
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request::{decode_path_segment, HttpRequest, Method};
+use crate::http::response::HttpResponse;
+
+// Define the format of the handlers for the response, errors are turned into responses by the router
+pub type Handler = fn(&HttpRequest) -> Result<HttpResponse, ApiError>;
+
+/// One segment of a route pattern.
+#[derive(Debug, Clone)]
+enum Segment {
+    /// Matches exactly this text
+    Literal(String),
+    /// `{name}` matches any non-empty segment
+    Param(String),
+    /// `{*name}` matches the rest of the path, only allowed at the end
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Literal segments win over parameters, parameters over wildcards
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+}
+
+/// The outcome of looking up a request.
+pub enum RouteMatch<'a> {
+    Found(Endpoint<'a>, HashMap<String, String>),
+    /// The path exists, but not with this method. Holds the methods it has
+    MethodNotAllowed(Vec<Method>),
+    /// An OPTIONS request for a path without its own OPTIONS route, answered from the route table
+    Options(Vec<Method>),
+    NotFound,
+}
+
+/// The handler of a matched route together with the middleware of its groups.
+pub struct Endpoint<'a> {
+    handler: Handler,
+    middleware: &'a [Arc<dyn Middleware>],
+}
+
+impl Endpoint<'_> {
+    /// Runs the middleware, the outermost group first, and then the handler
+    pub fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        let handler = self.handler;
+        let endpoint = |request: &mut HttpRequest| handler(request).unwrap_or_else(ApiError::into_response);
+        Next::new(self.middleware, &endpoint).run(request)
+    }
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+    /// The middleware of the routers the route was nested from
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+/// Finds the handler for a request by method and path pattern, e.g. `/recipes/{id}/comments`.
+/// Built once at startup, modules hand out their own routers which are nested below a prefix.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Runs `middleware` around every route of this router, the first one added runs first.
+    /// On the router the server uses it runs for every request, even without a route
+    pub fn layer<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// The middleware added with `layer`
+    pub fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    /// Adds a handler. Panics on a malformed pattern, routes are fixed at startup
+    pub fn route(mut self, method: Method, pattern: &str, handler: Handler) -> Self {
+        let segments = parse_pattern(pattern).unwrap_or_else(|message| panic!("Invalid route {}: {}", pattern, message));
+        self.routes.push(Route {
+            method,
+            segments,
+            handler,
+            middleware: Vec::new(),
+        });
+        self
+    }
+
+    /// Adds all routes of `router` below `prefix`, its `/` route answers the prefix itself.
+    /// The routes keep the middleware of `router`, it runs after the middleware of this router
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => Vec::new(),
+            prefix => parse_pattern(prefix).unwrap_or_else(|message| panic!("Invalid prefix {}: {}", prefix, message)),
+        };
+        if prefix.iter().any(|segment| matches!(segment, Segment::Wildcard(_))) {
+            panic!("A prefix cannot contain a wildcard");
+        }
+
+        for route in router.routes {
+            let segments = match route.segments.as_slice() {
+                [Segment::Literal(text)] if text.is_empty() && !prefix.is_empty() => Vec::new(),
+                _ => route.segments,
+            };
+            self.routes.push(Route {
+                method: route.method,
+                segments: prefix.iter().cloned().chain(segments).collect(),
+                handler: route.handler,
+                middleware: router.middleware.iter().cloned().chain(route.middleware).collect(),
+            });
+        }
+        self
+    }
+
+    /// Finds the route for the request together with its path parameters.
+    /// HEAD is served by the GET route unless it has its own.
+    pub fn find(&self, method: Method, path: &str) -> RouteMatch<'_> {
+        let path_segments: Vec<&str> = path.split('/').skip(1).collect();
+
+        let found = match self.find_route(method, &path_segments) {
+            None if method == Method::Head => self.find_route(Method::Get, &path_segments),
+            found => found,
+        };
+        if let Some((route, params)) = found {
+            let endpoint = Endpoint {
+                handler: route.handler,
+                middleware: &route.middleware,
+            };
+            return RouteMatch::Found(endpoint, params);
+        }
+
+        let allowed = self.allowed_methods(&path_segments);
+        match method {
+            _ if allowed.is_empty() => RouteMatch::NotFound,
+            Method::Options => RouteMatch::Options(allowed),
+            _ => RouteMatch::MethodNotAllowed(allowed),
+        }
+    }
+
+    /// Finds the most specific route with this method.
+    /// Of two equally specific routes the one registered first wins.
+    fn find_route(&self, method: Method, path_segments: &[&str]) -> Option<(&Route, HashMap<String, String>)> {
+        let mut best: Option<(Vec<u8>, &Route, HashMap<String, String>)> = None;
+        for route in self.routes.iter().filter(|route| route.method == method) {
+            let params = match match_segments(&route.segments, path_segments) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            let rank: Vec<u8> = route.segments.iter().map(Segment::rank).collect();
+            if best.as_ref().is_none_or(|(best_rank, _, _)| rank > *best_rank) {
+                best = Some((rank, route, params));
+            }
+        }
+
+        best.map(|(_, route, params)| (route, params))
+    }
+
+    /// The methods of all routes matching the path, none if the path is unknown
+    fn allowed_methods(&self, path_segments: &[&str]) -> Vec<Method> {
+        let mut allowed: Vec<Method> = self
+            .routes
+            .iter()
+            .filter(|route| match_segments(&route.segments, path_segments).is_some())
+            .map(|route| route.method)
+            .collect();
+        if allowed.is_empty() {
+            return allowed;
+        }
+
+        // HEAD and OPTIONS are answered for every path
+        if allowed.contains(&Method::Get) {
+            allowed.push(Method::Head);
+        }
+        allowed.push(Method::Options);
+        allowed.sort();
+        allowed.dedup();
+        allowed
+    }
+}
+
+/// Splits a pattern like `/recipes/{id}` into its segments
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, &'static str> {
+    let rest = pattern.strip_prefix('/').ok_or("pattern must start with '/'")?;
+
+    let parts: Vec<&str> = rest.split('/').collect();
+    let mut segments = Vec::with_capacity(parts.len());
+    for (index, part) in parts.iter().enumerate() {
+        let segment = match part.strip_prefix('{').and_then(|part| part.strip_suffix('}')) {
+            Some(name) if name.starts_with('*') => {
+                if index + 1 != parts.len() {
+                    return Err("a wildcard must be the last segment");
+                }
+                Segment::Wildcard(name[1..].to_string())
+            }
+            Some(name) => Segment::Param(name.to_string()),
+            None => Segment::Literal(part.to_string()),
+        };
+
+        match &segment {
+            Segment::Param(name) | Segment::Wildcard(name) if name.is_empty() => return Err("parameters need a name"),
+            Segment::Literal(text) if text.contains(['{', '}']) => return Err("braces must enclose a whole segment"),
+            _ => {}
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// Matches the path against a pattern, returns the percent-decoded parameters
+fn match_segments(segments: &[Segment], path_segments: &[&str]) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+
+    for (index, segment) in segments.iter().enumerate() {
+        match segment {
+            // The wildcard takes everything left, slashes included
+            Segment::Wildcard(name) => {
+                let rest = path_segments.get(index..)?.join("/");
+                params.insert(name.clone(), decode_path_segment(&rest));
+                return Some(params);
+            }
+            Segment::Param(name) => {
+                let value = path_segments.get(index).filter(|value| !value.is_empty())?;
+                params.insert(name.clone(), decode_path_segment(value));
+            }
+            Segment::Literal(text) => {
+                if path_segments.get(index) != Some(&text.as_str()) {
+                    return None;
+                }
+            }
+        }
+    }
+
+    // Without a wildcard the whole path has to be used up
+    match segments.len() == path_segments.len() {
+        true => Some(params),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::HeaderMap;
+    use crate::http::request::HttpVersion;
+    use crate::http::response::StatusCode;
+    use crate::response_builder::create_http_method_not_allowed_response;
+
+    fn named(name: &str) -> Result<HttpResponse, ApiError> {
+        Ok(HttpResponse::new(StatusCode::Ok).with_body("text/plain", name))
+    }
+
+    fn list(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("list")
+    }
+
+    fn create(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("create")
+    }
+
+    fn show(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("show")
+    }
+
+    fn latest(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("latest")
+    }
+
+    fn remove(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("remove")
+    }
+
+    fn comment(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("comment")
+    }
+
+    fn files(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("files")
+    }
+
+    fn readme(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("readme")
+    }
+
+    fn head(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("head")
+    }
+
+    fn root(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named("root")
+    }
+
+    fn router() -> Router {
+        let recipes = Router::new()
+            .route(Method::Get, "/", list)
+            .route(Method::Post, "/", create)
+            .route(Method::Get, "/{id}", show)
+            .route(Method::Delete, "/{id}", remove)
+            // Registered after {id} and still preferred
+            .route(Method::Get, "/latest", latest)
+            .route(Method::Get, "/{id}/comments/{comment}", comment);
+
+        Router::new()
+            .route(Method::Get, "/", root)
+            .nest("/recipes", recipes)
+            .route(Method::Get, "/files/{*path}", files)
+            .route(Method::Get, "/files/README", readme)
+            .route(Method::Head, "/files/README", head)
+    }
+
+    /// What the router does with a request, e.g. `show id=7` or `405 GET, HEAD, OPTIONS`
+    fn outcome(router: &Router, method: Method, path: &str) -> String {
+        match router.find(method, path) {
+            RouteMatch::Found(endpoint, params) => {
+                let mut request =
+                    HttpRequest::from_parts(method.as_str(), path, HttpVersion::Http11, HeaderMap::new(), Vec::new()).unwrap();
+                let response = endpoint.call(&mut request);
+                let mut params: Vec<String> = params.iter().map(|(name, value)| format!(" {}={}", name, value)).collect();
+                params.sort();
+                format!("{}{}", String::from_utf8_lossy(&response.body), params.concat())
+            }
+            RouteMatch::MethodNotAllowed(allowed) => format!("405 {}", allow(&allowed)),
+            RouteMatch::Options(allowed) => format!("options {}", allow(&allowed)),
+            RouteMatch::NotFound => "404".to_string(),
+        }
+    }
+
+    fn allow(allowed: &[Method]) -> String {
+        allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")
+    }
+
+    #[test]
+    fn finds_routes() {
+        let router = router();
+        for (method, path, expected) in [
+            (Method::Get, "/", "root"),
+            (Method::Get, "/recipes", "list"),
+            (Method::Post, "/recipes", "create"),
+            (Method::Get, "/recipes/42", "show id=42"),
+            (Method::Delete, "/recipes/42", "remove id=42"),
+            (Method::Get, "/recipes/latest", "latest"),
+            (Method::Delete, "/recipes/latest", "remove id=latest"),
+            (Method::Get, "/recipes/42/comments/7", "comment comment=7 id=42"),
+            (Method::Get, "/recipes/caf%C3%A9", "show id=café"),
+            (Method::Get, "/recipes/a%2Fb", "show id=a/b"),
+            (Method::Get, "/recipes/a+b", "show id=a+b"),
+            (Method::Get, "/files/css/site.css", "files path=css/site.css"),
+            (Method::Get, "/files/", "files path="),
+            // The wildcard may match nothing at all
+            (Method::Get, "/files", "files path="),
+            (Method::Get, "/files/a%20b/c", "files path=a b/c"),
+            (Method::Get, "/files/README", "readme"),
+            (Method::Get, "/files/README/more", "files path=README/more"),
+            // HEAD falls back to GET unless it has its own route
+            (Method::Head, "/recipes/42", "show id=42"),
+            (Method::Head, "/files/README", "head"),
+        ] {
+            assert_eq!(outcome(&router, method, path), expected, "{} {}", method.as_str(), path);
+        }
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let router = router();
+        for path in [
+            "/nothing",
+            // Parameters never match an empty segment
+            "/recipes/",
+            "/recipes//comments/7",
+            "/recipes/42/comments",
+            "/recipes/42/comments/7/extra",
+        ] {
+            assert_eq!(outcome(&router, Method::Get, path), "404", "{}", path);
+        }
+    }
+
+    #[test]
+    fn other_methods_are_not_allowed() {
+        let router = router();
+        for (method, path, expected) in [
+            (Method::Put, "/recipes", "405 GET, HEAD, POST, OPTIONS"),
+            (Method::Post, "/recipes/42", "405 GET, HEAD, DELETE, OPTIONS"),
+            (Method::Patch, "/recipes/latest", "405 GET, HEAD, DELETE, OPTIONS"),
+            (Method::Delete, "/files/a/b", "405 GET, HEAD, OPTIONS"),
+            (Method::Options, "/recipes/42", "options GET, HEAD, DELETE, OPTIONS"),
+        ] {
+            assert_eq!(outcome(&router, method, path), expected, "{} {}", method.as_str(), path);
+        }
+    }
+
+    #[test]
+    fn method_not_allowed_lists_the_methods_in_allow() {
+        let allowed = match router().find(Method::Put, "/recipes/42") {
+            RouteMatch::MethodNotAllowed(allowed) => allowed,
+            _ => panic!("expected 405"),
+        };
+        let response = create_http_method_not_allowed_response(&allowed);
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, DELETE, OPTIONS"));
+    }
+
+    #[test]
+    fn equally_specific_routes_keep_the_first() {
+        let router = Router::new().route(Method::Get, "/{a}", show).route(Method::Get, "/{b}", latest);
+        assert_eq!(outcome(&router, Method::Get, "/x"), "show a=x");
+    }
+
+    #[test]
+    fn earlier_literal_segments_win() {
+        let router = Router::new()
+            .route(Method::Get, "/{kind}/latest", show)
+            .route(Method::Get, "/recipes/{id}", latest);
+        assert_eq!(outcome(&router, Method::Get, "/recipes/latest"), "latest id=latest");
+    }
+
+    fn found_params(method: Method, path: &str) -> HashMap<String, String> {
+        match router().find(method, path) {
+            RouteMatch::Found(_, params) => params,
+            _ => panic!("expected a route for {}", path),
+        }
+    }
+
+    #[test]
+    fn path_parameters_convert_to_their_type() {
+        let params = found_params(Method::Get, "/recipes/42");
+        let mut request = HttpRequest::from_parts("GET", "/recipes/42", HttpVersion::Http11, HeaderMap::new(), Vec::new()).unwrap();
+        request.path_params = params;
+        assert_eq!(request.path_param::<i32>("id"), Some(42));
+        assert_eq!(request.path_param::<u8>("missing"), None);
+
+        let params = found_params(Method::Get, "/recipes/abc");
+        request.path_params = params;
+        assert_eq!(request.path_param::<i32>("id"), None);
+        assert_eq!(request.path_param::<String>("id").as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn nested_root_answers_the_prefix() {
+        let router = Router::new().nest("/api/", Router::new().route(Method::Get, "/", root));
+        assert_eq!(outcome(&router, Method::Get, "/api"), "root");
+        assert_eq!(outcome(&router, Method::Get, "/api/"), "404");
+    }
+
+    #[test]
+    fn nested_routers_nest_again() {
+        let comments = Router::new().route(Method::Get, "/{comment}", comment);
+        let recipes = Router::new().nest("/{id}/comments", comments);
+        let router = Router::new().nest("/recipes", recipes);
+        assert_eq!(outcome(&router, Method::Get, "/recipes/42/comments/7"), "comment comment=7 id=42");
+    }
+
+    /// Adds its name to the `trail` of the request before passing it on
+    fn mark(name: &'static str) -> impl Middleware {
+        move |request: &mut HttpRequest, next: Next| {
+            request.path_params.entry("trail".to_string()).or_default().push_str(name);
+            next.run(request)
+        }
+    }
+
+    fn refuse(_: &mut HttpRequest, _: Next) -> HttpResponse {
+        HttpResponse::new(StatusCode::Unauthorized).with_body("text/plain", "refused")
+    }
+
+    fn trail(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        named(request.path_params.get("trail").map_or("", String::as_str))
+    }
+
+    #[test]
+    fn nested_groups_run_the_outer_middleware_first() {
+        let admin = Router::new().layer(mark("admin ")).route(Method::Get, "/{page}", trail);
+        let api = Router::new()
+            .layer(mark("api "))
+            .nest("/admin", admin)
+            .route(Method::Get, "/open", trail);
+        let router = Router::new().layer(mark("global ")).nest("/api", api).route(Method::Get, "/", trail);
+
+        // The middleware of the router itself is run by the server, not by the endpoint
+        assert_eq!(outcome(&router, Method::Get, "/api/admin/users"), "api admin  page=users");
+        assert_eq!(outcome(&router, Method::Get, "/api/open"), "api ");
+        assert_eq!(outcome(&router, Method::Get, "/"), "");
+        assert_eq!(router.middleware().len(), 1);
+    }
+
+    #[test]
+    fn group_middleware_can_refuse_its_routes_only() {
+        let admin = Router::new().layer(refuse).route(Method::Post, "/shutdown", trail);
+        let router = Router::new().nest("/admin", admin).route(Method::Get, "/open", trail);
+
+        assert_eq!(outcome(&router, Method::Post, "/admin/shutdown"), "refused");
+        assert_eq!(outcome(&router, Method::Get, "/open"), "");
+    }
+
+    fn missing(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        Err(ApiError::NotFound(format!("No recipe {}", request.path_params["id"])))
+    }
+
+    #[test]
+    fn handler_errors_become_responses() {
+        let router = Router::new().route(Method::Get, "/recipes/{id}", missing);
+        let endpoint = match router.find(Method::Get, "/recipes/7") {
+            RouteMatch::Found(endpoint, _) => endpoint,
+            _ => panic!("expected a route"),
+        };
+        let mut request = HttpRequest::from_parts("GET", "/recipes/7", HttpVersion::Http11, HeaderMap::new(), Vec::new()).unwrap();
+        request.path_params.insert("id".to_string(), "7".to_string());
+
+        let response = endpoint.call(&mut request);
+        assert_eq!(response.status, StatusCode::NotFound);
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["detail"], "No recipe 7");
+    }
+
+    #[test]
+    fn malformed_patterns_are_rejected() {
+        for (pattern, message) in [
+            ("recipes", "pattern must start with '/'"),
+            ("/{*rest}/more", "a wildcard must be the last segment"),
+            ("/{}", "parameters need a name"),
+            ("/{*}", "parameters need a name"),
+            ("/id-{id}", "braces must enclose a whole segment"),
+        ] {
+            assert_eq!(parse_pattern(pattern).err(), Some(message), "{}", pattern);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid route /{}: parameters need a name")]
+    fn adding_a_malformed_route_panics() {
+        let _ = Router::new().route(Method::Get, "/{}", root);
+    }
+
+    #[test]
+    #[should_panic(expected = "A prefix cannot contain a wildcard")]
+    fn prefix_with_a_wildcard_panics() {
+        let _ = Router::new().nest("/{*rest}", Router::new());
+    }
+}
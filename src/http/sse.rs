@@ -0,0 +1,65 @@
+use std::sync::mpsc::Receiver;
+use crate::http::request::HttpRequest;
+use crate::http::websocket::Notify;
+
+/// A comment line, sent when nothing happened for a while so proxies keep the stream open
+pub const HEARTBEAT: &str = ": heartbeat\n\n";
+
+/// Subscribes an event stream to the formatted events pushed to it.
+/// Events after the given id, the `Last-Event-ID` of a reconnecting client, are replayed first.
+pub type Subscribe = fn(Option<u64>, Notify) -> Receiver<String>;
+
+/// Formats one event of the `text/event-stream` format.
+/// Every line of the data gets its own `data:` field, the client joins them again.
+pub fn format_event(id: u64, name: &str, data: &str) -> String {
+    let mut event = format!("id: {}\nevent: {}\n", id, name);
+    for line in data.lines() {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+/// The id of the last event a reconnecting client received
+pub fn last_event_id(request: &HttpRequest) -> Option<u64> {
+    request.headers.get("Last-Event-ID").and_then(|value| value.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::HeaderMap;
+    use crate::http::request::HttpVersion;
+
+    fn request_with_last_event_id(value: Option<&str>) -> HttpRequest {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = value {
+            headers.insert("Last-Event-ID", value);
+        }
+        HttpRequest::from_parts("GET", "/events", HttpVersion::Http11, headers, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn formats_an_event() {
+        assert_eq!(
+            format_event(7, "recipe_deleted", r#"{"type":"recipe_deleted","id":3}"#),
+            "id: 7\nevent: recipe_deleted\ndata: {\"type\":\"recipe_deleted\",\"id\":3}\n\n"
+        );
+    }
+
+    #[test]
+    fn every_line_of_the_data_gets_a_data_field() {
+        assert_eq!(format_event(1, "note", "first\nsecond"), "id: 1\nevent: note\ndata: first\ndata: second\n\n");
+    }
+
+    #[test]
+    fn reads_the_last_event_id() {
+        assert_eq!(last_event_id(&request_with_last_event_id(Some("42"))), Some(42));
+        assert_eq!(last_event_id(&request_with_last_event_id(Some(" 42 "))), Some(42));
+        assert_eq!(last_event_id(&request_with_last_event_id(Some("latest"))), None);
+        assert_eq!(last_event_id(&request_with_last_event_id(Some("-1"))), None);
+        assert_eq!(last_event_id(&request_with_last_event_id(None)), None);
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crate::http::request::{HttpRequest, HttpVersion};
+
+/// Appended to the client's key before hashing, RFC 6455 section 1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The only protocol version of RFC 6455
+pub const VERSION: &str = "13";
+
+/// Control frames carry at most this many bytes and are never fragmented
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Opcodes of RFC 6455 section 5.2.
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Called whenever a message for a WebSocket client is queued, e.g. to wake an event loop.
+pub type Notify = Arc<dyn Fn() + Send + Sync>;
+
+/// Subscribes a WebSocket connection to the text messages pushed to it.
+pub type Subscribe = fn(Notify) -> Receiver<String>;
+
+/// Why the opening handshake was refused.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// A plain HTTP request, the client has to ask for the upgrade
+    NotUpgrade,
+    /// The client speaks another version of the protocol
+    UnsupportedVersion,
+    BadRequest(&'static str),
+}
+
+/// Checks the opening handshake of RFC 6455 section 4.2.1.
+/// Returns the value of the `Sec-WebSocket-Accept` header.
+pub fn handshake(request: &HttpRequest) -> Result<String, HandshakeError> {
+    if !request.headers.has_element("Upgrade", "websocket") || !request.headers.has_element("Connection", "upgrade") {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    if request.version != HttpVersion::Http11 {
+        return Err(HandshakeError::BadRequest("WebSocket requires HTTP/1.1"));
+    }
+    if request.headers.get("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+
+    // The key is 16 random bytes in base64
+    let key = request
+        .headers
+        .get("Sec-WebSocket-Key")
+        .map(str::trim)
+        .ok_or(HandshakeError::BadRequest("Sec-WebSocket-Key is missing"))?;
+    match STANDARD.decode(key) {
+        Ok(decoded) if decoded.len() == 16 => {}
+        _ => return Err(HandshakeError::BadRequest("Sec-WebSocket-Key is invalid")),
+    }
+
+    Ok(accept_key(key))
+}
+
+/// Proves to the client that the server understood its handshake
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.digest().bytes())
+}
+
+/// Status codes of close frames, RFC 6455 section 7.4.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal = 1000,
+    GoingAway = 1001,
+    ProtocolError = 1002,
+    InvalidPayload = 1007,
+    MessageTooBig = 1009,
+}
+
+/// A complete message from the client.
+#[derive(Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// A message which arrives in several frames.
+struct Fragments {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// The server side of a WebSocket connection without any I/O.
+/// Received bytes go into `receive`, messages come out of `next_message`,
+/// and everything to send is collected in `take_output`.
+pub struct WebSocketSession {
+    max_message_size: usize,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    fragments: Option<Fragments>,
+    messages: VecDeque<Message>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocketSession {
+    /// Starts a session after the handshake, larger messages close the connection
+    pub fn new(max_message_size: usize) -> Self {
+        WebSocketSession {
+            max_message_size,
+            input: Vec::new(),
+            output: Vec::new(),
+            fragments: None,
+            messages: VecDeque::new(),
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Processes received bytes, incomplete frames wait for the next call
+    pub fn receive(&mut self, data: &[u8]) {
+        if self.close_received {
+            return;
+        }
+        self.input.extend_from_slice(data);
+
+        let mut position = 0;
+        while !self.close_received {
+            let frame = match parse_frame(&self.input[position..], self.max_message_size) {
+                Ok(Some((frame, used))) => {
+                    position += used;
+                    frame
+                }
+                Ok(None) => break,
+                Err(code) => return self.fail(code),
+            };
+
+            if let Err(code) = self.handle_frame(frame) {
+                return self.fail(code);
+            }
+        }
+        self.input.drain(..position);
+    }
+
+    /// The next complete message from the client
+    pub fn next_message(&mut self) -> Option<Message> {
+        self.messages.pop_front()
+    }
+
+    pub fn send_text(&mut self, text: &str) {
+        if !self.close_sent {
+            write_frame(&mut self.output, TEXT, text.as_bytes());
+        }
+    }
+
+    /// Checks that the client is still there, it answers with a pong
+    pub fn ping(&mut self) {
+        if !self.close_sent {
+            write_frame(&mut self.output, PING, &[]);
+        }
+    }
+
+    /// Starts the closing handshake, the client answers with its own close frame
+    pub fn close(&mut self, code: CloseCode) {
+        if !self.close_sent {
+            self.close_sent = true;
+            write_frame(&mut self.output, CLOSE, &(code as u16).to_be_bytes());
+        }
+    }
+
+    /// Everything which should be written to the connection
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// The server started closing and waits for the client
+    pub fn is_closing(&self) -> bool {
+        self.close_sent
+    }
+
+    /// The connection can be closed once the output is written
+    pub fn is_finished(&self) -> bool {
+        self.close_sent && self.close_received
+    }
+
+    /// A broken client gets a close frame and no further attention
+    fn fail(&mut self, code: CloseCode) {
+        eprintln!("WebSocket protocol error: {:?}", code);
+        self.close(code);
+        self.close_received = true;
+        self.input.clear();
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), CloseCode> {
+        match frame.opcode {
+            PING => write_frame(&mut self.output, PONG, &frame.payload),
+            PONG => {}
+            CLOSE => {
+                self.close_received = true;
+                // Valid codes and a close frame without code are answered with a normal close
+                let code = match frame.payload.len() {
+                    0 => CloseCode::Normal,
+                    1 => return Err(CloseCode::ProtocolError),
+                    _ if std::str::from_utf8(&frame.payload[2..]).is_err() => return Err(CloseCode::InvalidPayload),
+                    _ => match u16::from_be_bytes([frame.payload[0], frame.payload[1]]) {
+                        1000 | 1001 | 1002 | 1003 | 1007..=1011 | 3000..=4999 => CloseCode::Normal,
+                        _ => return Err(CloseCode::ProtocolError),
+                    },
+                };
+                self.close(code);
+            }
+            TEXT | BINARY => {
+                if self.fragments.is_some() {
+                    return Err(CloseCode::ProtocolError);
+                }
+                match frame.fin {
+                    true => self.finish_message(frame.opcode, frame.payload)?,
+                    false => {
+                        self.fragments = Some(Fragments {
+                            opcode: frame.opcode,
+                            payload: frame.payload,
+                        })
+                    }
+                }
+            }
+            CONTINUATION => {
+                let fragments = self.fragments.as_mut().ok_or(CloseCode::ProtocolError)?;
+                if fragments.payload.len() + frame.payload.len() > self.max_message_size {
+                    return Err(CloseCode::MessageTooBig);
+                }
+                fragments.payload.extend_from_slice(&frame.payload);
+
+                if frame.fin {
+                    if let Some(fragments) = self.fragments.take() {
+                        self.finish_message(fragments.opcode, fragments.payload)?;
+                    }
+                }
+            }
+            _ => return Err(CloseCode::ProtocolError),
+        }
+        Ok(())
+    }
+
+    fn finish_message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), CloseCode> {
+        let message = match opcode {
+            TEXT => Message::Text(String::from_utf8(payload).map_err(|_| CloseCode::InvalidPayload)?),
+            _ => Message::Binary(payload),
+        };
+        self.messages.push_back(message);
+        Ok(())
+    }
+}
+
+/// A frame with the mask already removed.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Reads one frame from the start of the buffer, `None` until the whole frame is there
+fn parse_frame(buffer: &[u8], max_message_size: usize) -> Result<Option<(Frame, usize)>, CloseCode> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0f;
+    // No extension was negotiated, so the reserved bits stay zero
+    if buffer[0] & 0x70 != 0 {
+        return Err(CloseCode::ProtocolError);
+    }
+    // Every frame from a client is masked
+    if buffer[1] & 0x80 == 0 {
+        return Err(CloseCode::ProtocolError);
+    }
+
+    let (length, mut position) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        }
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+
+    if opcode & 0x8 != 0 && (length as usize > MAX_CONTROL_PAYLOAD || !fin) {
+        return Err(CloseCode::ProtocolError);
+    }
+    if length > max_message_size as u64 {
+        return Err(CloseCode::MessageTooBig);
+    }
+
+    let length = length as usize;
+    if buffer.len() < position + 4 + length {
+        return Ok(None);
+    }
+
+    let mask = [buffer[position], buffer[position + 1], buffer[position + 2], buffer[position + 3]];
+    position += 4;
+    let payload = buffer[position..position + length]
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| byte ^ mask[index % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, position + length)))
+}
+
+/// Appends an unmasked frame, servers never mask
+fn write_frame(output: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    output.push(0x80 | opcode);
+    match payload.len() {
+        length if length < 126 => output.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            output.push(126);
+            output.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            output.push(127);
+            output.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    output.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::HeaderMap;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// A frame as a client sends it, masked and with the shortest length encoding
+    fn client_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first_byte];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ MASK[index % 4]));
+        frame
+    }
+
+    fn text(session: &mut WebSocketSession) -> String {
+        match session.next_message() {
+            Some(Message::Text(text)) => text,
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    /// The code of the close frame the server sent last
+    fn close_code(session: &mut WebSocketSession) -> u16 {
+        let output = session.take_output();
+        let start = output.len() - 4;
+        assert_eq!(output[start..start + 2], [0x80 | CLOSE, 2]);
+        u16::from_be_bytes([output[start + 2], output[start + 3]])
+    }
+
+    fn upgrade_request(key: &str) -> HttpRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", "localhost");
+        headers.insert("Upgrade", "websocket");
+        headers.insert("Connection", "keep-alive, Upgrade");
+        headers.insert("Sec-WebSocket-Version", "13");
+        headers.insert("Sec-WebSocket-Key", key);
+        HttpRequest::from_parts("GET", "/ws", HttpVersion::Http11, headers, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn accept_key_of_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn handshake_answers_with_the_accept_key() {
+        let request = upgrade_request("dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(handshake(&request).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn handshake_rejects_bad_requests() {
+        let mut request = upgrade_request("dGhlIHNhbXBsZSBub25jZQ==");
+        request.headers.insert("Sec-WebSocket-Version", "8");
+        assert!(matches!(handshake(&request), Err(HandshakeError::UnsupportedVersion)));
+
+        // 15 bytes instead of 16
+        let request = upgrade_request("dGhlIHNhbXBsZSBub25jZQ");
+        assert!(matches!(handshake(&request), Err(HandshakeError::BadRequest(_))));
+
+        let mut request = upgrade_request("dGhlIHNhbXBsZSBub25jZQ==");
+        request.headers.insert("Upgrade", "h2c");
+        assert!(matches!(handshake(&request), Err(HandshakeError::NotUpgrade)));
+    }
+
+    #[test]
+    fn masked_text_frame_is_a_message() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | TEXT, b"Hello"));
+        assert_eq!(text(&mut session), "Hello");
+        assert!(session.next_message().is_none());
+    }
+
+    #[test]
+    fn frames_may_arrive_in_pieces() {
+        let mut session = WebSocketSession::new(1024);
+        let mut input = client_frame(0x80 | TEXT, b"first");
+        input.extend(client_frame(0x80 | BINARY, &[1, 2, 3]));
+        for byte in input {
+            session.receive(&[byte]);
+        }
+        assert_eq!(text(&mut session), "first");
+        assert!(matches!(session.next_message(), Some(Message::Binary(payload)) if payload == [1, 2, 3]));
+    }
+
+    #[test]
+    fn unmasked_frame_is_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&[0x80 | TEXT, 5, b'H', b'e', b'l', b'l', b'o']);
+        assert!(session.next_message().is_none());
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn reserved_bits_are_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | 0x40 | TEXT, b"Hello"));
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn unknown_opcode_is_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | 0x3, b""));
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn reads_16_bit_lengths() {
+        let payload = vec![b'a'; 300];
+        let frame = client_frame(0x80 | TEXT, &payload);
+        assert_eq!(frame[1..4], [0x80 | 126, 0x01, 0x2c]);
+
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&frame);
+        assert_eq!(text(&mut session).len(), 300);
+    }
+
+    #[test]
+    fn reads_64_bit_lengths() {
+        let payload = vec![b'a'; 70_000];
+        let frame = client_frame(0x80 | TEXT, &payload);
+        assert_eq!(frame[1], 0x80 | 127);
+
+        let mut session = WebSocketSession::new(100_000);
+        session.receive(&frame);
+        assert_eq!(text(&mut session).len(), 70_000);
+    }
+
+    #[test]
+    fn too_large_message_is_refused_before_it_arrives() {
+        let mut session = WebSocketSession::new(1024);
+        // Only the header of a 64-bit length frame
+        session.receive(&[0x80 | BINARY, 0x80 | 127, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(close_code(&mut session), CloseCode::MessageTooBig as u16);
+    }
+
+    #[test]
+    fn fragments_are_joined() {
+        let mut session = WebSocketSession::new(1024);
+        let mut input = client_frame(TEXT, b"Hel");
+        // Control frames may come between the fragments
+        input.extend(client_frame(0x80 | PING, b"?"));
+        input.extend(client_frame(CONTINUATION, b"lo, "));
+        input.extend(client_frame(0x80 | CONTINUATION, b"world"));
+        session.receive(&input);
+
+        assert_eq!(text(&mut session), "Hello, world");
+        assert_eq!(session.take_output(), [0x80 | PONG, 1, b'?']);
+    }
+
+    #[test]
+    fn fragments_count_towards_the_message_size() {
+        let mut session = WebSocketSession::new(8);
+        let mut input = client_frame(BINARY, &[0; 5]);
+        input.extend(client_frame(0x80 | CONTINUATION, &[0; 5]));
+        session.receive(&input);
+        assert!(session.next_message().is_none());
+        assert_eq!(close_code(&mut session), CloseCode::MessageTooBig as u16);
+    }
+
+    #[test]
+    fn continuation_without_a_start_is_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | CONTINUATION, b"lost"));
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn new_message_inside_fragments_is_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        let mut input = client_frame(TEXT, b"Hel");
+        input.extend(client_frame(0x80 | TEXT, b"lo"));
+        session.receive(&input);
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_invalid_payload() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | TEXT, &[0xc3, 0x28]));
+        assert_eq!(close_code(&mut session), CloseCode::InvalidPayload as u16);
+    }
+
+    #[test]
+    fn too_large_control_frame_is_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | PING, &[0; 126]));
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_a_protocol_error() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(PING, b""));
+        assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn ping_is_answered_with_the_same_payload() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | PING, b"abc"));
+        assert_eq!(session.take_output(), [0x80 | PONG, 3, b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn close_from_the_client_is_answered() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | CLOSE, &[0x03, 0xe9, b'b', b'y', b'e']));
+        assert_eq!(close_code(&mut session), CloseCode::Normal as u16);
+        assert!(session.is_finished());
+
+        // Nothing is read after the close frame
+        session.receive(&client_frame(0x80 | TEXT, b"late"));
+        assert!(session.next_message().is_none());
+    }
+
+    #[test]
+    fn close_without_a_code_is_answered() {
+        let mut session = WebSocketSession::new(1024);
+        session.receive(&client_frame(0x80 | CLOSE, b""));
+        assert_eq!(close_code(&mut session), CloseCode::Normal as u16);
+    }
+
+    #[test]
+    fn close_with_a_bad_code_is_a_protocol_error() {
+        for payload in [&[0x03][..], &[0x03, 0xed], &[0x00, 0x00]] {
+            let mut session = WebSocketSession::new(1024);
+            session.receive(&client_frame(0x80 | CLOSE, payload));
+            assert_eq!(close_code(&mut session), CloseCode::ProtocolError as u16, "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn server_close_waits_for_the_client() {
+        let mut session = WebSocketSession::new(1024);
+        session.close(CloseCode::GoingAway);
+        assert_eq!(close_code(&mut session), CloseCode::GoingAway as u16);
+        assert!(session.is_closing());
+        assert!(!session.is_finished());
+
+        // Nothing is sent after the close frame
+        session.send_text("late");
+        assert!(session.take_output().is_empty());
+
+        session.receive(&client_frame(0x80 | CLOSE, &[0x03, 0xe9]));
+        assert!(session.is_finished());
+        assert!(session.take_output().is_empty());
+    }
+
+    #[test]
+    fn server_frames_are_unmasked_with_the_shortest_length() {
+        let mut session = WebSocketSession::new(1024);
+        session.send_text("hi");
+        assert_eq!(session.take_output(), [0x80 | TEXT, 2, b'h', b'i']);
+
+        session.send_text(&"a".repeat(300));
+        assert_eq!(session.take_output()[..4], [0x80 | TEXT, 126, 0x01, 0x2c]);
+
+        session.send_text(&"a".repeat(70_000));
+        assert_eq!(session.take_output()[..10], [0x80 | TEXT, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+    }
+}
@@ -3,247 +3,187 @@ mod models;
 mod assembler;
 mod dto;
 mod controllers;
+mod middleware;
 mod services;
-
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::os::raw::c_int;
-use std::str;
-use winapi::shared::inaddr::IN_ADDR;
-use winapi::shared::ws2def::{AF_INET, SOCKADDR, SOCKADDR_IN, SOCK_STREAM};
-use winapi::um::winsock2::{accept, bind, closesocket, listen, recv, send, socket, WSACleanup, WSAStartup, INVALID_SOCKET, SOCKET_ERROR, WSADATA};
-use crate::controllers::recipe_controller::{add_recipe, delete_recipe_by_id, get_all_recipes, get_recipe_by_id, update_recipe};
-use crate::response_builder::create_http_success_response;
-
-// Define the format of the handlers for the response
-type Handler = fn(&str, Option<&str>, &str) -> String;
-
-pub fn get_dispatcher() -> HashMap<(&'static str, &'static str), Handler> {
-    let mut dispatcher: HashMap<(&str, &str), Handler> = HashMap::new();
-
-    // Register routes with methods
-    dispatcher.insert(("GET", "/RecipeService/GetRecipeById"), get_recipe_by_id);
-    dispatcher.insert(("GET", "/RecipeService/GetAllRecipes"), get_all_recipes);
-    dispatcher.insert(("PUT", "/RecipeService/UpdateRecipe"), update_recipe);
-    dispatcher.insert(("POST", "/RecipeService/AddRecipe"), add_recipe);
-    dispatcher.insert(("DELETE", "/RecipeService/DeleteRecipeById"), delete_recipe_by_id);
-
-    // Insert a lambda for shutdown
-    dispatcher.insert(("GET", "/ShutDown"), |_req_content_type, _query_params, _req_body| {
-        create_http_success_response("Shutting down")
-    });
-
-    dispatcher
+mod transport;
+mod http;
+mod server;
+mod config;
+mod error;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use crate::config::{Command, ServerConfig};
+use crate::controllers::{admin_controller, live_controller, recipe_controller};
+use crate::http::middleware::Next;
+use crate::http::problem;
+use crate::http::request::{HttpRequest, Method};
+use crate::http::response::HttpResponse;
+use crate::http::router::{RouteMatch, Router};
+use crate::middleware::logging;
+use crate::server::{connection, shutdown, ServerMode};
+
+/// The routes of the server, built once and shared by all workers
+static ROUTER: OnceLock<Router> = OnceLock::new();
+
+/// Puts the routes of all controllers together, behind the middleware for every request
+fn build_router() -> Router {
+    Router::new()
+        .layer(logging::log_request)
+        .nest("/RecipeService", recipe_controller::service_routes())
+        .nest("/RecipeService", live_controller::routes())
+        .nest("/recipes", recipe_controller::routes())
+        .nest("/admin", admin_controller::routes())
 }
 
-fn htons(host_short: u16) -> u16 {
-    host_short.to_be()
+/// The shared routes, built on first use
+pub fn router() -> &'static Router {
+    ROUTER.get_or_init(build_router)
 }
 
 fn main() {
-
-    unsafe {
-        // Initialize win socket
-        let mut wsa_data: WSADATA = std::mem::zeroed();
-
-        // If win socket startup failed
-        if WSAStartup(0x202, &mut wsa_data) != 0 {
-            panic!("Failed to initialize win socket");
+    // Settings from the config file, the environment and the command line
+    let config = match ServerConfig::load(std::env::args().skip(1)) {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::PrintConfig(config)) => {
+            print!("{}", config.to_toml());
+            return;
         }
-
-        // Create a socket
-        let sock_fd = socket(AF_INET, SOCK_STREAM, 0);
-
-        // If it is an invalid socket
-        if sock_fd == INVALID_SOCKET {
-            panic!("Failed to create socket");
+        Ok(Command::Help) => {
+            println!("{}", ServerConfig::usage());
+            return;
         }
-
-        // Bind the socket to an address and port
-        let mut server_addr = SOCKADDR_IN {
-            sin_family: AF_INET as u16,
-            sin_port: htons(8080),
-            sin_addr: IN_ADDR {
-                S_un: std::mem::zeroed(),
-            },
-            sin_zero: [0; 8],
-        };
-
-        // Define the localhost
-        let localhost: u32 = u32::from(Ipv4Addr::UNSPECIFIED);
-
-        // Assign to the S_addr field within the union to localhost
-        *server_addr.sin_addr.S_un.S_addr_mut() = localhost;
-
-        // Bind the socket file descriptor to the server address
-        let bind_result = bind(
-            sock_fd,
-            &server_addr as *const SOCKADDR_IN as *const SOCKADDR,
-            std::mem::size_of::<SOCKADDR_IN>() as c_int,
-        );
-
-        // If the bind result is a socket error
-        if bind_result == SOCKET_ERROR {
-            let error_code = winapi::um::winsock2::WSAGetLastError();
-            panic!("Failed to bind socket, error code: {}", error_code);
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("Use --help to list the options");
+            std::process::exit(2);
         }
+    };
 
-        // Listen for incoming connections
-        if listen(sock_fd, 10) == SOCKET_ERROR {
-            panic!("Failed to listen on socket");
-        }
-
-        // Server started successfully
-        println!("Server listening on 127.0.0.1:8080");
-
-        // Accept incoming connections while server is running
-        loop {
-            let mut client_addr: SOCKADDR_IN = std::mem::zeroed();
-            let mut addr_len: c_int = std::mem::size_of::<SOCKADDR_IN>() as c_int;
-            let client_fd = accept(
-                sock_fd,
-                &mut client_addr as *mut SOCKADDR_IN as *mut SOCKADDR,
-                &mut addr_len as *mut c_int,
-            );
-
-            // If the client file descriptor is invalid
-            if client_fd == INVALID_SOCKET {
-                eprintln!("Failed to accept connection");
-                continue;
-            }
-
-            println!("Accepted new connection");
-
-            // Handle HTTP Request
-            let keep_running = handle_http_request(client_fd);
+    // The admin shutdown endpoint is only available with a token
+    if let Some(token) = &config.admin_token {
+        shutdown::set_admin_token(token.clone());
+    }
 
-            // Exit loop
-            if keep_running < 1 {
-                break;
-            }
+    // Requests whose handler hangs are answered with 503
+    if let Some(timeout) = config.handler_timeout() {
+        connection::set_handler_timeout(timeout, config.workers);
+    }
 
-        }
+    // The admin routes depend on the token, so the routes are built after it is known
+    router();
 
-        // Server closed by the api
-        println!("Close socket and cleanup");
+    // Shut down gracefully on SIGINT and SIGTERM
+    if let Err(error) = shutdown::listen_for_signals() {
+        eprintln!("Failed to register signal handlers: {}", error);
+    }
 
-        // Cleanup win socket and the socket file descriptor
-        closesocket(sock_fd);
-        WSACleanup();
+    match config.mode {
+        ServerMode::Threaded => server::threaded::run(&config),
+        ServerMode::EventLoop => server::event_loop::run(&config),
     }
 }
 
-unsafe fn handle_http_request(client_fd: usize) -> i8 {
-
-    // Define the buffer for the incoming message
-    let mut buffer = [0u8; 1024];
-
-    // Receive data from the client
-    let bytes_read = recv(
-        client_fd,
-        buffer.as_mut_ptr() as *mut winapi::ctypes::c_char,
-        buffer.len() as c_int,
-        0,
-    );
-
-    // If recv failed
-    if bytes_read == SOCKET_ERROR {
-        let error_code = winapi::um::winsock2::WSAGetLastError();
-        eprintln!("Failed to read from connection, error code: {}", error_code);
-        return -1;
-    }
+fn build_response_from_request(request: &mut HttpRequest) -> HttpResponse {
+    handle_request(router(), request)
+}
 
-    // Build the response
-    let response = build_response_from_request(buffer, bytes_read);
-
-    // Send the response in chunks to ensure it is fully sent
-    let mut total_sent = 0;
-    let response_bytes = response.as_bytes();
-    while total_sent < response_bytes.len() {
-        let bytes_sent = send(
-            client_fd,
-            response_bytes[total_sent..].as_ptr() as *const winapi::ctypes::c_char,
-            (response_bytes.len() - total_sent) as c_int,
-            0,
-        );
-
-        // If send failed
-        if bytes_sent == SOCKET_ERROR {
-            let error_code = winapi::um::winsock2::WSAGetLastError();
-            eprintln!("Failed to send response, error code: {}", error_code);
-            return -1;
+/// Runs the request through the middleware and the routes of `router`
+fn handle_request(router: &Router, request: &mut HttpRequest) -> HttpResponse {
+    // Error responses name the request they belong to
+    let path = request.path.clone();
+    problem::with_instance(&path, || {
+        // The global middleware also sees requests without a route.
+        // A panic only fails its own request, the worker or event loop keeps serving the others
+        let endpoint = |request: &mut HttpRequest| dispatch(router, request);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| Next::new(router.middleware(), &endpoint).run(request)));
+        match result {
+            Ok(response) => response,
+            Err(payload) => {
+                eprintln!("Request {} panicked: {}", request.id, server::panic_message(payload.as_ref()));
+                response_builder::create_http_internal_server_error_response("The server failed to handle the request")
+            }
         }
+    })
+}
 
-        total_sent += bytes_sent as usize;
-    }
-
-    println!("Response sent successfully");
-
-    // Close the client socket
-    closesocket(client_fd);
+/// Runs the route of the request, or answers why there is none
+fn dispatch(router: &Router, request: &mut HttpRequest) -> HttpResponse {
+    // Find and execute the corresponding handler with the parameters of its path
+    let mut response = match router.find(request.method, &request.path) {
+        RouteMatch::Found(endpoint, path_params) => {
+            request.path_params = path_params;
+            endpoint.call(request)
+        }
+        RouteMatch::MethodNotAllowed(allowed) => response_builder::create_http_method_not_allowed_response(&allowed),
+        RouteMatch::Options(allowed) => response_builder::create_http_options_response(&allowed),
+        RouteMatch::NotFound => response_builder::create_http_not_found_response(Some("404 Not Found")),
+    };
 
-    if response.contains("Shutting down") {
-        return -1;
+    // HEAD runs the GET handler, the connection never switches to a stream for it
+    if request.method == Method::Head {
+        response.upgrade = None;
     }
 
-    // Continue loop
-    1
+    response
 }
 
-fn build_response_from_request(buffer: [u8; 1024], bytes_read: c_int) -> String {
-    // Parse the received request
-    let request = str::from_utf8(&buffer[..bytes_read as usize]).unwrap_or("");
-    println!("Received request:\n{}", request);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use crate::http::headers::HeaderMap;
+    use crate::http::request::HttpVersion;
+    use crate::http::response::StatusCode;
 
-    // Parse request line
-    let mut lines = request.lines();
-    let request_line = lines.next().unwrap_or("");
-    let mut parts = request_line.split_whitespace();
-    let method = parts.next().unwrap_or("");
-    let url  = parts.next().unwrap_or("/");
+    fn ok(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        Ok(HttpResponse::new(StatusCode::Ok).with_body("text/plain", "ok"))
+    }
 
+    fn broken(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        panic!("the handler is broken")
+    }
 
-    let (path, query_params) = if let Some((p, q)) = url.split_once('?') {
-        (p, Some(q))
-    } else {
-        (url, None)
-    };
+    fn test_router() -> Router {
+        Router::new().route(Method::Get, "/ok", ok).route(Method::Get, "/broken", broken)
+    }
 
-    // Parse headers
-    let mut headers = HashMap::new();
-    while let Some(line) = lines.next() {
-        if line.is_empty() {
-            // End of headers
-            break;
-        }
-        if let Some((key, value)) = line.split_once(": ") {
-            headers.insert(key.to_string(), value.to_string());
-        }
+    fn get(router: &Router, path: &str) -> HttpResponse {
+        let mut request = HttpRequest::from_parts("GET", path, HttpVersion::Http11, HeaderMap::new(), Vec::new()).unwrap();
+        handle_request(router, &mut request)
     }
 
-    // Extract Content-Type
-    let content_type = headers
-        .get("Content-Type")
-        .map(|s| s.as_str())
-        .unwrap_or("text/plain");
+    #[test]
+    fn a_panicking_handler_gets_a_500_problem() {
+        let response = get(&test_router(), "/broken");
+        assert_eq!(response.status, StatusCode::InternalServerError);
 
-    // Parse body
-    let body = lines.collect::<Vec<&str>>().join("\n");
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["detail"], "The server failed to handle the request");
+        assert_eq!(problem["instance"], "/broken");
+    }
 
-    println!(
-        "Method: {}, Path: {}, Query-Parameters: {:?}, Content-Type: {}, Body: {}",
-        method, path, query_params, content_type, body
-    );
+    #[test]
+    fn the_thread_keeps_serving_after_a_panic() {
+        let router = test_router();
+        assert_eq!(get(&router, "/broken").status, StatusCode::InternalServerError);
+        assert_eq!(get(&router, "/ok").status, StatusCode::Ok);
+        assert_eq!(get(&router, "/broken").status, StatusCode::InternalServerError);
+        assert_eq!(get(&router, "/ok").body, b"ok");
+    }
 
-    // Get dispatcher
-    let dispatcher = get_dispatcher();
+    #[test]
+    fn a_panicking_middleware_gets_a_500_too() {
+        let router = test_router().layer(|_: &mut HttpRequest, _: Next| -> HttpResponse { panic!("the middleware is broken") });
+        assert_eq!(get(&router, "/ok").status, StatusCode::InternalServerError);
+    }
 
-    // Find and execute the corresponding handler
-    let response = if let Some(handler) = dispatcher.get(&(method, path)) {
-        handler(content_type, query_params, &body)
-    } else {
-        response_builder::create_http_not_found_response(Some("404 Not Found"))
-    };
+    #[test]
+    fn errors_without_a_route_name_the_request() {
+        let response = get(&test_router(), "/missing");
+        assert_eq!(response.status, StatusCode::NotFound);
 
-    response
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["instance"], "/missing");
+    }
 }
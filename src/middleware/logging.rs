@@ -0,0 +1,19 @@
+use crate::http::middleware::Next;
+use crate::http::request::HttpRequest;
+use crate::http::response::HttpResponse;
+
+/// Prints every request before it is handled
+pub fn log_request(request: &mut HttpRequest, next: Next) -> HttpResponse {
+    println!(
+        "Request {}: Method: {}, Path: {}, Query-Parameters: {:?}, Content-Type: {}, Body: {}, Client: {:?}",
+        request.id,
+        request.method,
+        request.path,
+        request.query,
+        request.content_type(),
+        String::from_utf8_lossy(&request.body),
+        request.peer_addr
+    );
+
+    next.run(request)
+}
@@ -1,70 +1,164 @@
-fn create_http_response(status: &str, content_type: &str, body: &str) -> String {
-    let status_line = format!("HTTP/1.1 {}", status);
-    let headers = format!(
-        "Content-Type: {}\r\nContent-Length: {}\r\nConnection: close",
-        content_type,
-        body.len()
-    );
-    format_response(status_line, headers, body.to_string())
+use crate::http::problem::{FieldError, Problem, PROBLEM_JSON};
+use crate::http::request::Method;
+use crate::http::response::{HttpResponse, StatusCode, Upgrade};
+use crate::http::sse;
+use crate::http::websocket;
+
+fn create_http_response(status: StatusCode, content_type: &str, body: &str) -> HttpResponse {
+    HttpResponse::new(status).with_body(content_type, body)
+}
+
+/// Error responses describe the problem as `application/problem+json` (RFC 7807).
+fn create_http_problem_response(status: StatusCode, detail: &str) -> HttpResponse {
+    create_http_response(status, PROBLEM_JSON, &Problem::new(status, detail, Vec::new()).to_json())
 }
 
 /// Creates a http success response.
-pub fn create_http_success_response(body: &str) -> String {
-    create_http_response("200 OK", "application/json", body)
+pub fn create_http_success_response(body: &str) -> HttpResponse {
+    create_http_response(StatusCode::Ok, "application/json", body)
 }
 
 /// Creates a http not found response.
 /// Use None to use the default message.
 /// Use Some("") to add a custom message to the response.
-pub fn create_http_not_found_response(body: Option<&str>) -> String {
+pub fn create_http_not_found_response(body: Option<&str>) -> HttpResponse {
     let body = body.unwrap_or("The requested resource was not found.");
-    create_http_response("404 Not Found", "text/plain", body)
+    create_http_problem_response(StatusCode::NotFound, body)
+}
+
+/// Creates a http moved permanently response, clients may switch the method to GET.
+pub fn create_http_moved_permanently_response(location: &str) -> HttpResponse {
+    create_http_response(StatusCode::MovedPermanently, "text/plain", location).with_header("Location", location)
+}
+
+/// Creates a http permanent redirect response, clients repeat the request with the same method and body.
+pub fn create_http_permanent_redirect_response(location: &str) -> HttpResponse {
+    create_http_response(StatusCode::PermanentRedirect, "text/plain", location).with_header("Location", location)
+}
+
+/// Creates a http switching protocols response which accepts a WebSocket handshake.
+/// The connection then receives the messages of the subscription.
+pub fn create_http_switching_protocols_response(accept_key: &str, subscribe: websocket::Subscribe) -> HttpResponse {
+    let mut response = HttpResponse::new(StatusCode::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key);
+    response.upgrade = Some(Upgrade::WebSocket(subscribe));
+    response
+}
+
+/// Creates a http success response whose body is a stream of server-sent events.
+/// The events after `last_event_id` are replayed first, the stream ends with the connection.
+pub fn create_http_event_stream_response(subscribe: sse::Subscribe, last_event_id: Option<u64>) -> HttpResponse {
+    let mut response = HttpResponse::new(StatusCode::Ok)
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache");
+    response.upgrade = Some(Upgrade::EventStream(subscribe, last_event_id));
+    response
+}
+
+/// Creates a http upgrade required response, the endpoint only speaks WebSocket.
+pub fn create_http_upgrade_required_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::UpgradeRequired, body)
+        .with_header("Upgrade", "websocket")
+        .with_header("Sec-WebSocket-Version", websocket::VERSION)
 }
 
 /// Creates a http bad request response.
-pub fn create_http_bad_request_response(body: &str) -> String {
-    create_http_response("400 Bad Request", "text/plain", body)
+pub fn create_http_bad_request_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::BadRequest, body)
 }
 
 /// Creates a http internal server error response
-pub fn create_http_internal_server_error_response(body: &str) -> String {
-    create_http_response("500 Internal Server Error", "text/plain", body)
+pub fn create_http_internal_server_error_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::InternalServerError, body)
+}
+
+/// Creates a http request timeout response.
+pub fn create_http_request_timeout_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::RequestTimeout, body)
+}
+
+/// Creates a http payload too large response.
+pub fn create_http_payload_too_large_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::PayloadTooLarge, body)
+}
+
+/// Creates a http unsupported media type response.
+pub fn create_http_unsupported_media_type_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::UnsupportedMediaType, body)
+}
+
+/// Creates a http unprocessable entity response, the request was understood but its content is invalid.
+/// The invalid fields are listed in the `errors` member of the problem.
+pub fn create_http_unprocessable_entity_response(body: &str, errors: Vec<FieldError>) -> HttpResponse {
+    let problem = Problem::new(StatusCode::UnprocessableEntity, body, errors);
+    create_http_response(StatusCode::UnprocessableEntity, PROBLEM_JSON, &problem.to_json())
+}
+
+/// Creates a http request header fields too large response.
+pub fn create_http_request_header_fields_too_large_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::RequestHeaderFieldsTooLarge, body)
+}
+
+/// Creates a http not implemented response.
+pub fn create_http_not_implemented_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::NotImplemented, body)
+}
+
+/// Creates a http service unavailable response.
+pub fn create_http_service_unavailable_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::ServiceUnavailable, body)
+}
+
+/// Creates a http version not supported response.
+pub fn create_http_version_not_supported_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::HttpVersionNotSupported, body)
 }
 
 /// Create a http created response
-pub fn create_http_created_response(id: &str, body: &str) -> String {
-    let status_line = "HTTP/1.1 201 Created";
-    let headers = format!(
-        "Content-Type: text/plain\r\nId: {}\r\nConnection: close",
-        id
-    );
-    format_response(status_line.to_string(), headers, body.to_string())
+pub fn create_http_created_response(id: &str, body: &str) -> HttpResponse {
+    create_http_response(StatusCode::Created, "text/plain", body).with_header("Id", id)
+}
+
+/// Creates a http created response with the JSON of the new resource and where to find it
+pub fn create_http_created_at_response(location: &str, body: &str) -> HttpResponse {
+    create_http_response(StatusCode::Created, "application/json", body).with_header("Location", location)
+}
+
+/// Creates a http method not allowed response listing the methods of the resource.
+pub fn create_http_method_not_allowed_response(allowed: &[Method]) -> HttpResponse {
+    let allow = allow_header(allowed);
+    create_http_problem_response(StatusCode::MethodNotAllowed, &format!("Allowed methods: {}", allow))
+        .with_header("Allow", &allow)
+}
+
+/// Creates the answer to an OPTIONS request, which lists the methods of the resource.
+pub fn create_http_options_response(allowed: &[Method]) -> HttpResponse {
+    HttpResponse::new(StatusCode::NoContent).with_header("Allow", &allow_header(allowed))
+}
+
+fn allow_header(allowed: &[Method]) -> String {
+    allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")
+}
+
+/// Creates a http conflict response.
+pub fn create_http_conflict_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::Conflict, body)
 }
 
 /// Creates a http unauthorized response.
-/// Currently not used
-pub fn _create_http_unauthorized_response(body: &str) -> String {
-    create_http_response("401 Unauthorized", "text/plain", body)
+pub fn create_http_unauthorized_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::Unauthorized, body)
 }
 
 /// Creates a http forbidden response.
 /// Currently not used
-pub fn _create_http_forbidden_response(body: &str) -> String {
-    create_http_response("403 Forbidden", "text/plain", body)
+pub fn _create_http_forbidden_response(body: &str) -> HttpResponse {
+    create_http_problem_response(StatusCode::Forbidden, body)
 }
 
 /// Creates a http no content response
-/// Currently not used
-pub fn _create_http_no_content_response() -> String {
-    create_http_response("204 No Content", "text/plain", "")
-}
-
-/// Formats the response to a valid HTTP response
-fn format_response(status_line: String, response_headers: String, response_body: String) -> String {
-    format!(
-        "{}\r\n{}\r\n\n{}",
-        status_line,
-        response_headers,
-        response_body
-    )
+pub fn create_http_no_content_response() -> HttpResponse {
+    HttpResponse::new(StatusCode::NoContent)
 }
@@ -0,0 +1,555 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use crate::build_response_from_request;
+use crate::http::request::{HttpRequest, HttpVersion, Method, ParseError};
+use crate::http::request_reader::{RawRequest, ReadError, RequestLimits, RequestReader};
+use crate::http::response::{HttpResponse, Upgrade};
+use crate::response_builder::{create_http_bad_request_response, create_http_moved_permanently_response, create_http_not_implemented_response, create_http_internal_server_error_response, create_http_permanent_redirect_response, create_http_payload_too_large_response, create_http_request_header_fields_too_large_response, create_http_request_timeout_response, create_http_service_unavailable_response, create_http_version_not_supported_response};
+use crate::server::event_stream::serve_event_stream;
+use crate::server::http2::serve_http2;
+use crate::server::shutdown::is_shutdown_requested;
+use crate::server::websocket::serve_websocket;
+use crate::server::worker_pool::WorkerPool;
+use crate::transport::Connection;
+
+/// Settings for persistent connections.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// How long an idle connection waits for the next request
+    pub idle_timeout: Duration,
+    /// How many requests are served on one connection before it is closed
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// A request for the handler threads, when it has to be answered and where its response goes
+type HandlerJob = (HttpRequest, Instant, Sender<HttpResponse>);
+
+/// The threads which run the handlers, set once at startup if there is a handler timeout
+static HANDLERS: OnceLock<Handlers> = OnceLock::new();
+
+/// Plaintext requests are redirected to the HTTPS listener on this port.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsRedirect {
+    pub port: u16,
+}
+
+/// A response together with the decision whether the connection stays open.
+pub struct Reply {
+    pub response: HttpResponse,
+    pub keep_alive: bool,
+    /// The protocol the connection switches to after the response
+    pub upgrade: Option<Upgrade>,
+    /// False for HEAD requests, the head still announces the length of the body
+    pub send_body: bool,
+}
+
+impl Reply {
+    /// The bytes to write to the connection
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.send_body {
+            true => self.response.to_bytes(),
+            false => self.response.head_to_bytes(),
+        }
+    }
+}
+
+/// Builds the reply for the result of reading a request.
+/// Shared by the blocking and the event-driven server, `None` means
+/// the connection should be closed without a response.
+/// `allow_keep_alive` is false once the connection reached its request limit or the server drains.
+/// With `redirect` every request is answered with a redirect to HTTPS instead.
+pub fn reply_for(
+    result: Result<RawRequest, ReadError>,
+    peer_addr: Option<SocketAddr>,
+    keep_alive: &KeepAlive,
+    allow_keep_alive: bool,
+    redirect: Option<HttpsRedirect>,
+) -> Option<Reply> {
+    let mut send_body = true;
+    let (response, wants_keep_alive) = match result {
+        Ok(raw_request) => match HttpRequest::parse(raw_request, peer_addr) {
+            Ok(request) => {
+                let wants_keep_alive = wants_keep_alive(&request);
+                send_body = request.method != Method::Head;
+                (respond(Ok(request), redirect), wants_keep_alive)
+            }
+            Err(error) => (respond(Err(error), redirect), false),
+        },
+        // After a framing error the start of the next request is unknown, so these always close
+        Err(ReadError::HeadersTooLarge) => {
+            (create_http_request_header_fields_too_large_response("Request headers are too large"), false)
+        }
+        Err(ReadError::BodyTooLarge) => {
+            (create_http_payload_too_large_response("Request body is too large"), false)
+        }
+        Err(ReadError::BadRequest(message)) => (create_http_bad_request_response(message), false),
+        Err(ReadError::Timeout) => {
+            (create_http_request_timeout_response("The request was not received in time"), false)
+        }
+        Err(ReadError::Http2Preface) => {
+            (create_http_bad_request_response("HTTP/2 has to start with the connection"), false)
+        }
+        Err(ReadError::NoRequest) => return None,
+        Err(ReadError::ConnectionClosed) => {
+            eprintln!("Connection closed before the request was complete");
+            return None;
+        }
+        Err(ReadError::Io(error)) => {
+            eprintln!("Failed to read from connection: {}", error);
+            return None;
+        }
+    };
+
+    // The connection belongs to the new protocol or the stream, the response has its own headers
+    if let Some(upgrade) = response.upgrade {
+        let mut response = response;
+        // A streamed body has no length, it ends when the connection closes
+        if let Upgrade::EventStream(..) = upgrade {
+            response.headers.insert("Connection", "close");
+        }
+        return Some(Reply {
+            response,
+            keep_alive: true,
+            upgrade: Some(upgrade),
+            send_body,
+        });
+    }
+
+    let keep_alive_connection = wants_keep_alive && allow_keep_alive;
+    let mut response = response;
+
+    if keep_alive_connection {
+        response.headers.insert("Connection", "keep-alive");
+        response.headers.insert(
+            "Keep-Alive",
+            &format!("timeout={}, max={}", keep_alive.idle_timeout.as_secs(), keep_alive.max_requests),
+        );
+    } else {
+        response.headers.insert("Connection", "close");
+    }
+
+    Some(Reply {
+        response,
+        keep_alive: keep_alive_connection,
+        upgrade: None,
+        send_body,
+    })
+}
+
+/// Answers a parsed request, or the reason why it could not be parsed.
+/// Shared by HTTP/1 and HTTP/2.
+pub fn respond(result: Result<HttpRequest, ParseError>, redirect: Option<HttpsRedirect>) -> HttpResponse {
+    match result {
+        Ok(request) => {
+            let access = access_line(&request);
+            let response = match redirect {
+                Some(redirect) => redirect_to_https(&request, redirect),
+                None => dispatch_in_time(request),
+            };
+            println!("{} {} {}", access, response.status.code(), response.body.len());
+            response
+        }
+        Err(ParseError::BadRequest(message)) => create_http_bad_request_response(message),
+        Err(ParseError::MethodNotImplemented) => create_http_not_implemented_response("Request method is not supported"),
+        Err(ParseError::VersionNotSupported) => {
+            create_http_version_not_supported_response("HTTP version is not supported")
+        }
+        Err(ParseError::HeadersTooLarge) => {
+            create_http_request_header_fields_too_large_response("Request headers are too large")
+        }
+        Err(ParseError::BodyTooLarge) => create_http_payload_too_large_response("Request body is too large"),
+    }
+}
+
+/// Runs the handlers on `threads` handler threads, which give up on a handler after `timeout`.
+/// Without a timeout the handlers run on the thread which serves the connection
+pub fn set_handler_timeout(timeout: Duration, threads: usize) {
+    let _ = HANDLERS.set(Handlers::new(timeout, threads, build_response_from_request));
+}
+
+/// Runs the handlers on the handler threads if a handler timeout is set
+fn dispatch_in_time(mut request: HttpRequest) -> HttpResponse {
+    match HANDLERS.get() {
+        Some(handlers) => handlers.dispatch(request),
+        None => build_response_from_request(&mut request),
+    }
+}
+
+/// Handler threads together with how long a handler may take before its request is answered with 503.
+struct Handlers {
+    timeout: Duration,
+    pool: WorkerPool<HandlerJob>,
+}
+
+impl Handlers {
+    /// Starts `threads` threads which answer the requests with `handle`
+    fn new(timeout: Duration, threads: usize, handle: fn(&mut HttpRequest) -> HttpResponse) -> Self {
+        let pool = WorkerPool::new("handler", threads, threads, move |(mut request, deadline, sender): HandlerJob| {
+            // A request which waited in the queue until its deadline was already answered with 503
+            if Instant::now() < deadline {
+                let _ = sender.send(handle(&mut request));
+            }
+        });
+        Handlers { timeout, pool }
+    }
+
+    /// A handler which does not finish in time is left running, its request is answered with 503.
+    /// It keeps its thread meanwhile, once all threads and the queue are taken requests get 503 right away.
+    fn dispatch(&self, request: HttpRequest) -> HttpResponse {
+        let id = request.id;
+        let (sender, receiver) = mpsc::channel();
+        if self.pool.try_execute((request, Instant::now() + self.timeout, sender)).is_err() {
+            eprintln!("No handler thread free for request {}", id);
+            return create_http_service_unavailable_response("Server is busy, try again later");
+        }
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => {
+                eprintln!("Request {} did not finish within {:?}", id, self.timeout);
+                create_http_service_unavailable_response("The request took too long to handle")
+            }
+            // Panics are answered by the dispatcher, so this only happens if the thread died otherwise
+            Err(RecvTimeoutError::Disconnected) => {
+                create_http_internal_server_error_response("The server failed to handle the request")
+            }
+        }
+    }
+}
+
+/// Sends the client to the same target on the HTTPS listener
+fn redirect_to_https(request: &HttpRequest, redirect: HttpsRedirect) -> HttpResponse {
+    let host = match request.headers.get("Host").map(host_without_port) {
+        Some(host) if !host.is_empty() => host,
+        _ => return create_http_bad_request_response("Host header is required"),
+    };
+
+    let location = match redirect.port {
+        443 => format!("https://{}{}", host, request.target),
+        port => format!("https://{}:{}{}", host, port, request.target),
+    };
+
+    // 301 lets clients turn a POST into a GET, 308 keeps method and body
+    match request.method {
+        Method::Get | Method::Head => create_http_moved_permanently_response(&location),
+        _ => create_http_permanent_redirect_response(&location),
+    }
+}
+
+/// Strips the port from a Host header, IPv6 literals keep their brackets
+fn host_without_port(host: &str) -> &str {
+    let host = host.trim();
+    if let Some(end) = host.find(']') {
+        return &host[..=end];
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// The start of the access log line with the client address, IPv4 and IPv6 alike.
+/// Taken before the request is handled, a handler which times out keeps the request
+fn access_line(request: &HttpRequest) -> String {
+    let client = match request.peer_addr {
+        Some(peer_addr) => peer_addr.ip().to_string(),
+        None => "-".to_string(),
+    };
+    format!("{} \"{} {} {}\"", client, request.method, request.path, request.version.as_str())
+}
+
+/// HTTP/1.1 connections persist unless the client sends `Connection: close`,
+/// HTTP/1.0 connections only persist with `Connection: keep-alive`
+fn wants_keep_alive(request: &HttpRequest) -> bool {
+    let has_option = |option: &str| request.headers.has_element("Connection", option);
+
+    match request.version {
+        HttpVersion::Http11 | HttpVersion::Http2 => !has_option("close"),
+        HttpVersion::Http10 => has_option("keep-alive"),
+    }
+}
+
+/// Serves requests on a blocking connection until it is closed.
+pub fn handle_http_request<C: Connection>(
+    mut connection: C,
+    limits: &RequestLimits,
+    keep_alive: &KeepAlive,
+    write_timeout: Duration,
+    redirect: Option<HttpsRedirect>,
+) {
+    let mut reader = RequestReader::new(*limits);
+    let mut requests_served = 0;
+
+    // A client which stops reading cannot block the worker, the read timeouts are set by the reader
+    if let Err(error) = connection.set_write_timeout(Some(write_timeout)) {
+        eprintln!("Failed to set the write timeout: {}", error);
+    }
+
+    loop {
+        // Receive the next request from the client and build the reply,
+        // pipelined requests are answered in the order they arrived
+        let result = reader.read_request(&mut connection, keep_alive.idle_timeout);
+        requests_served += 1;
+
+        // The client speaks HTTP/2 from the start, the connection switches for good
+        if let (Err(ReadError::Http2Preface), 1) = (&result, requests_served) {
+            serve_http2(&mut connection, reader.take_buffer(), limits, keep_alive, redirect);
+            break;
+        }
+        // Connections are not kept open while the server drains
+        let allow_keep_alive = requests_served < keep_alive.max_requests && !is_shutdown_requested();
+
+        let reply = match reply_for(result, connection.peer_addr(), keep_alive, allow_keep_alive, redirect) {
+            Some(reply) => reply,
+            None => break,
+        };
+
+        // Send the whole response, write_all retries until everything is sent
+        if let Err(error) = connection.write_all(&reply.to_bytes()) {
+            eprintln!("Failed to send response: {}", error);
+            break;
+        }
+
+        println!("Response sent successfully");
+
+        // The connection now belongs to the new protocol or the stream
+        match reply.upgrade {
+            Some(Upgrade::WebSocket(subscribe)) => {
+                serve_websocket(&mut connection, reader.take_buffer(), subscribe, limits);
+                break;
+            }
+            Some(Upgrade::EventStream(subscribe, last_event_id)) => {
+                serve_event_stream(&mut connection, subscribe, last_event_id);
+                break;
+            }
+            None => {}
+        }
+
+        if !reply.keep_alive {
+            break;
+        }
+    }
+
+    // Close the client socket
+    connection.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Instant;
+    use crate::error::ApiError;
+    use crate::handle_request;
+    use crate::http::headers::HeaderMap;
+    use crate::http::response::StatusCode;
+    use crate::http::router::Router;
+    use crate::transport::std_net::loopback_pair;
+
+    fn ok(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        Ok(HttpResponse::new(StatusCode::Ok).with_body("text/plain", "ok"))
+    }
+
+    fn slow(request: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        thread::sleep(Duration::from_secs(1));
+        ok(request)
+    }
+
+    fn broken(_: &HttpRequest) -> Result<HttpResponse, ApiError> {
+        panic!("the handler is broken")
+    }
+
+    /// Answers with the routes above, like `build_response_from_request` does with the real ones
+    fn handle(request: &mut HttpRequest) -> HttpResponse {
+        static ROUTER: OnceLock<Router> = OnceLock::new();
+        let router = ROUTER.get_or_init(|| {
+            Router::new()
+                .route(Method::Get, "/ok", ok)
+                .route(Method::Get, "/slow", slow)
+                .route(Method::Get, "/broken", broken)
+        });
+        handle_request(router, request)
+    }
+
+    fn get(handlers: &Handlers, path: &str) -> StatusCode {
+        let request = HttpRequest::from_parts("GET", path, HttpVersion::Http11, HeaderMap::new(), Vec::new()).unwrap();
+        handlers.dispatch(request).status
+    }
+
+    #[test]
+    fn a_slow_handler_gets_503_in_time() {
+        let handlers = Handlers::new(Duration::from_millis(100), 1, handle);
+        let started = Instant::now();
+        assert_eq!(get(&handlers, "/slow"), StatusCode::ServiceUnavailable);
+        assert!(started.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn handlers_keep_working_after_a_panic_or_a_timeout() {
+        let handlers = Handlers::new(Duration::from_millis(300), 2, handle);
+        assert_eq!(get(&handlers, "/broken"), StatusCode::InternalServerError);
+        assert_eq!(get(&handlers, "/slow"), StatusCode::ServiceUnavailable);
+        // One thread still runs the slow handler, the other one answers
+        assert_eq!(get(&handlers, "/ok"), StatusCode::Ok);
+    }
+
+    #[test]
+    fn requests_get_503_right_away_once_all_threads_are_busy() {
+        let handlers = Handlers::new(Duration::from_millis(100), 1, handle);
+        // The first takes the thread and the second the place in the queue
+        assert_eq!(get(&handlers, "/slow"), StatusCode::ServiceUnavailable);
+        assert_eq!(get(&handlers, "/slow"), StatusCode::ServiceUnavailable);
+
+        let started = Instant::now();
+        assert_eq!(get(&handlers, "/ok"), StatusCode::ServiceUnavailable);
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // The queued request expired, so the thread is free again once the first handler is done
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(get(&handlers, "/ok"), StatusCode::Ok);
+    }
+
+    /// Serves the connection with short timeouts on a thread of its own
+    fn serve(limits: RequestLimits, keep_alive: KeepAlive) -> (TcpStream, thread::JoinHandle<()>) {
+        let (connection, client) = loopback_pair();
+        let server = thread::spawn(move || {
+            handle_http_request(connection, &limits, &keep_alive, Duration::from_millis(200), None);
+        });
+        (client, server)
+    }
+
+    /// Sends `input`, waits without sending the rest and returns everything the server answered
+    fn answer_to_stalled(input: &[u8], limits: RequestLimits) -> String {
+        let (mut client, server) = serve(limits, KeepAlive::default());
+        client.write_all(input).unwrap();
+
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        server.join().unwrap();
+        answer
+    }
+
+    #[test]
+    fn slow_headers_get_408_and_the_connection_closes() {
+        let limits = RequestLimits {
+            header_timeout: Duration::from_millis(100),
+            ..RequestLimits::default()
+        };
+        let answer = answer_to_stalled(b"GET /ok HTTP/1.1\r\nHost: a", limits);
+        assert!(answer.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", answer);
+    }
+
+    #[test]
+    fn a_slow_body_gets_408_and_the_connection_closes() {
+        let limits = RequestLimits {
+            body_timeout: Duration::from_millis(100),
+            ..RequestLimits::default()
+        };
+        let answer = answer_to_stalled(b"POST /ok HTTP/1.1\r\nContent-Length: 5\r\n\r\nhe", limits);
+        assert!(answer.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", answer);
+    }
+
+    #[test]
+    fn a_client_which_stops_reading_is_dropped_after_the_write_timeout() {
+        let keep_alive = KeepAlive {
+            idle_timeout: Duration::from_secs(30),
+            max_requests: usize::MAX,
+        };
+        let (client, server) = serve(RequestLimits::default(), keep_alive);
+
+        // Pipelines requests but never reads the responses, the writes fail once the buffers are full
+        let mut writer = client.try_clone().unwrap();
+        thread::spawn(move || {
+            let request = b"GET /missing HTTP/1.1\r\nHost: a\r\n\r\n";
+            while writer.write_all(request).is_ok() {}
+        });
+
+        let started = Instant::now();
+        while !server.is_finished() {
+            assert!(started.elapsed() < Duration::from_secs(10), "the server still waits for the client");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server.join().unwrap();
+    }
+
+    fn request(version: HttpVersion, connection: Option<&str>) -> HttpRequest {
+        let mut headers = HeaderMap::new();
+        if let Some(connection) = connection {
+            headers.insert("Connection", connection);
+        }
+        HttpRequest::from_parts("GET", "/", version, headers, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn keep_alive_depends_on_the_version_and_the_connection_header() {
+        assert!(wants_keep_alive(&request(HttpVersion::Http11, None)));
+        assert!(!wants_keep_alive(&request(HttpVersion::Http11, Some("close"))));
+        assert!(wants_keep_alive(&request(HttpVersion::Http11, Some("keep-alive"))));
+        assert!(!wants_keep_alive(&request(HttpVersion::Http10, None)));
+        assert!(wants_keep_alive(&request(HttpVersion::Http10, Some("Keep-Alive"))));
+        assert!(!wants_keep_alive(&request(HttpVersion::Http10, Some("close"))));
+    }
+
+    fn reply_to(head: &str, allow_keep_alive: bool) -> Reply {
+        let raw_request = RawRequest {
+            head: head.as_bytes().to_vec(),
+            body: Vec::new(),
+            trailers: Vec::new(),
+        };
+        reply_for(Ok(raw_request), None, &KeepAlive::default(), allow_keep_alive, None).unwrap()
+    }
+
+    #[test]
+    fn the_reply_announces_whether_the_connection_stays_open() {
+        let reply = reply_to("GET /missing HTTP/1.1\r\nHost: a", true);
+        assert!(reply.keep_alive);
+        assert_eq!(reply.response.headers.get("Connection"), Some("keep-alive"));
+        assert_eq!(reply.response.headers.get("Keep-Alive"), Some("timeout=5, max=100"));
+
+        let reply = reply_to("GET /missing HTTP/1.1\r\nConnection: close", true);
+        assert!(!reply.keep_alive);
+        assert_eq!(reply.response.headers.get("Connection"), Some("close"));
+
+        let reply = reply_to("GET /missing HTTP/1.0", true);
+        assert!(!reply.keep_alive);
+
+        let reply = reply_to("GET /missing HTTP/1.0\r\nConnection: keep-alive", true);
+        assert!(reply.keep_alive);
+    }
+
+    #[test]
+    fn the_connection_closes_at_the_request_limit_or_during_shutdown() {
+        let reply = reply_to("GET /missing HTTP/1.1\r\nConnection: keep-alive", false);
+        assert!(!reply.keep_alive);
+        assert_eq!(reply.response.headers.get("Connection"), Some("close"));
+        assert_eq!(reply.response.headers.get("Keep-Alive"), None);
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let (mut client, server) = serve(RequestLimits::default(), KeepAlive::default());
+        client
+            .write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\n\r\nHEAD /missing HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        server.join().unwrap();
+
+        let responses: Vec<&str> = answer.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", answer);
+        assert!(responses[0].contains("Connection: keep-alive\r\n"), "{}", answer);
+        assert!(responses[1].contains("Connection: close\r\n"), "{}", answer);
+        assert!(responses[1].ends_with("\r\n\r\n"), "{}", answer);
+    }
+}
@@ -0,0 +1,869 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use mio::event::{Event, Source};
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use rustls::{ServerConnection, StreamOwned};
+use crate::config::ServerConfig;
+use crate::http::h2::Http2Session;
+use crate::http::request_reader::{ReadError, RequestLimits, RequestParser, RequestTimer};
+use crate::http::response::Upgrade;
+use crate::http::websocket::Notify;
+use crate::server;
+use crate::server::connection::{reply_for, HttpsRedirect, KeepAlive};
+use crate::server::event_stream::EventStream;
+use crate::server::http2::{answer_requests, push_events};
+use crate::server::shutdown;
+use crate::server::websocket::LiveConnection;
+use crate::transport::std_net::{bind_tcp, canonical_addr};
+use crate::transport::tls::TlsAcceptor;
+use crate::transport::ListenOptions;
+#[cfg(unix)]
+use crate::transport::unix::bind_unix;
+
+/// Token of the shutdown waker, the listening sockets and then the clients use the following ones
+const WAKER: Token = Token(0);
+
+/// How often idle connections are checked for their timeout
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What the event loop should do with a client after an event
+enum ClientState {
+    Open,
+    Closed,
+}
+
+/// A listening socket of the event loop.
+enum ServerListener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<TlsAcceptor>),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl ServerListener {
+    fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            ServerListener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Some(canonical_addr(peer_addr))))
+            }
+            ServerListener::Tls(listener, tls_acceptor) => {
+                let (stream, peer_addr) = listener.accept()?;
+                let session = tls_acceptor.session()?;
+                Ok((Stream::Tls(Box::new(StreamOwned::new(session, stream))), Some(canonical_addr(peer_addr))))
+            }
+            #[cfg(unix)]
+            ServerListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+}
+
+impl Source for ServerListener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ServerListener::Tcp(listener) => listener.register(registry, token, interests),
+            ServerListener::Tls(listener, _) => listener.register(registry, token, interests),
+            #[cfg(unix)]
+            ServerListener::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ServerListener::Tcp(listener) => listener.reregister(registry, token, interests),
+            ServerListener::Tls(listener, _) => listener.reregister(registry, token, interests),
+            #[cfg(unix)]
+            ServerListener::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            ServerListener::Tcp(listener) => listener.deregister(registry),
+            ServerListener::Tls(listener, _) => listener.deregister(registry),
+            #[cfg(unix)]
+            ServerListener::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+/// A non-blocking client socket, TCP or Unix domain.
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn is_tls(&self) -> bool {
+        matches!(self, Stream::Tls(_))
+    }
+
+    /// TLS data which could not be written yet, e.g. handshake messages
+    fn has_pending_output(&self) -> bool {
+        match self {
+            Stream::Tls(stream) => stream.conn.wants_write(),
+            _ => false,
+        }
+    }
+
+    /// Writes pending TLS data until the socket would block
+    fn write_pending(&mut self) -> io::Result<()> {
+        if let Stream::Tls(stream) = self {
+            while stream.conn.wants_write() {
+                stream.conn.write_tls(&mut stream.sock)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Tls(stream) => {
+                // Best effort, the socket does not wait for the close notification
+                stream.conn.send_close_notify();
+                let _ = stream.conn.write_tls(&mut stream.sock);
+                stream.sock.shutdown(Shutdown::Both)
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            Stream::Tls(stream) => stream.sock.register(registry, token, interests),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            Stream::Tls(stream) => stream.sock.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            Stream::Tls(stream) => stream.sock.deregister(registry),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+/// A client connection with its pending input and output.
+struct Client {
+    stream: Stream,
+    token: Token,
+    peer_addr: Option<SocketAddr>,
+    /// Requests are answered with a redirect to HTTPS
+    redirect: Option<HttpsRedirect>,
+    read_buffer: Vec<u8>,
+    /// Keeps the progress of a chunked body in the read buffer
+    parser: RequestParser,
+    /// The connection was closed by the client, only buffered requests are left
+    read_closed: bool,
+    write_buffer: Vec<u8>,
+    written: usize,
+    /// Keep the connection open once the pending response is written
+    keep_alive: bool,
+    requests_served: usize,
+    last_activity: Instant,
+    /// How far the request in the read buffer got, slow requests are answered with 408
+    request_timer: RequestTimer,
+    /// Since when the socket takes none of the pending output
+    write_stalled_since: Option<Instant>,
+    /// The client switched to HTTP/2, all further bytes belong to the session
+    http2: Option<Http2Session>,
+    /// The protocol to switch to once the pending response is written
+    upgrade: Option<Upgrade>,
+    /// The client switched to WebSocket and receives pushed messages
+    websocket: Option<LiveConnection>,
+    /// The response body is a stream of events, nothing else is sent anymore
+    event_stream: Option<EventStream>,
+    /// HTTP/2 streams whose response body is a stream of events
+    http2_event_streams: Vec<(u32, EventStream)>,
+}
+
+/// Runs the event-driven server.
+/// All sockets are non-blocking and driven by one thread, so idle clients cost no thread.
+/// Requests are parsed and dispatched exactly like in the threaded server.
+/// On shutdown it stops accepting and gives in-flight requests the drain timeout to finish.
+pub fn run(config: &ServerConfig) {
+    let limits = config.limits();
+    let keep_alive = config.keep_alive();
+    let drain_timeout = config.drain_timeout();
+    let write_timeout = config.write_timeout();
+    let options = config.listen_options();
+    let redirect = config.https_redirect();
+    let tls_acceptor = server::tls_acceptor(config);
+
+    let mut poll = match Poll::new() {
+        Ok(poll) => poll,
+        Err(error) => panic!("Failed to create the event loop: {}", error),
+    };
+
+    // One listening socket per server address, registered right after the waker token
+    let mut listeners: Vec<Option<ServerListener>> = Vec::new();
+    for &server_addr in &config.listen {
+        listeners.push(Some(ServerListener::Tcp(bind_nonblocking(server_addr, &options))));
+        println!("Server listening on {} (event loop)", server_addr);
+    }
+
+    // HTTPS listeners, the handshake runs on the event loop like any other read
+    if let Some(tls_acceptor) = &tls_acceptor {
+        for &server_addr in &config.tls_listen {
+            let listener = bind_nonblocking(server_addr, &options);
+            listeners.push(Some(ServerListener::Tls(listener, Arc::clone(tls_acceptor))));
+            println!("Server listening on {} (HTTPS, event loop)", server_addr);
+        }
+    }
+
+    // Unix domain socket for a reverse proxy on the same host
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let listener = match bind_unix(path, config.unix_socket_mode).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(UnixListener::from_std(listener))
+        }) {
+            Ok(listener) => listener,
+            Err(error) => panic!("Failed to listen on {}: {}", path.display(), error),
+        };
+
+        listeners.push(Some(ServerListener::Unix(listener)));
+        println!("Server listening on {} (event loop)", path.display());
+    }
+
+    for (index, listener) in listeners.iter_mut().flatten().enumerate() {
+        if let Err(error) = poll.registry().register(listener, Token(WAKER.0 + 1 + index), Interest::READABLE) {
+            panic!("Failed to register the listener: {}", error);
+        }
+    }
+
+    // Wake up the poll on shutdown
+    let waker = match Waker::new(poll.registry(), WAKER) {
+        Ok(waker) => Arc::new(waker),
+        Err(error) => panic!("Failed to create the waker: {}", error),
+    };
+    // WebSocket clients wake up the poll when a message is pushed to them
+    let notify: Notify = {
+        let waker = Arc::clone(&waker);
+        Arc::new(move || {
+            let _ = waker.wake();
+        })
+    };
+    shutdown::on_shutdown(move || {
+        let _ = waker.wake();
+    });
+
+    let mut clients: HashMap<Token, Client> = HashMap::new();
+    let mut next_token = WAKER.0 + 1 + listeners.len();
+    let mut events = Events::with_capacity(1024);
+    let mut drain_deadline: Option<Instant> = None;
+
+    loop {
+        if let Err(error) = poll.poll(&mut events, Some(IDLE_CHECK_INTERVAL)) {
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("Failed to poll for events: {}", error);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                // Shutdown and pushed messages are handled below
+                WAKER => {}
+                token if token.0 <= listeners.len() => {
+                    if let Some(listener) = &listeners[token.0 - 1] {
+                        // Only plaintext TCP is redirected, the Unix socket is local
+                        let redirect = match listener {
+                            ServerListener::Tcp(_) => redirect,
+                            _ => None,
+                        };
+                        accept_clients(listener, redirect, &poll, &mut clients, &mut next_token);
+                    }
+                }
+                token => drive_client(&poll, &mut clients, token, |client| {
+                    client.handle_event(event, &limits, &keep_alive, &poll, &notify)
+                }),
+            }
+        }
+
+        // Push messages and events, WebSocket clients also get their pings and close frames
+        let streaming_clients: Vec<Token> = clients
+            .values()
+            .filter(|client| client.is_streaming())
+            .map(|client| client.token)
+            .collect();
+        for token in streaming_clients {
+            drive_client(&poll, &mut clients, token, |client| client.push(&keep_alive, &poll, &notify));
+        }
+
+        // Stop accepting once a shutdown was requested
+        if shutdown::is_shutdown_requested() && drain_deadline.is_none() {
+            for mut listener in listeners.iter_mut().filter_map(Option::take) {
+                let _ = poll.registry().deregister(&mut listener);
+            }
+            drain_deadline = Some(Instant::now() + drain_timeout);
+
+            // HTTP/2 clients are told not to open more streams
+            let http2_clients: Vec<Token> = clients
+                .values()
+                .filter(|client| client.http2.is_some())
+                .map(|client| client.token)
+                .collect();
+            for token in http2_clients {
+                drive_client(&poll, &mut clients, token, |client| client.serve_http2(&keep_alive, &poll, &notify));
+            }
+        }
+
+        // Answer requests which did not arrive within the header or body timeout
+        let now = Instant::now();
+        let slow: Vec<Token> = clients
+            .values()
+            .filter(|client| client.write_buffer.is_empty())
+            .filter(|client| client.request_timer.deadline(&limits).is_some_and(|deadline| now >= deadline))
+            .map(|client| client.token)
+            .collect();
+        for token in slow {
+            drive_client(&poll, &mut clients, token, |client| client.time_out_request(&keep_alive, &poll));
+        }
+
+        // Close connections which do not read what is sent to them
+        let stalled: Vec<Token> = clients
+            .values()
+            .filter(|client| client.write_stalled_since.is_some_and(|since| now.duration_since(since) >= write_timeout))
+            .map(|client| client.token)
+            .collect();
+        for token in stalled {
+            eprintln!("Client did not read for {:?}, closing the connection", write_timeout);
+            close_client(&poll, &mut clients, token);
+        }
+
+        // Close connections which waited too long for their next request,
+        // while draining every connection without a request in progress.
+        // Partly received requests have their own timeouts, streaming clients are never idle
+        let idle: Vec<Token> = clients
+            .values()
+            .filter(|client| client.write_buffer.is_empty() && !client.is_streaming())
+            .filter(|client| {
+                (drain_deadline.is_some() && !client.has_request_in_progress())
+                    || (now.duration_since(client.last_activity) >= keep_alive.idle_timeout
+                        && !client.request_timer.is_started())
+            })
+            .map(|client| client.token)
+            .collect();
+        for token in idle {
+            close_client(&poll, &mut clients, token);
+        }
+
+        if let Some(deadline) = drain_deadline {
+            if clients.is_empty() {
+                break;
+            }
+            if now >= deadline {
+                eprintln!("Requests still in flight after {:?}, exiting anyway", drain_timeout);
+                break;
+            }
+        }
+    }
+
+    for token in clients.keys().copied().collect::<Vec<_>>() {
+        close_client(&poll, &mut clients, token);
+    }
+
+    // The socket file would make the next start look for a running server
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        if let Err(error) = std::fs::remove_file(path) {
+            eprintln!("Failed to remove socket {}: {}", path.display(), error);
+        }
+    }
+
+    println!("Close socket and cleanup");
+}
+
+fn bind_nonblocking(server_addr: SocketAddr, options: &ListenOptions) -> TcpListener {
+    match bind_tcp(server_addr, options).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(listener))
+    }) {
+        Ok(listener) => listener,
+        Err(error) => panic!("Failed to listen on {}: {}", server_addr, error),
+    }
+}
+
+/// Accepts all pending connections
+fn accept_clients(
+    listener: &ServerListener,
+    redirect: Option<HttpsRedirect>,
+    poll: &Poll,
+    clients: &mut HashMap<Token, Client>,
+    next_token: &mut usize,
+) {
+    loop {
+        let (mut stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+            Err(error) => {
+                eprintln!("Failed to accept connection: {}", error);
+                return;
+            }
+        };
+
+        match peer_addr {
+            Some(peer_addr) => println!("Accepted new connection from {}", peer_addr),
+            None => println!("Accepted new connection"),
+        }
+
+        let token = Token(*next_token);
+        *next_token += 1;
+
+        if let Err(error) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+            eprintln!("Failed to register connection: {}", error);
+            continue;
+        }
+
+        clients.insert(token, Client {
+            stream,
+            token,
+            peer_addr,
+            redirect,
+            read_buffer: Vec::new(),
+            parser: RequestParser::default(),
+            read_closed: false,
+            write_buffer: Vec::new(),
+            written: 0,
+            keep_alive: false,
+            requests_served: 0,
+            last_activity: Instant::now(),
+            request_timer: RequestTimer::default(),
+            write_stalled_since: None,
+            http2: None,
+            upgrade: None,
+            websocket: None,
+            event_stream: None,
+            http2_event_streams: Vec::new(),
+        });
+    }
+}
+
+/// Lets the client handle its part of the loop and closes the connection once it is done.
+/// A panic only closes this connection, the event loop keeps serving the others
+fn drive_client<F>(poll: &Poll, clients: &mut HashMap<Token, Client>, token: Token, action: F)
+where
+    F: FnOnce(&mut Client) -> ClientState,
+{
+    let state = match clients.get_mut(&token) {
+        Some(client) => panic::catch_unwind(AssertUnwindSafe(|| action(client))),
+        None => return,
+    };
+
+    match state {
+        Ok(ClientState::Open) => {}
+        Ok(ClientState::Closed) => close_client(poll, clients, token),
+        Err(payload) => {
+            eprintln!("Connection panicked, closing it: {}", server::panic_message(payload.as_ref()));
+            close_client(poll, clients, token);
+        }
+    }
+}
+
+fn close_client(poll: &Poll, clients: &mut HashMap<Token, Client>, token: Token) {
+    if let Some(mut client) = clients.remove(&token) {
+        let _ = poll.registry().deregister(&mut client.stream);
+        client.stream.shutdown();
+    }
+}
+
+impl Client {
+    fn handle_event(
+        &mut self,
+        event: &Event,
+        limits: &RequestLimits,
+        keep_alive: &KeepAlive,
+        poll: &Poll,
+        notify: &Notify,
+    ) -> ClientState {
+        self.last_activity = Instant::now();
+
+        if self.websocket.is_some() || self.event_stream.is_some() {
+            if event.is_readable() || self.stream.is_tls() {
+                if let Err(error) = self.read_available() {
+                    eprintln!("Failed to read from connection: {}", error);
+                    return ClientState::Closed;
+                }
+            }
+            return self.push(keep_alive, poll, notify);
+        }
+
+        // HTTP/2 reads and writes at the same time, responses never block new streams
+        if self.http2.is_some() {
+            if event.is_readable() || self.stream.is_tls() {
+                if let Err(error) = self.read_available() {
+                    eprintln!("Failed to read from connection: {}", error);
+                    return ClientState::Closed;
+                }
+            }
+            return self.serve_http2(keep_alive, poll, notify);
+        }
+
+        // A response is pending, new requests wait in the buffer until it is written
+        if !self.write_buffer.is_empty() {
+            if !event.is_writable() {
+                return ClientState::Open;
+            }
+            return match self.write_response() {
+                ClientState::Closed if self.keep_alive => self.next_request(limits, keep_alive, poll, notify),
+                state => state,
+            };
+        }
+
+        // Handshake messages which did not fit into the socket yet
+        match self.stream.write_pending() {
+            Err(error) if error.kind() != io::ErrorKind::WouldBlock => {
+                eprintln!("Failed to send to connection: {}", error);
+                return ClientState::Closed;
+            }
+            _ => {}
+        }
+
+        // TLS may have stopped reading to write first, so it always reads on
+        if event.is_readable() || self.stream.is_tls() {
+            if let Err(error) = self.read_available() {
+                eprintln!("Failed to read from connection: {}", error);
+                return ClientState::Closed;
+            }
+        }
+
+        self.next_request(limits, keep_alive, poll, notify)
+    }
+
+    /// Reads everything available without blocking
+    fn read_available(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return Ok(());
+                }
+                Ok(bytes_read) => self.read_buffer.extend_from_slice(&chunk[..bytes_read]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                // TLS clients which close without a close notification
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.read_closed = true;
+                    return Ok(());
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Answers the next buffered request, pipelined requests are answered one after another
+    fn next_request(&mut self, limits: &RequestLimits, keep_alive: &KeepAlive, poll: &Poll, notify: &Notify) -> ClientState {
+        loop {
+            // The handshake response is written, the connection belongs to the new protocol now
+            match self.upgrade.take() {
+                Some(Upgrade::WebSocket(subscribe)) => {
+                    self.websocket = Some(LiveConnection::new(subscribe, Arc::clone(notify), limits));
+                    return self.serve_websocket(poll);
+                }
+                Some(Upgrade::EventStream(subscribe, last_event_id)) => {
+                    self.event_stream = Some(EventStream::new(subscribe, last_event_id, Arc::clone(notify)));
+                    return self.serve_event_stream(poll);
+                }
+                None => {}
+            }
+
+            // Use the same parser as the blocking server
+            let result = match self.parser.parse(&self.read_buffer, limits) {
+                Ok(Some((raw_request, used))) => {
+                    self.read_buffer.drain(..used);
+                    self.request_timer.reset();
+                    Ok(raw_request)
+                }
+                Ok(None) if self.read_closed && self.read_buffer.is_empty() => Err(ReadError::NoRequest),
+                Ok(None) if self.read_closed => Err(ReadError::ConnectionClosed),
+                Ok(None) => {
+                    self.request_timer.update(&self.read_buffer);
+                    return self.wait_for(poll, Interest::READABLE);
+                }
+                // The client speaks HTTP/2 from the start, the connection switches for good
+                Err(ReadError::Http2Preface) if self.requests_served == 0 => {
+                    self.request_timer.reset();
+                    self.http2 = Some(Http2Session::new(*limits, self.peer_addr));
+                    return self.serve_http2(keep_alive, poll, notify);
+                }
+                Err(error) => Err(error),
+            };
+
+            self.requests_served += 1;
+            // Connections are not kept open while the server drains
+            let allow_keep_alive = self.requests_served < keep_alive.max_requests && !shutdown::is_shutdown_requested();
+
+            let reply = match reply_for(result, self.peer_addr, keep_alive, allow_keep_alive, self.redirect) {
+                Some(reply) => reply,
+                None => return ClientState::Closed,
+            };
+
+            self.keep_alive = reply.keep_alive;
+            self.upgrade = reply.upgrade;
+            self.write_buffer = reply.to_bytes();
+            self.written = 0;
+
+            // Try to send right away, the socket is usually writable
+            match self.write_response() {
+                ClientState::Closed if self.keep_alive => continue,
+                ClientState::Open => return self.wait_for(poll, Interest::WRITABLE),
+                state => return state,
+            }
+        }
+    }
+
+    /// Answers a request which was not received in time with 408, the connection closes afterwards
+    fn time_out_request(&mut self, keep_alive: &KeepAlive, poll: &Poll) -> ClientState {
+        self.request_timer.reset();
+        self.read_buffer.clear();
+        self.parser.reset();
+
+        let reply = match reply_for(Err(ReadError::Timeout), self.peer_addr, keep_alive, false, self.redirect) {
+            Some(reply) => reply,
+            None => return ClientState::Closed,
+        };
+        self.keep_alive = false;
+        self.write_buffer = reply.to_bytes();
+        self.written = 0;
+
+        match self.write_response() {
+            ClientState::Open => self.wait_for(poll, Interest::WRITABLE),
+            state => state,
+        }
+    }
+
+    /// Feeds the received bytes to the HTTP/2 session, answers the completed streams
+    /// and writes what the session has to send
+    fn serve_http2(&mut self, keep_alive: &KeepAlive, poll: &Poll, notify: &Notify) -> ClientState {
+        let session = match &mut self.http2 {
+            Some(session) => session,
+            None => return ClientState::Closed,
+        };
+
+        session.receive(&self.read_buffer);
+        self.read_buffer.clear();
+
+        self.requests_served += answer_requests(session, self.redirect, notify, &mut self.http2_event_streams);
+        push_events(session, &mut self.http2_event_streams);
+
+        // Stop accepting streams, the ones already started are still answered
+        if shutdown::is_shutdown_requested() || self.requests_served >= keep_alive.max_requests {
+            session.go_away();
+        }
+
+        let output = session.take_output();
+        let finished = session.is_finished() || self.read_closed;
+        self.send_output(output, finished, poll)
+    }
+
+    /// Feeds the received bytes to the WebSocket connection and writes the pushed messages
+    fn serve_websocket(&mut self, poll: &Poll) -> ClientState {
+        let live = match &mut self.websocket {
+            Some(live) => live,
+            None => return ClientState::Closed,
+        };
+
+        if !self.read_buffer.is_empty() {
+            live.receive(&self.read_buffer);
+            self.read_buffer.clear();
+        }
+
+        let output = live.poll();
+        let finished = live.is_finished() || self.read_closed;
+        self.send_output(output, finished, poll)
+    }
+
+    /// Writes the new events, the client only sends to close the connection
+    fn serve_event_stream(&mut self, poll: &Poll) -> ClientState {
+        let stream = match &mut self.event_stream {
+            Some(stream) => stream,
+            None => return ClientState::Closed,
+        };
+        self.read_buffer.clear();
+
+        let output = stream.poll();
+        let finished = stream.is_finished() || self.read_closed;
+        self.send_output(output, finished, poll)
+    }
+
+    /// Pushes to a client which streams messages or events
+    fn push(&mut self, keep_alive: &KeepAlive, poll: &Poll, notify: &Notify) -> ClientState {
+        if self.websocket.is_some() {
+            self.serve_websocket(poll)
+        } else if self.event_stream.is_some() {
+            self.serve_event_stream(poll)
+        } else {
+            self.serve_http2(keep_alive, poll, notify)
+        }
+    }
+
+    /// The connection stays open for pushed messages or events
+    fn is_streaming(&self) -> bool {
+        self.websocket.is_some() || self.event_stream.is_some() || !self.http2_event_streams.is_empty()
+    }
+
+    /// Writes the output of a session or stream, reading goes on meanwhile.
+    /// With `finished` the connection is closed once everything is written.
+    fn send_output(&mut self, output: Vec<u8>, finished: bool, poll: &Poll) -> ClientState {
+        // Unsent frames stay in front of the new ones
+        self.write_buffer.drain(..self.written);
+        self.written = 0;
+        self.write_buffer.extend(output);
+
+        if !self.write_buffer.is_empty() || self.stream.has_pending_output() {
+            match self.write_buffered() {
+                Ok(true) => {
+                    self.write_buffer.clear();
+                    self.written = 0;
+                }
+                Ok(false) => return self.wait_for(poll, Interest::READABLE | Interest::WRITABLE),
+                Err(error) => {
+                    eprintln!("Failed to send to connection: {}", error);
+                    return ClientState::Closed;
+                }
+            }
+        }
+
+        match finished {
+            true => ClientState::Closed,
+            false => self.wait_for(poll, Interest::READABLE),
+        }
+    }
+
+    /// A request is being received or answered, draining waits for it
+    fn has_request_in_progress(&self) -> bool {
+        match &self.http2 {
+            Some(session) => session.has_open_streams(),
+            None => !self.read_buffer.is_empty(),
+        }
+    }
+
+    fn wait_for(&mut self, poll: &Poll, interest: Interest) -> ClientState {
+        let interest = match self.stream.has_pending_output() {
+            true => interest | Interest::WRITABLE,
+            false => interest,
+        };
+
+        match poll.registry().reregister(&mut self.stream, self.token, interest) {
+            Ok(()) => ClientState::Open,
+            Err(error) => {
+                eprintln!("Failed to register connection: {}", error);
+                ClientState::Closed
+            }
+        }
+    }
+
+    /// Writes as much of the pending response as possible.
+    /// Returns `Closed` once the response is complete, the caller decides if the connection stays open.
+    fn write_response(&mut self) -> ClientState {
+        match self.write_buffered() {
+            Ok(true) => {}
+            Ok(false) => return ClientState::Open,
+            Err(error) => {
+                eprintln!("Failed to send response: {}", error);
+                self.keep_alive = false;
+                return ClientState::Closed;
+            }
+        }
+
+        println!("Response sent successfully");
+        self.write_buffer.clear();
+        self.written = 0;
+
+        ClientState::Closed
+    }
+
+    /// Writes as much of the write buffer as the socket takes, `false` while the socket is full
+    fn write_buffered(&mut self) -> io::Result<bool> {
+        while self.written < self.write_buffer.len() {
+            match self.stream.write(&self.write_buffer[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(bytes_sent) => {
+                    self.written += bytes_sent;
+                    self.write_stalled_since = None;
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    self.write_stalled_since.get_or_insert_with(Instant::now);
+                    return Ok(false);
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        // TLS keeps the encrypted response until the socket takes it
+        match self.stream.write_pending() {
+            Ok(()) => {
+                self.write_stalled_since = None;
+                Ok(true)
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                self.write_stalled_since.get_or_insert_with(Instant::now);
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
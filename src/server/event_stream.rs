@@ -0,0 +1,111 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::http::sse::{self, Subscribe};
+use crate::http::websocket::Notify;
+use crate::server::shutdown::is_shutdown_requested;
+use crate::transport::Connection;
+
+/// A quiet stream gets a comment after this long, proxies often close idle connections after a minute
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often a blocking connection checks for a shutdown while it waits for events
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A response body of server-sent events, shared by HTTP/1 and HTTP/2 in both server modes.
+pub struct EventStream {
+    events: Receiver<String>,
+    last_sent: Instant,
+    closed: bool,
+}
+
+impl EventStream {
+    pub fn new(subscribe: Subscribe, last_event_id: Option<u64>, notify: Notify) -> Self {
+        EventStream {
+            events: subscribe(last_event_id, notify),
+            last_sent: Instant::now(),
+            closed: false,
+        }
+    }
+
+    /// The events which arrived since the last call, or a heartbeat after a quiet period
+    pub fn poll(&mut self) -> Vec<u8> {
+        self.poll_timeout(Duration::ZERO)
+    }
+
+    /// Like `poll`, but waits up to `timeout` for the first event
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        let first = match timeout.is_zero() {
+            true => self.events.try_recv().map_err(|error| error == TryRecvError::Disconnected),
+            false => self.events.recv_timeout(timeout).map_err(|error| error == RecvTimeoutError::Disconnected),
+        };
+        match first {
+            Ok(event) => output.extend_from_slice(event.as_bytes()),
+            Err(disconnected) => self.closed |= disconnected,
+        }
+        if !output.is_empty() {
+            while let Ok(event) = self.events.try_recv() {
+                output.extend_from_slice(event.as_bytes());
+            }
+        }
+
+        if output.is_empty() && self.last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+            output.extend_from_slice(sse::HEARTBEAT.as_bytes());
+        }
+        if !output.is_empty() {
+            self.last_sent = Instant::now();
+        }
+        output
+    }
+
+    /// The stream ends on shutdown, clients reconnect with their last event id
+    pub fn is_finished(&self) -> bool {
+        self.closed || is_shutdown_requested()
+    }
+}
+
+/// Streams events on a blocking HTTP/1 connection until the client goes away.
+/// The response head was already sent.
+pub fn serve_event_stream<C: Connection>(connection: &mut C, subscribe: Subscribe, last_event_id: Option<u64>) {
+    let mut stream = EventStream::new(subscribe, last_event_id, Arc::new(|| {}));
+
+    // The client never sends anything, a failed write tells that it is gone
+    while !stream.is_finished() {
+        let output = stream.poll_timeout(POLL_INTERVAL);
+        if output.is_empty() {
+            continue;
+        }
+        if let Err(error) = connection.write_all(&output).and_then(|()| connection.flush()) {
+            println!("Event stream closed: {}", error);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Two events, then the publisher is gone
+    fn two_events(_last_event_id: Option<u64>, _notify: Notify) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(sse::format_event(1, "first", "a")).unwrap();
+        sender.send(sse::format_event(2, "second", "b")).unwrap();
+        receiver
+    }
+
+    #[test]
+    fn sends_the_queued_events_together() {
+        let mut stream = EventStream::new(two_events, None, Arc::new(|| {}));
+        let output = String::from_utf8(stream.poll()).unwrap();
+        assert_eq!(output, "id: 1\nevent: first\ndata: a\n\nid: 2\nevent: second\ndata: b\n\n");
+        assert!(!stream.is_finished());
+
+        // Nothing more to send and no heartbeat yet, the stream ends with its publisher
+        assert!(stream.poll().is_empty());
+        assert!(stream.is_finished());
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::http::h2::Http2Session;
+use crate::http::request_reader::RequestLimits;
+use crate::http::response::Upgrade;
+use crate::http::websocket::Notify;
+use crate::server::connection::{respond, HttpsRedirect, KeepAlive};
+use crate::server::event_stream::EventStream;
+use crate::server::shutdown::is_shutdown_requested;
+use crate::transport::Connection;
+
+/// How often a blocking connection with event streams looks for new events
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Answers every request the client has completed so far, returns how many were answered.
+/// Event stream responses keep their stream open and are added to `event_streams`.
+pub fn answer_requests(
+    session: &mut Http2Session,
+    redirect: Option<HttpsRedirect>,
+    notify: &Notify,
+    event_streams: &mut Vec<(u32, EventStream)>,
+) -> usize {
+    let mut answered = 0;
+    while let Some((stream_id, result)) = session.next_request() {
+        let response = respond(result, redirect);
+        match response.upgrade {
+            Some(Upgrade::EventStream(subscribe, last_event_id)) => {
+                session.respond_streaming(stream_id, response);
+                event_streams.push((stream_id, EventStream::new(subscribe, last_event_id, Arc::clone(notify))));
+            }
+            _ => session.respond(stream_id, response),
+        }
+        answered += 1;
+    }
+    answered
+}
+
+/// Sends the new events of the streamed responses, forgets the streams the client reset
+/// and ends all of them on shutdown
+pub fn push_events(session: &mut Http2Session, event_streams: &mut Vec<(u32, EventStream)>) {
+    event_streams.retain_mut(|(stream_id, stream)| {
+        let output = stream.poll();
+        let open = session.send_data(*stream_id, &output);
+        if open && stream.is_finished() {
+            session.end_stream(*stream_id);
+            return false;
+        }
+        open
+    });
+}
+
+/// Serves a HTTP/2 connection on a blocking socket until either side ends it.
+/// `received` holds the bytes read before the connection was detected as HTTP/2.
+/// Every stream is answered by the same dispatcher as HTTP/1 requests.
+pub fn serve_http2<C: Connection>(
+    connection: &mut C,
+    received: Vec<u8>,
+    limits: &RequestLimits,
+    keep_alive: &KeepAlive,
+    redirect: Option<HttpsRedirect>,
+) {
+    let mut session = Http2Session::new(*limits, connection.peer_addr());
+    let mut event_streams = Vec::new();
+    let mut requests_served = 0;
+    let mut read_timeout = None;
+    let mut buffer = [0u8; 16 * 1024];
+    // A blocking read cannot be woken up, so event streams are polled
+    let notify: Notify = Arc::new(|| {});
+    session.receive(&received);
+
+    loop {
+        requests_served += answer_requests(&mut session, redirect, &notify, &mut event_streams);
+        push_events(&mut session, &mut event_streams);
+
+        // Stop accepting streams, the ones already started are still answered
+        if is_shutdown_requested() || requests_served >= keep_alive.max_requests {
+            session.go_away();
+        }
+
+        if let Err(error) = connection.write_all(&session.take_output()) {
+            eprintln!("Failed to send HTTP/2 frames: {}", error);
+            return;
+        }
+        if session.is_finished() {
+            return;
+        }
+
+        // Only a connection without event streams can be idle
+        let timeout = match event_streams.is_empty() {
+            true => keep_alive.idle_timeout,
+            false => POLL_INTERVAL,
+        };
+        if read_timeout != Some(timeout) {
+            if let Err(error) = connection.set_read_timeout(Some(timeout)) {
+                eprintln!("Failed to set the read timeout: {}", error);
+                return;
+            }
+            read_timeout = Some(timeout);
+        }
+
+        match connection.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(read) => session.receive(&buffer[..read]),
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if !event_streams.is_empty() {
+                    continue;
+                }
+                // The client was idle for too long, say goodbye before closing
+                session.go_away();
+                let _ = connection.write_all(&session.take_output());
+                return;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => {
+                eprintln!("Failed to read HTTP/2 frames: {}", error);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+pub mod connection;
+pub mod event_loop;
+pub mod event_stream;
+pub mod http2;
+pub mod shutdown;
+pub mod threaded;
+pub mod websocket;
+pub mod worker_pool;
+
+use std::any::Any;
+use std::str::FromStr;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::config::ServerConfig;
+use crate::transport::tls::TlsAcceptor;
+
+/// How the server handles its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerMode {
+    /// Blocking sockets handled by a pool of worker threads
+    Threaded,
+    /// Non-blocking sockets driven by a single event loop
+    EventLoop,
+}
+
+impl FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "threaded" => Ok(ServerMode::Threaded),
+            "event-loop" => Ok(ServerMode::EventLoop),
+            _ => Err(format!("Unknown server mode {}, expected threaded or event-loop", value)),
+        }
+    }
+}
+
+/// Loads the certificates for the HTTPS listeners and reloads them on SIGHUP
+pub fn tls_acceptor(config: &ServerConfig) -> Option<Arc<TlsAcceptor>> {
+    let (default, sni) = config.tls_certificates()?;
+    let acceptor = match TlsAcceptor::new(default, sni) {
+        Ok(acceptor) => Arc::new(acceptor),
+        Err(error) => panic!("Failed to load the TLS certificates: {}", error),
+    };
+
+    let reloaded = Arc::clone(&acceptor);
+    shutdown::on_reload(move || match reloaded.reload() {
+        Ok(()) => println!("TLS certificates reloaded"),
+        Err(error) => eprintln!("Failed to reload the TLS certificates, keeping the old ones: {}", error),
+    });
+
+    Some(acceptor)
+}
+
+/// The message of `panic!`, which is either a `&str` or a formatted `String`
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "unknown panic",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_panic_message_is_read_from_either_payload() {
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&"formatted".to_string()), "formatted");
+        assert_eq!(panic_message(&7), "unknown panic");
+    }
+}
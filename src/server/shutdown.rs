@@ -0,0 +1,107 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+#[cfg(unix)]
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// How often the signal watcher checks for a received signal
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set once a shutdown was requested, the server then drains and exits
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Callbacks which wake up the server loops blocked on their sockets
+static WAKERS: Mutex<Vec<Box<dyn Fn() + Send>>> = Mutex::new(Vec::new());
+
+/// Callbacks which reload settings from disk, e.g. the TLS certificates
+static RELOADERS: Mutex<Vec<Box<dyn Fn() + Send>>> = Mutex::new(Vec::new());
+
+/// Token for the admin shutdown endpoint, the endpoint is disabled without it
+static ADMIN_TOKEN: OnceLock<String> = OnceLock::new();
+
+/// Requests a graceful shutdown, repeated requests are ignored
+pub fn request_shutdown(reason: &str) {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    println!("Shutdown requested by {}, draining connections", reason);
+
+    if let Ok(wakers) = WAKERS.lock() {
+        for wake in wakers.iter() {
+            wake();
+        }
+    }
+}
+
+pub fn is_shutdown_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Registers a callback which is called once a shutdown is requested
+pub fn on_shutdown<F: Fn() + Send + 'static>(wake: F) {
+    // The request may have arrived before the callback was registered
+    if is_shutdown_requested() {
+        wake();
+    }
+
+    if let Ok(mut wakers) = WAKERS.lock() {
+        wakers.push(Box::new(wake));
+    }
+}
+
+/// Registers a callback which is called on SIGHUP
+pub fn on_reload<F: Fn() + Send + 'static>(reload: F) {
+    if let Ok(mut reloaders) = RELOADERS.lock() {
+        reloaders.push(Box::new(reload));
+    }
+}
+
+fn reload() {
+    println!("Reload requested by signal");
+
+    if let Ok(reloaders) = RELOADERS.lock() {
+        for reload in reloaders.iter() {
+            reload();
+        }
+    }
+}
+
+/// Turns SIGINT and SIGTERM (Ctrl+C on Windows) into a graceful shutdown
+/// and SIGHUP into a reload
+pub fn listen_for_signals() -> io::Result<()> {
+    let received = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&received))?;
+    signal_hook::flag::register(SIGTERM, Arc::clone(&received))?;
+
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload_requested))?;
+
+    // Signal handlers may only set the flag, the watcher thread does the rest
+    thread::Builder::new()
+        .name("signal-watcher".to_string())
+        .spawn(move || {
+            while !received.load(Ordering::SeqCst) {
+                if reload_requested.swap(false, Ordering::SeqCst) {
+                    reload();
+                }
+                thread::sleep(SIGNAL_POLL_INTERVAL);
+            }
+            request_shutdown("signal");
+        })?;
+
+    Ok(())
+}
+
+/// Enables the admin shutdown endpoint with the given token
+pub fn set_admin_token(token: String) {
+    let _ = ADMIN_TOKEN.set(token);
+}
+
+pub fn admin_token() -> Option<&'static str> {
+    ADMIN_TOKEN.get().map(|token| token.as_str())
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+use crate::config::ServerConfig;
+use crate::response_builder::create_http_service_unavailable_response;
+use crate::server::connection::handle_http_request;
+use crate::server;
+use crate::server::shutdown;
+use crate::server::worker_pool::WorkerPool;
+use crate::transport::{AnyConnection, Connection, DefaultListener, Listener};
+#[cfg(unix)]
+use crate::transport::unix::UnixSocketListener;
+
+/// Connections of the TCP listeners and the Unix socket share the worker pool
+type ServerConnection = AnyConnection<<DefaultListener as Listener>::Connection>;
+
+/// Runs the blocking server, accepted connections are handled on a worker pool.
+/// Every listen address gets its own accept thread feeding the same pool.
+/// On shutdown it stops accepting and gives in-flight requests the drain timeout to finish.
+pub fn run(config: &ServerConfig) {
+    let limits = config.limits();
+    let keep_alive = config.keep_alive();
+    let write_timeout = config.write_timeout();
+    let drain_timeout = config.drain_timeout();
+    let options = config.listen_options();
+    let redirect = config.https_redirect();
+    let tls_acceptor = server::tls_acceptor(config);
+
+    // Bind the listener of this platform to every server address
+    let listeners: Vec<(SocketAddr, DefaultListener)> = config
+        .listen
+        .iter()
+        .map(|&server_addr| match DefaultListener::listen(server_addr, &options) {
+            Ok(listener) => (server_addr, listener),
+            Err(error) => panic!("Failed to listen on {}: {}", server_addr, error),
+        })
+        .collect();
+
+    // HTTPS listeners, TLS is terminated on the worker threads
+    let tls_listeners: Vec<(SocketAddr, DefaultListener)> = config
+        .tls_listen
+        .iter()
+        .map(|&server_addr| match DefaultListener::listen(server_addr, &options) {
+            Ok(listener) => (server_addr, listener),
+            Err(error) => panic!("Failed to listen on {}: {}", server_addr, error),
+        })
+        .collect();
+
+    // Unix domain socket for a reverse proxy on the same host
+    #[cfg(unix)]
+    let unix_listener = config.unix_socket.as_ref().map(|path| match UnixSocketListener::bind(path, config.unix_socket_mode) {
+        Ok(listener) => (path.clone(), listener),
+        Err(error) => panic!("Failed to listen on {}: {}", path.display(), error),
+    });
+
+    // Wake up the blocking accept loops by connecting to them, so they see the shutdown request
+    let server_addrs: Vec<SocketAddr> = config.listen.iter().chain(&config.tls_listen).copied().collect();
+    #[cfg(unix)]
+    let unix_socket = config.unix_socket.clone();
+    shutdown::on_shutdown(move || {
+        for server_addr in &server_addrs {
+            let _ = TcpStream::connect_timeout(&local_address(*server_addr), Duration::from_secs(1));
+        }
+        #[cfg(unix)]
+        if let Some(path) = &unix_socket {
+            let _ = UnixStream::connect(path);
+        }
+    });
+
+    // Handle accepted connections on the worker threads
+    let pool = WorkerPool::new("worker", config.workers, config.queue_depth, move |connection: ServerConnection| {
+        // Only plaintext TCP is redirected, the Unix socket is local
+        let redirect = match connection {
+            AnyConnection::Tcp(_) => redirect,
+            _ => None,
+        };
+
+        // Handle HTTP Request
+        handle_http_request(connection, &limits, &keep_alive, write_timeout, redirect);
+    });
+
+    thread::scope(|scope| {
+        for (server_addr, listener) in listeners {
+            let pool = &pool;
+            scope.spawn(move || {
+                // Server started successfully
+                println!("Server listening on {} with {} workers", server_addr, config.workers);
+
+                accept_loop(|| listener.accept().map(AnyConnection::Tcp), pool);
+
+                // Stop accepting new connections
+                listener.close();
+            });
+        }
+
+        for (server_addr, listener) in tls_listeners {
+            let pool = &pool;
+            let tls_acceptor = tls_acceptor.as_deref();
+            scope.spawn(move || {
+                println!("Server listening on {} (HTTPS) with {} workers", server_addr, config.workers);
+
+                if let Some(tls_acceptor) = tls_acceptor {
+                    let accept = || {
+                        let connection = tls_acceptor.accept(listener.accept()?)?;
+                        Ok(AnyConnection::Tls(Box::new(connection)))
+                    };
+                    accept_loop(accept, pool);
+                }
+
+                listener.close();
+            });
+        }
+
+        #[cfg(unix)]
+        if let Some((path, listener)) = unix_listener {
+            let pool = &pool;
+            scope.spawn(move || {
+                println!("Server listening on {} with {} workers", path.display(), config.workers);
+
+                accept_loop(|| listener.accept().map(AnyConnection::Unix), pool);
+
+                // Stop accepting and remove the socket file
+                listener.close();
+            });
+        }
+    });
+
+    // Let the workers finish the connections which are already accepted
+    if !pool.shutdown_timeout(drain_timeout) {
+        eprintln!("Requests still in flight after {:?}, exiting anyway", drain_timeout);
+    }
+
+    println!("Close socket and cleanup");
+}
+
+/// Accepts incoming connections until a shutdown is requested
+fn accept_loop<F: Fn() -> io::Result<ServerConnection>>(accept: F, pool: &WorkerPool<ServerConnection>) {
+    loop {
+        let connection = match accept() {
+            Ok(connection) => connection,
+            Err(error) => {
+                if shutdown::is_shutdown_requested() {
+                    break;
+                }
+                eprintln!("Failed to accept connection: {}", error);
+                continue;
+            }
+        };
+
+        // Exit loop
+        if shutdown::is_shutdown_requested() {
+            break;
+        }
+
+        match connection.peer_addr() {
+            Some(peer_addr) => println!("Accepted new connection from {}", peer_addr),
+            None => println!("Accepted new connection"),
+        }
+
+        // Hand the connection to a worker, reject it if all of them are busy
+        if let Err(connection) = pool.try_execute(connection) {
+            reject_connection(connection);
+        }
+    }
+}
+
+/// Address used to connect to the own listener
+fn local_address(server_addr: SocketAddr) -> SocketAddr {
+    match server_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::from((Ipv4Addr::LOCALHOST, server_addr.port())),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::from((Ipv6Addr::LOCALHOST, server_addr.port())),
+        _ => server_addr,
+    }
+}
+
+/// Answers with 503 when the worker queue is full
+fn reject_connection<C: Connection>(mut connection: C) {
+    let response = create_http_service_unavailable_response("Server is busy, try again later")
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close");
+
+    if let Err(error) = connection.write_all(&response.to_bytes()) {
+        eprintln!("Failed to send response: {}", error);
+    }
+
+    connection.close();
+}
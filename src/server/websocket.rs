@@ -0,0 +1,126 @@
+use std::io;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::http::request_reader::RequestLimits;
+use crate::http::websocket::{CloseCode, Message, Notify, Subscribe, WebSocketSession};
+use crate::server::shutdown::is_shutdown_requested;
+use crate::transport::Connection;
+
+/// Quiet clients are pinged, which also keeps proxies from closing the connection
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A client which sent nothing for this long, not even a pong, is gone
+const DEAD_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// How long the client has to answer the close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a blocking connection looks for new messages, its read cannot be woken up
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A WebSocket connection together with the messages it pushes.
+/// Shared by the blocking and the event-driven server, neither does I/O here.
+pub struct LiveConnection {
+    session: WebSocketSession,
+    messages: Receiver<String>,
+    last_received: Instant,
+    last_ping: Instant,
+    closing_since: Option<Instant>,
+}
+
+impl LiveConnection {
+    pub fn new(subscribe: Subscribe, notify: Notify, limits: &RequestLimits) -> Self {
+        LiveConnection {
+            session: WebSocketSession::new(limits.max_body_size),
+            messages: subscribe(notify),
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+            closing_since: None,
+        }
+    }
+
+    pub fn receive(&mut self, data: &[u8]) {
+        self.last_received = Instant::now();
+        self.session.receive(data);
+
+        // The channel only pushes, messages from the client are ignored
+        while let Some(message) = self.session.next_message() {
+            let length = match message {
+                Message::Text(text) => text.len(),
+                Message::Binary(data) => data.len(),
+            };
+            println!("Ignoring WebSocket message of {} bytes", length);
+        }
+    }
+
+    /// Queues the pending messages, pings and close frames, returns what should be written
+    pub fn poll(&mut self) -> Vec<u8> {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.session.send_text(&message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.session.close(CloseCode::GoingAway);
+                    break;
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if is_shutdown_requested() {
+            self.session.close(CloseCode::GoingAway);
+        } else if now.duration_since(self.last_received) >= PING_INTERVAL
+            && now.duration_since(self.last_ping) >= PING_INTERVAL
+        {
+            self.session.ping();
+            self.last_ping = now;
+        }
+
+        if self.session.is_closing() && self.closing_since.is_none() {
+            self.closing_since = Some(now);
+        }
+
+        self.session.take_output()
+    }
+
+    /// The connection can be closed once the last output is written
+    pub fn is_finished(&self) -> bool {
+        let close_timed_out = self.closing_since.is_some_and(|since| since.elapsed() >= CLOSE_TIMEOUT);
+        self.session.is_finished() || close_timed_out || self.last_received.elapsed() >= DEAD_TIMEOUT
+    }
+}
+
+/// Serves a WebSocket connection on a blocking socket until it is closed.
+/// `received` holds the bytes which arrived right after the handshake.
+pub fn serve_websocket<C: Connection>(connection: &mut C, received: Vec<u8>, subscribe: Subscribe, limits: &RequestLimits) {
+    let mut live = LiveConnection::new(subscribe, Arc::new(|| {}), limits);
+    let mut buffer = [0u8; 4096];
+    live.receive(&received);
+
+    if let Err(error) = connection.set_read_timeout(Some(POLL_INTERVAL)) {
+        eprintln!("Failed to set the read timeout: {}", error);
+        return;
+    }
+
+    loop {
+        if let Err(error) = connection.write_all(&live.poll()) {
+            eprintln!("Failed to send WebSocket frames: {}", error);
+            return;
+        }
+        if live.is_finished() {
+            return;
+        }
+
+        match connection.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(read) => live.receive(&buffer[..read]),
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => {
+                eprintln!("Failed to read WebSocket frames: {}", error);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A fixed number of worker threads fed by a bounded queue.
+/// A panicking job does not take its worker down, so the pool keeps its size.
+pub struct WorkerPool<T: Send + 'static> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Starts `size` workers which run `handler` for every queued job, the threads are named after `name`.
+    /// At most `queue_depth` jobs wait for a free worker.
+    pub fn new<F>(name: &str, size: usize, queue_depth: usize, handler: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        let workers = (0..size.max(1))
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let handler = Arc::clone(&handler);
+                thread::Builder::new()
+                    .name(format!("{}-{}", name, id))
+                    .spawn(move || run_worker(&receiver, &*handler))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues a job, the job is handed back if the queue is full
+    pub fn try_execute(&self, job: T) -> Result<(), T> {
+        let sender = self.sender.as_ref().expect("Worker pool is shut down");
+        match sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Err(job),
+        }
+    }
+
+    /// Waits until all queued jobs are done and stops the workers.
+    /// Returns false if the workers did not finish within the timeout, they are left running then.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> bool {
+        // Closing the queue lets the workers exit once it is empty
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + timeout;
+        while self.workers.iter().any(|worker| !worker.is_finished()) {
+            if Instant::now() >= deadline {
+                // Dropping the handles detaches the remaining workers
+                self.workers.clear();
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        self.stop();
+        true
+    }
+
+    fn stop(&mut self) {
+        // Closing the queue lets the workers exit once it is empty
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                eprintln!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_worker<T, F: Fn(T)>(receiver: &Mutex<Receiver<T>>, handler: &F) {
+    loop {
+        // Only hold the lock while waiting for the next job
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+
+        let job = match job {
+            Ok(job) => job,
+            // The queue was closed
+            Err(_) => return,
+        };
+
+        // The panic hook already reported the panic, the worker goes on with the next job
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+            let name = thread::current().name().unwrap_or("worker").to_string();
+            eprintln!("Job on {} panicked, the worker keeps running", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panicking_jobs_keep_the_workers_running() {
+        let (sender, receiver) = mpsc::channel();
+        let pool = WorkerPool::new("test", 2, 16, move |job: u32| {
+            if job.is_multiple_of(2) {
+                panic!("job {} failed", job);
+            }
+            sender.send(job).unwrap();
+        });
+
+        // More panics than workers, every worker has to survive several of them
+        for job in 0..10 {
+            while pool.try_execute(job).is_err() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+
+        let mut done: Vec<u32> = receiver.try_iter().collect();
+        done.sort();
+        assert_eq!(done, vec![1, 3, 5, 7, 9]);
+    }
+}
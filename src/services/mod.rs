@@ -1 +1,2 @@
+pub mod recipe_events;
 pub mod recipe_service;
\ No newline at end of file
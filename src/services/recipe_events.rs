@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use serde::Serialize;
+use crate::dto::recipe_dto::RecipeDto;
+
+/// Events kept for clients which reconnect and ask for what they missed
+const EVENT_LOG_SIZE: usize = 1000;
+
+/// A change of the recipes, pushed to the clients watching them live.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum RecipeEvent {
+    #[serde(rename = "recipe_added")]
+    Added { recipe: RecipeDto },
+    #[serde(rename = "recipe_updated")]
+    Updated { recipe: RecipeDto },
+    #[serde(rename = "recipe_deleted")]
+    Deleted { id: i32 },
+}
+
+impl RecipeEvent {
+    /// The name of the event, the same as the `type` field of its JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecipeEvent::Added { .. } => "recipe_added",
+            RecipeEvent::Updated { .. } => "recipe_updated",
+            RecipeEvent::Deleted { .. } => "recipe_deleted",
+        }
+    }
+
+    /// The event as JSON, the `type` field tells the events apart
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "".to_string())
+    }
+}
+
+/// Delivers an event with its id to one subscriber, returns false once the subscriber is gone
+type Subscriber = Box<dyn Fn(u64, &RecipeEvent) -> bool + Send>;
+
+/// The latest events and everyone who waits for the next ones.
+struct EventLog {
+    next_id: u64,
+    events: VecDeque<(u64, RecipeEvent)>,
+    subscribers: Vec<Subscriber>,
+}
+
+static EVENT_LOG: Mutex<EventLog> = Mutex::new(EventLog {
+    next_id: 1,
+    events: VecDeque::new(),
+    subscribers: Vec::new(),
+});
+
+/// Subscribes to all following events, converted with `map`.
+/// `notify` is called after every delivered event, the subscription ends when the receiver is dropped.
+pub fn subscribe<T, N>(map: fn(u64, &RecipeEvent) -> T, notify: N) -> Receiver<T>
+where
+    T: Send + 'static,
+    N: Fn() + Send + 'static,
+{
+    subscribe_after(None, map, notify)
+}
+
+/// Like `subscribe`, but first replays the logged events which came after `last_event_id`.
+/// Events which already left the log are lost, an id the log does not know yet,
+/// e.g. from before a restart, replays the whole log.
+pub fn subscribe_after<T, N>(last_event_id: Option<u64>, map: fn(u64, &RecipeEvent) -> T, notify: N) -> Receiver<T>
+where
+    T: Send + 'static,
+    N: Fn() + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+
+    // Replay and registration happen under one lock, so no event is missed or sent twice
+    let mut log = event_log();
+    if let Some(last_event_id) = last_event_id {
+        let first_id = if last_event_id >= log.next_id { 0 } else { last_event_id + 1 };
+        let missed: Vec<T> = log
+            .events
+            .iter()
+            .filter(|(id, _)| *id >= first_id)
+            .map(|(id, event)| map(*id, event))
+            .collect();
+        if !missed.is_empty() {
+            for event in missed {
+                let _ = sender.send(event);
+            }
+            notify();
+        }
+    }
+
+    log.subscribers.push(Box::new(move |id, event| {
+        let delivered = sender.send(map(id, event)).is_ok();
+        if delivered {
+            notify();
+        }
+        delivered
+    }));
+    receiver
+}
+
+/// Logs an event, sends it to every subscriber and forgets the ones which are gone
+pub fn publish(event: RecipeEvent) {
+    let mut log = event_log();
+    let id = log.next_id;
+    log.next_id += 1;
+
+    log.subscribers.retain(|subscriber| subscriber(id, &event));
+
+    log.events.push_back((id, event));
+    if log.events.len() > EVENT_LOG_SIZE {
+        log.events.pop_front();
+    }
+}
+
+/// Locks the event log. A subscriber which panicked while it was locked poisons the lock,
+/// the log itself stays usable, so events keep flowing
+fn event_log() -> MutexGuard<'static, EventLog> {
+    EVENT_LOG.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// The log is shared, tests which publish take turns so none pushes the events of another out
+    static PUBLISHING: Mutex<()> = Mutex::new(());
+
+    /// The id of a deleted recipe, the tests tell their events apart by it
+    fn deleted_id(id: u64, event: &RecipeEvent) -> (u64, i32) {
+        match event {
+            RecipeEvent::Deleted { id: recipe_id } => (id, *recipe_id),
+            _ => (id, 0),
+        }
+    }
+
+    #[test]
+    fn events_are_tagged_json() {
+        let event = RecipeEvent::Deleted { id: 3 };
+        assert_eq!(event.name(), "recipe_deleted");
+        assert_eq!(event.to_json(), r#"{"type":"recipe_deleted","id":3}"#);
+    }
+
+    #[test]
+    fn subscribers_get_new_events_and_replay_the_missed_ones() {
+        let _turn = PUBLISHING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let live = subscribe(deleted_id, || {});
+        for recipe_id in [-101, -102, -103] {
+            publish(RecipeEvent::Deleted { id: recipe_id });
+        }
+
+        let received: Vec<(u64, i32)> = live.try_iter().collect();
+        assert_eq!(received.iter().map(|(_, recipe_id)| *recipe_id).collect::<Vec<_>>(), [-101, -102, -103]);
+        assert!(received.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // A client which saw the first event gets the other two first
+        let resumed = subscribe_after(Some(received[0].0), deleted_id, || {});
+        let replayed: Vec<i32> = resumed.try_iter().map(|(_, recipe_id)| recipe_id).collect();
+        assert_eq!(replayed, [-102, -103]);
+
+        // An id from before a restart replays the whole log, which is bounded
+        let unknown = subscribe_after(Some(u64::MAX - 1), deleted_id, || {});
+        let log: Vec<(u64, i32)> = unknown.try_iter().collect();
+        assert!(log.len() <= EVENT_LOG_SIZE);
+        assert!(log.iter().any(|(_, recipe_id)| *recipe_id == -101));
+    }
+
+    #[test]
+    fn the_log_forgets_the_oldest_events() {
+        let _turn = PUBLISHING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let live = subscribe(deleted_id, || {});
+        for _ in 0..=EVENT_LOG_SIZE {
+            publish(RecipeEvent::Deleted { id: -200 });
+        }
+        let ids: Vec<u64> = live.try_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids.len(), EVENT_LOG_SIZE + 1);
+
+        // The first of them left the log, a client which resumes after it misses nothing else
+        let resumed = subscribe_after(Some(ids[0]), deleted_id, || {});
+        let replayed = resumed.try_iter().count();
+        assert_eq!(replayed, EVENT_LOG_SIZE);
+    }
+
+    #[test]
+    fn dropped_receivers_are_unsubscribed() {
+        let _turn = PUBLISHING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let notified = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&notified);
+        drop(subscribe(deleted_id, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        publish(RecipeEvent::Deleted { id: -300 });
+        assert_eq!(notified.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn a_panicking_subscriber_does_not_stop_the_events() {
+        let _turn = PUBLISHING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let panicked = Arc::new(AtomicBool::new(false));
+        let first = Arc::clone(&panicked);
+        let _broken = subscribe(deleted_id, move || {
+            if !first.swap(true, Ordering::SeqCst) {
+                panic!("the subscriber is broken");
+            }
+        });
+        assert!(panic::catch_unwind(|| publish(RecipeEvent::Deleted { id: -400 })).is_err());
+
+        let live = subscribe(deleted_id, || {});
+        publish(RecipeEvent::Deleted { id: -401 });
+        let received: Vec<i32> = live.try_iter().map(|(_, recipe_id)| recipe_id).collect();
+        assert_eq!(received, [-401]);
+    }
+}
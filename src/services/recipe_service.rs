@@ -1,10 +1,12 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use lazy_static::lazy_static;
 use crate::models::recipe::Recipe;
 use crate::dto::recipe_dto::RecipeDto;
-use crate::assembler::recipe_assembler::to_recipe_dto;
+use crate::assembler::recipe_assembler::{from_recipe_dto, to_recipe_dto};
+use crate::error::ApiError;
 use crate::models::user::User;
+use crate::services::recipe_events::{self, RecipeEvent};
 
 // Mock database setup
 lazy_static! {
@@ -27,30 +29,27 @@ lazy_static! {
 }
 
 /// Get recipe by id
-pub fn get_recipe_by_id(id: i32) -> Option<RecipeDto> {
+pub fn get_recipe_by_id(id: i32) -> Result<RecipeDto, ApiError> {
     // Borrow read-only
-    let db = MOCK_DATABASE.read().unwrap();
+    let db = read_database();
 
     // Read the recipe
-    let recipe = db.get(&id).cloned();
+    let recipe = db.get(&id).ok_or_else(|| not_found(id))?;
 
-    match recipe {
-        Some(recipe) => Some(to_recipe_dto(&recipe)),
-        None => None,
-    }
+    Ok(to_recipe_dto(recipe))
 }
 
 /// Get all recipes
 pub fn get_all_recipes() -> Option<Vec<RecipeDto>> {
     // Borrow read-only
-    let db = MOCK_DATABASE.read().unwrap();
+    let db = read_database();
 
     // Check if there are any recipes in the database
     if db.is_empty() {
         None
     } else {
         // Map all Recipe entries to RecipeDto
-        let recipe_list: Vec<RecipeDto> = db.values().map(|recipe| to_recipe_dto(recipe)).collect();
+        let recipe_list: Vec<RecipeDto> = db.values().map(to_recipe_dto).collect();
         Some(recipe_list)
     }
 }
@@ -58,40 +57,127 @@ pub fn get_all_recipes() -> Option<Vec<RecipeDto>> {
 /// Add a new recipe
 pub fn add_recipe(recipe: Recipe) -> bool {
     // Borrow write access
-    let mut db = MOCK_DATABASE.write().unwrap();
+    let mut db = write_database();
 
     // Insert the recipe
-    db.insert(recipe.id, recipe).is_none()
+    let dto = to_recipe_dto(&recipe);
+    let added = db.insert(recipe.id, recipe).is_none();
+
+    // Published under the lock, so live clients see the changes in the order they happened.
+    // A recipe with the same id was replaced, which is an update for them
+    let event = match added {
+        true => RecipeEvent::Added { recipe: dto },
+        false => RecipeEvent::Updated { recipe: dto },
+    };
+    recipe_events::publish(event);
+
+    added
+}
+
+/// Add a new recipe, a recipe with the same id is a conflict
+pub fn create_recipe(recipe: Recipe) -> Result<RecipeDto, ApiError> {
+    // Borrow write access
+    let mut db = write_database();
+
+    if db.contains_key(&recipe.id) {
+        return Err(ApiError::Conflict(format!("Recipe {} already exists", recipe.id)));
+    }
+
+    let dto = to_recipe_dto(&recipe);
+    db.insert(recipe.id, recipe);
+    recipe_events::publish(RecipeEvent::Added { recipe: dto.clone() });
+
+    Ok(dto)
+}
+
+/// Change an existing recipe.
+/// `change` runs under the write lock, so no other request changes the recipe in between
+pub fn modify_recipe<F>(id: i32, change: F) -> Result<RecipeDto, ApiError>
+where
+    F: FnOnce(RecipeDto) -> Result<RecipeDto, ApiError>,
+{
+    // Borrow write access
+    let mut db = write_database();
+
+    let current = to_recipe_dto(db.get(&id).ok_or_else(|| not_found(id))?);
+    let dto = change(current)?;
+
+    db.insert(id, from_recipe_dto(&dto));
+    recipe_events::publish(RecipeEvent::Updated { recipe: dto.clone() });
+
+    Ok(dto)
 }
 
 /// Update an existing recipe, if not found, add the recipe instead
 pub fn update_recipe(updated_recipe: Recipe) -> RecipeDto {
-    // Borrow read access first to check existence
-    let exists = {
-        let db = MOCK_DATABASE.read().unwrap();
-        db.contains_key(&updated_recipe.id)
-    };
+    // Borrow write access, checking and updating under one lock keeps concurrent requests consistent
+    let mut db = write_database();
 
-    if exists {
-        // Update existing recipe
-        let mut db = MOCK_DATABASE.write().unwrap();
-        if let Some(existing) = db.get_mut(&updated_recipe.id) {
-            *existing = updated_recipe.clone();
-            return to_recipe_dto(existing);
-        }
-    } else {
-        // Add new recipe
-        add_recipe(updated_recipe.clone());
-    }
+    let dto = to_recipe_dto(&updated_recipe);
 
-    to_recipe_dto(&updated_recipe)
+    // Update the existing recipe or add it as a new one
+    let event = match db.insert(updated_recipe.id, updated_recipe) {
+        Some(_) => RecipeEvent::Updated { recipe: dto.clone() },
+        None => RecipeEvent::Added { recipe: dto.clone() },
+    };
+    recipe_events::publish(event);
+
+    dto
 }
 
 /// Delete a recipe by id
-pub fn delete_recipe(id: i32) -> bool {
+pub fn delete_recipe(id: i32) -> Result<(), ApiError> {
     // Borrow write access
-    let mut db = MOCK_DATABASE.write().unwrap();
+    let mut db = write_database();
 
     // Remove and check if it was found and removed
-    db.remove(&id).is_some()
-}
\ No newline at end of file
+    db.remove(&id).ok_or_else(|| not_found(id))?;
+    recipe_events::publish(RecipeEvent::Deleted { id });
+    Ok(())
+}
+
+/// Borrows the database read-only, see `write_database` for a poisoned lock
+fn read_database() -> RwLockReadGuard<'static, HashMap<i32, Recipe>> {
+    MOCK_DATABASE.read().unwrap_or_else(|poisoned| {
+        recover_database();
+        poisoned.into_inner()
+    })
+}
+
+/// Borrows write access. A request which panicked while holding it poisons the lock,
+/// but every change is a single insert or remove, so the recipes are still consistent
+fn write_database() -> RwLockWriteGuard<'static, HashMap<i32, Recipe>> {
+    MOCK_DATABASE.write().unwrap_or_else(|poisoned| {
+        recover_database();
+        poisoned.into_inner()
+    })
+}
+
+/// Clears the poison, so only the first request after the panic reports it
+fn recover_database() {
+    eprintln!("The recipe database lock was poisoned by a panic, recovering");
+    MOCK_DATABASE.clear_poison();
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::NotFound(format!("Recipe {} not found", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn a_panic_while_writing_does_not_lock_out_the_next_requests() {
+        let result = thread::spawn(|| {
+            let _db = write_database();
+            panic!("the request failed while changing the recipes");
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert!(get_recipe_by_id(1).is_ok());
+        assert!(!MOCK_DATABASE.is_poisoned());
+    }
+}
@@ -0,0 +1,173 @@
+pub mod std_net;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+#[cfg(all(windows, feature = "winsock"))]
+pub mod winsock;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Socket options applied when a listener is created.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenOptions {
+    /// Length of the queue of pending connections
+    pub backlog: i32,
+    /// Only accept IPv6 on IPv6 addresses, otherwise `[::]` also accepts IPv4 clients
+    pub ipv6_only: bool,
+}
+
+/// Permission bits of a socket file, written in octal like `chmod` (e.g. "660").
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let digits = value.strip_prefix("0o").unwrap_or(value);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(FileMode(mode)),
+            _ => Err(format!("{} is not an octal file mode like 660", value)),
+        }
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03o}", self.0)
+    }
+}
+
+// Kept as a string in the config file, a TOML integer would be read as decimal
+impl Serialize for FileMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A listening socket which hands out client connections.
+pub trait Listener: Sized {
+    type Connection: Connection;
+
+    /// Binds to the address and starts listening for incoming connections.
+    fn listen(addr: SocketAddr, options: &ListenOptions) -> io::Result<Self>;
+
+    /// Blocks until a client connects.
+    fn accept(&self) -> io::Result<Self::Connection>;
+
+    /// Stops listening and releases the socket.
+    fn close(self);
+}
+
+/// A connected client socket.
+/// Reading and writing go through `std::io::Read` and `std::io::Write`.
+pub trait Connection: Read + Write + Send + 'static {
+    /// The address of the connected client, if known.
+    /// IPv4 clients of a dual-stack listener are reported with their IPv4 address.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// Sets how long a read may block, `None` blocks forever.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Sets how long a write may block, `None` blocks forever.
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes the client socket.
+    fn close(self);
+}
+
+/// The listener used by the server on this platform.
+#[cfg(all(windows, feature = "winsock"))]
+pub type DefaultListener = winsock::WinSockListener;
+
+/// The listener used by the server on this platform.
+#[cfg(not(all(windows, feature = "winsock")))]
+pub type DefaultListener = std_net::StdListener;
+
+/// A connection from any of the listeners, so TCP, TLS and Unix socket clients share one worker pool.
+pub enum AnyConnection<C: Connection> {
+    Tcp(C),
+    Tls(Box<tls::TlsConnection<C>>),
+    #[cfg(unix)]
+    Unix(unix::UnixConnection),
+}
+
+impl<C: Connection> Read for AnyConnection<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            AnyConnection::Tcp(connection) => connection.read(buf),
+            AnyConnection::Tls(connection) => connection.read(buf),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.read(buf),
+        }
+    }
+}
+
+impl<C: Connection> Write for AnyConnection<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AnyConnection::Tcp(connection) => connection.write(buf),
+            AnyConnection::Tls(connection) => connection.write(buf),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AnyConnection::Tcp(connection) => connection.flush(),
+            AnyConnection::Tls(connection) => connection.flush(),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.flush(),
+        }
+    }
+}
+
+impl<C: Connection> Connection for AnyConnection<C> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            AnyConnection::Tcp(connection) => connection.peer_addr(),
+            AnyConnection::Tls(connection) => connection.peer_addr(),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.peer_addr(),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            AnyConnection::Tcp(connection) => connection.set_read_timeout(timeout),
+            AnyConnection::Tls(connection) => connection.set_read_timeout(timeout),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            AnyConnection::Tcp(connection) => connection.set_write_timeout(timeout),
+            AnyConnection::Tls(connection) => connection.set_write_timeout(timeout),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.set_write_timeout(timeout),
+        }
+    }
+
+    fn close(self) {
+        match self {
+            AnyConnection::Tcp(connection) => connection.close(),
+            AnyConnection::Tls(connection) => (*connection).close(),
+            #[cfg(unix)]
+            AnyConnection::Unix(connection) => connection.close(),
+        }
+    }
+}
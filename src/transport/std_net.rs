@@ -0,0 +1,110 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::transport::{Connection, ListenOptions, Listener};
+
+/// Binds a TCP listener with the backlog and IPv6 options, which `std::net` alone does not expose
+pub fn bind_tcp(addr: SocketAddr, options: &ListenOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Dual-stack unless restricted, the OS default differs between platforms
+    if addr.is_ipv6() {
+        socket.set_only_v6(options.ipv6_only)?;
+    }
+
+    // Allow restarting while old connections are in TIME_WAIT,
+    // on Windows the option would allow stealing the port instead
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog)?;
+    Ok(socket.into())
+}
+
+/// Reports IPv4 clients of a dual-stack socket with their IPv4 address instead of ::ffff:a.b.c.d
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Listener based on `std::net`, works on every platform.
+pub struct StdListener {
+    inner: TcpListener,
+}
+
+impl Listener for StdListener {
+    type Connection = StdConnection;
+
+    fn listen(addr: SocketAddr, options: &ListenOptions) -> io::Result<Self> {
+        let inner = bind_tcp(addr, options)?;
+        Ok(StdListener { inner })
+    }
+
+    fn accept(&self) -> io::Result<StdConnection> {
+        let (stream, peer_addr) = self.inner.accept()?;
+        Ok(StdConnection {
+            stream,
+            peer_addr: canonical_addr(peer_addr),
+        })
+    }
+
+    fn close(self) {
+        // The socket is closed when the listener is dropped
+        drop(self.inner);
+    }
+}
+
+/// Client connection accepted by a `StdListener`.
+pub struct StdConnection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+}
+
+impl Read for StdConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for StdConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Connection for StdConnection {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer_addr)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn close(self) {
+        // Ignore the error, the peer may already be gone
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// A server connection and its client on the loopback interface, for the tests of the servers.
+/// The socket buffers are small, so a client which stops reading soon blocks the writes
+#[cfg(test)]
+pub fn loopback_pair() -> (StdConnection, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    socket2::SockRef::from(&client).set_recv_buffer_size(4096).unwrap();
+
+    let (stream, peer_addr) = listener.accept().unwrap();
+    socket2::SockRef::from(&stream).set_send_buffer_size(4096).unwrap();
+    (StdConnection { stream, peer_addr }, client)
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde::{Deserialize, Serialize};
+use crate::transport::Connection;
+
+/// A PEM certificate chain and private key on disk.
+/// `server_names` selects the certificate by SNI, `*.example.com` matches one subdomain level.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateFiles {
+    #[serde(default)]
+    pub server_names: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The loaded certificates, replaced as a whole on reload.
+#[derive(Debug)]
+struct Certificates {
+    /// Served to clients without SNI or with an unknown name
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Picks the certificate for the server name the client asked for.
+#[derive(Debug)]
+struct CertificateResolver {
+    default: CertificateFiles,
+    sni: Vec<CertificateFiles>,
+    current: RwLock<Arc<Certificates>>,
+}
+
+impl CertificateResolver {
+    fn load(default: &CertificateFiles, sni: &[CertificateFiles], provider: &CryptoProvider) -> io::Result<Certificates> {
+        let mut by_name = HashMap::new();
+        for files in sni {
+            let certified_key = Arc::new(load_certified_key(files, provider)?);
+            for name in &files.server_names {
+                by_name.insert(name.to_ascii_lowercase(), Arc::clone(&certified_key));
+            }
+        }
+
+        Ok(Certificates {
+            default: Arc::new(load_certified_key(default, provider)?),
+            by_name,
+        })
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = match self.current.read() {
+            Ok(current) => Arc::clone(&current),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        };
+
+        // Exact name first, then a wildcard for the parent domain
+        let certified_key = client_hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            certificates.by_name.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                certificates.by_name.get(&format!("*.{}", parent))
+            })
+        });
+
+        Some(Arc::clone(certified_key.unwrap_or(&certificates.default)))
+    }
+}
+
+/// Reads a certificate chain and its private key from PEM files
+fn load_certified_key(files: &CertificateFiles, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    let cert_chain = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| pem_error(&files.cert, error))?;
+    if cert_chain.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", files.cert.display()),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&files.key).map_err(|error| pem_error(&files.key, error))?;
+
+    // Also checks that the key belongs to the certificate
+    CertifiedKey::from_der(cert_chain, key, provider).map_err(|error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid certificate {}: {}", files.cert.display(), error),
+        )
+    })
+}
+
+fn pem_error(path: &Path, error: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read {}: {}", path.display(), error))
+}
+
+/// Terminates TLS for accepted connections.
+/// The certificates can be reloaded while the server runs, new handshakes use the new ones.
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    resolver: Arc<CertificateResolver>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TlsAcceptor {
+    /// Loads the default certificate and the certificates selected by SNI
+    pub fn new(default: CertificateFiles, sni: Vec<CertificateFiles>) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let certificates = CertificateResolver::load(&default, &sni, &provider)?;
+        let resolver = Arc::new(CertificateResolver {
+            default,
+            sni,
+            current: RwLock::new(Arc::new(certificates)),
+        });
+
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|error| io::Error::other(error.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+        // HTTP/2 is preferred, HTTP/1.1 stays for older clients
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+            resolver,
+            provider,
+        })
+    }
+
+    /// Loads the certificate files again, the old certificates stay in use if that fails
+    pub fn reload(&self) -> io::Result<()> {
+        let certificates = CertificateResolver::load(&self.resolver.default, &self.resolver.sni, &self.provider)?;
+        match self.resolver.current.write() {
+            Ok(mut current) => *current = Arc::new(certificates),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(certificates),
+        }
+        Ok(())
+    }
+
+    /// Starts the server side of a TLS session, the handshake happens on the first read
+    pub fn session(&self) -> io::Result<ServerConnection> {
+        ServerConnection::new(Arc::clone(&self.config)).map_err(|error| io::Error::other(error.to_string()))
+    }
+
+    pub fn accept<C: Connection>(&self, connection: C) -> io::Result<TlsConnection<C>> {
+        Ok(TlsConnection {
+            stream: StreamOwned::new(self.session()?, connection),
+        })
+    }
+}
+
+/// A connection which is encrypted with TLS.
+pub struct TlsConnection<C: Connection> {
+    stream: StreamOwned<ServerConnection, C>,
+}
+
+impl<C: Connection> Read for TlsConnection<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<C: Connection> Write for TlsConnection<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<C: Connection> Connection for TlsConnection<C> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.sock.peer_addr()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.sock.set_write_timeout(timeout)
+    }
+
+    fn close(self) {
+        // Tell the client the response is complete before the socket goes away
+        let StreamOwned { mut conn, mut sock } = self.stream;
+        conn.send_close_notify();
+        while conn.wants_write() {
+            if conn.write_tls(&mut sock).is_err() {
+                break;
+            }
+        }
+        sock.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use rustls::client::Resumption;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    /// A CA which signs the server certificates, the test clients trust only this CA
+    struct TestCa {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl TestCa {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            TestCa {
+                cert: params.self_signed(&key).unwrap(),
+                key,
+            }
+        }
+
+        /// Writes a certificate for the names and its key to `dir`, returns its DER to compare with
+        fn issue(&self, dir: &Path, file_name: &str, names: &[&str]) -> Vec<u8> {
+            let key = KeyPair::generate().unwrap();
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            let cert = CertificateParams::new(names).unwrap().signed_by(&key, &self.cert, &self.key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", file_name)), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}.key", file_name)), key.serialize_pem()).unwrap();
+            cert.der().to_vec()
+        }
+
+        fn client_config(&self, alpn: &[&str]) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.cert.der().clone()).unwrap();
+            let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            // A resumed session would show the certificate of the first handshake again
+            config.resumption = Resumption::disabled();
+            config.alpn_protocols = alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+            Arc::new(config)
+        }
+    }
+
+    /// A directory of its own for every test, removed again when the test ends
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("tls-test-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn files(&self, file_name: &str, server_names: &[&str]) -> CertificateFiles {
+            CertificateFiles {
+                server_names: server_names.iter().map(|name| name.to_string()).collect(),
+                cert: self.0.join(format!("{}.pem", file_name)),
+                key: self.0.join(format!("{}.key", file_name)),
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Runs a handshake in memory, returns the server certificate and the negotiated protocol
+    fn handshake(
+        acceptor: &TlsAcceptor,
+        config: Arc<ClientConfig>,
+        name: &str,
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>), rustls::Error> {
+        let server_name = ServerName::try_from(name.to_string()).unwrap();
+        let mut client = ClientConnection::new(config, server_name)?;
+        let mut server = acceptor.session().unwrap();
+
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buffer = Vec::new();
+            client.write_tls(&mut buffer).unwrap();
+            server.read_tls(&mut buffer.as_slice()).unwrap();
+            let server_result = server.process_new_packets();
+
+            // The server's alert still goes to the client
+            buffer.clear();
+            server.write_tls(&mut buffer).unwrap();
+            client.read_tls(&mut buffer.as_slice()).unwrap();
+            server_result?;
+            client.process_new_packets()?;
+        }
+
+        let certificate = client.peer_certificates().unwrap()[0].to_vec();
+        Ok((certificate, server.alpn_protocol().map(<[u8]>::to_vec)))
+    }
+
+    #[test]
+    fn selects_the_certificate_by_server_name() {
+        let dir = TempDir::new("sni");
+        let ca = TestCa::new();
+        let default = ca.issue(&dir.0, "default", &["localhost", "other.test"]);
+        let api = ca.issue(&dir.0, "api", &["api.test"]);
+        let wildcard = ca.issue(&dir.0, "wildcard", &["*.apps.test"]);
+
+        let acceptor = TlsAcceptor::new(
+            dir.files("default", &[]),
+            vec![dir.files("api", &["api.test"]), dir.files("wildcard", &["*.Apps.test"])],
+        )
+        .unwrap();
+        let config = ca.client_config(&[]);
+
+        for (name, expected) in [
+            ("api.test", &api),
+            ("API.test", &api),
+            ("one.apps.test", &wildcard),
+            ("two.APPS.test", &wildcard),
+            ("other.test", &default),
+            ("localhost", &default),
+        ] {
+            let (certificate, _) = handshake(&acceptor, Arc::clone(&config), name).unwrap();
+            assert!(certificate == *expected, "wrong certificate for {}", name);
+        }
+
+        // The wildcard covers a single level, the default certificate does not fit
+        assert!(handshake(&acceptor, Arc::clone(&config), "a.b.apps.test").is_err());
+        assert!(handshake(&acceptor, config, "apps.test").is_err());
+    }
+
+    #[test]
+    fn clients_without_sni_get_the_default_certificate() {
+        let dir = TempDir::new("no-sni");
+        let ca = TestCa::new();
+        let default = ca.issue(&dir.0, "default", &["api.test"]);
+        ca.issue(&dir.0, "api", &["api.test"]);
+
+        let acceptor = TlsAcceptor::new(dir.files("default", &[]), vec![dir.files("api", &["api.test"])]).unwrap();
+        let mut config = (*ca.client_config(&[])).clone();
+        config.enable_sni = false;
+
+        let (certificate, _) = handshake(&acceptor, Arc::new(config), "api.test").unwrap();
+        assert!(certificate == default);
+    }
+
+    #[test]
+    fn negotiates_h2_before_http_1_1() {
+        let dir = TempDir::new("alpn");
+        let ca = TestCa::new();
+        ca.issue(&dir.0, "default", &["localhost"]);
+        let acceptor = TlsAcceptor::new(dir.files("default", &[]), Vec::new()).unwrap();
+
+        for (offered, expected) in [
+            (&["http/1.1", "h2"][..], Some(&b"h2"[..])),
+            (&["h2"], Some(b"h2")),
+            (&["http/1.1"], Some(b"http/1.1")),
+            (&[], None),
+        ] {
+            let (_, protocol) = handshake(&acceptor, ca.client_config(offered), "localhost").unwrap();
+            assert_eq!(protocol.as_deref(), expected, "offered {:?}", offered);
+        }
+
+        // A client which only speaks other protocols is refused
+        assert!(handshake(&acceptor, ca.client_config(&["spdy/3"]), "localhost").is_err());
+    }
+
+    #[test]
+    fn reload_picks_up_new_certificates() {
+        let dir = TempDir::new("reload");
+        let ca = TestCa::new();
+        let first = ca.issue(&dir.0, "default", &["localhost"]);
+        let acceptor = TlsAcceptor::new(dir.files("default", &[]), Vec::new()).unwrap();
+        let config = ca.client_config(&[]);
+        assert!(handshake(&acceptor, Arc::clone(&config), "localhost").unwrap().0 == first);
+
+        // Files replaced on disk are only used after the reload
+        let second = ca.issue(&dir.0, "default", &["localhost"]);
+        assert!(handshake(&acceptor, Arc::clone(&config), "localhost").unwrap().0 == first);
+        acceptor.reload().unwrap();
+        assert!(handshake(&acceptor, Arc::clone(&config), "localhost").unwrap().0 == second);
+
+        // A broken file fails the reload and keeps the loaded certificate
+        std::fs::write(dir.0.join("default.key"), "not a key").unwrap();
+        assert!(acceptor.reload().is_err());
+        assert!(handshake(&acceptor, config, "localhost").unwrap().0 == second);
+    }
+
+    #[test]
+    fn rejects_missing_and_mismatched_files() {
+        let dir = TempDir::new("invalid");
+        let ca = TestCa::new();
+        ca.issue(&dir.0, "first", &["localhost"]);
+        ca.issue(&dir.0, "second", &["localhost"]);
+
+        assert!(TlsAcceptor::new(dir.files("missing", &[]), Vec::new()).is_err());
+
+        let mismatched = CertificateFiles {
+            server_names: Vec::new(),
+            cert: dir.0.join("first.pem"),
+            key: dir.0.join("second.key"),
+        };
+        assert!(TlsAcceptor::new(mismatched, Vec::new()).is_err());
+
+        std::fs::write(dir.0.join("empty.pem"), "").unwrap();
+        let empty = CertificateFiles {
+            server_names: Vec::new(),
+            cert: dir.0.join("empty.pem"),
+            key: dir.0.join("first.key"),
+        };
+        let error = TlsAcceptor::new(empty, Vec::new()).err().unwrap();
+        assert!(error.to_string().starts_with("No certificate found"), "{}", error);
+    }
+}
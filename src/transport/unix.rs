@@ -0,0 +1,161 @@
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::transport::{Connection, FileMode};
+
+/// Binds a Unix domain socket at `path` and applies the file permissions.
+/// A socket file left behind by a crashed server is removed first,
+/// a socket which still accepts connections is left alone.
+pub fn bind_unix(path: &Path, mode: FileMode) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+
+    // Bind in a directory only we can enter, so nobody connects before the permissions are set
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display()))
+    })?;
+    let private_dir = path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+    let result = bind_private(&private_dir.join(file_name), path, mode);
+    let _ = fs::remove_dir_all(&private_dir);
+    result
+}
+
+/// Binds at `private_path`, restricts who may connect and moves the socket to `path`
+fn bind_private(private_path: &Path, path: &Path, mode: FileMode) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(private_path)?;
+
+    // Restrict who may connect, e.g. only the reverse proxy's group
+    fs::set_permissions(private_path, Permissions::from_mode(mode.0))?;
+    fs::rename(private_path, path)?;
+
+    Ok(listener)
+}
+
+/// Removes the socket file at `path` if no server is listening on it anymore
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    // Never delete a regular file by accident
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("Another server is listening on {}", path.display()),
+        )),
+        Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+            println!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Listener on a Unix domain socket, the socket file is removed when it is closed.
+pub struct UnixSocketListener {
+    inner: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    pub fn bind(path: &Path, mode: FileMode) -> io::Result<Self> {
+        let inner = bind_unix(path, mode)?;
+        Ok(UnixSocketListener {
+            inner,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Blocks until a client connects.
+    pub fn accept(&self) -> io::Result<UnixConnection> {
+        let (stream, _) = self.inner.accept()?;
+        Ok(UnixConnection { stream })
+    }
+
+    /// Stops listening and removes the socket file.
+    pub fn close(self) {
+        drop(self.inner);
+        if let Err(error) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove socket {}: {}", self.path.display(), error);
+        }
+    }
+}
+
+/// Client connection accepted by a `UnixSocketListener`.
+pub struct UnixConnection {
+    stream: UnixStream,
+}
+
+impl Read for UnixConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for UnixConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Connection for UnixConnection {
+    /// Unix socket clients have no IP address
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn close(self) {
+        // Ignore the error, the peer may already be gone
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_socket_gets_its_permissions_before_it_appears() {
+        let dir = std::env::temp_dir().join(format!("unix-socket-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+
+        let listener = bind_unix(&path, FileMode(0o660)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // Only the socket is left, and it accepts connections at its final path
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+
+        drop(listener);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
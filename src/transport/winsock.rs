@@ -0,0 +1,291 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::raw::c_int;
+use std::sync::Arc;
+use std::time::Duration;
+use winapi::shared::ws2def::{AF_INET, AF_INET6, IPPROTO_IPV6, SOCKADDR, SOCKADDR_IN, SOCKADDR_STORAGE_LH, SOCK_STREAM, SOL_SOCKET, SO_RCVTIMEO, SO_SNDTIMEO};
+use winapi::shared::ws2ipdef::{IPV6_V6ONLY, SOCKADDR_IN6};
+use winapi::um::winsock2::{accept, bind, closesocket, listen, recv, send, setsockopt, socket, WSACleanup, WSAGetLastError, WSAStartup, INVALID_SOCKET, SOCKET, SOCKET_ERROR, WSADATA};
+use crate::transport::std_net::canonical_addr;
+use crate::transport::{Connection, ListenOptions, Listener};
+
+fn htons(host_short: u16) -> u16 {
+    host_short.to_be()
+}
+
+/// Converts the last win socket error into an io error
+fn last_error() -> io::Error {
+    io::Error::from_raw_os_error(unsafe { WSAGetLastError() })
+}
+
+/// Fills a socket address structure for bind, returns it with its used length
+fn to_raw_addr(addr: &SocketAddr) -> (SOCKADDR_STORAGE_LH, c_int) {
+    unsafe {
+        let mut storage: SOCKADDR_STORAGE_LH = std::mem::zeroed();
+
+        match addr {
+            SocketAddr::V4(addr) => {
+                let raw = &mut *(&mut storage as *mut SOCKADDR_STORAGE_LH as *mut SOCKADDR_IN);
+                raw.sin_family = AF_INET as u16;
+                raw.sin_port = htons(addr.port());
+
+                // Assign the address in network byte order to the S_addr field within the union
+                *raw.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(addr.ip().octets());
+                (storage, std::mem::size_of::<SOCKADDR_IN>() as c_int)
+            }
+            SocketAddr::V6(addr) => {
+                let raw = &mut *(&mut storage as *mut SOCKADDR_STORAGE_LH as *mut SOCKADDR_IN6);
+                raw.sin6_family = AF_INET6 as u16;
+                raw.sin6_port = htons(addr.port());
+                raw.sin6_flowinfo = addr.flowinfo();
+                *raw.sin6_addr.u.Byte_mut() = addr.ip().octets();
+                *raw.u.sin6_scope_id_mut() = addr.scope_id();
+                (storage, std::mem::size_of::<SOCKADDR_IN6>() as c_int)
+            }
+        }
+    }
+}
+
+/// Reads the socket address filled in by accept
+fn from_raw_addr(storage: &SOCKADDR_STORAGE_LH) -> Option<SocketAddr> {
+    unsafe {
+        match storage.ss_family as c_int {
+            AF_INET => {
+                let raw = &*(storage as *const SOCKADDR_STORAGE_LH as *const SOCKADDR_IN);
+
+                // Both the address and the port are in network byte order
+                let ip = Ipv4Addr::from(raw.sin_addr.S_un.S_addr().to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(raw.sin_port))))
+            }
+            AF_INET6 => {
+                let raw = &*(storage as *const SOCKADDR_STORAGE_LH as *const SOCKADDR_IN6);
+                let ip = Ipv6Addr::from(*raw.sin6_addr.u.Byte());
+                let addr = SocketAddrV6::new(ip, u16::from_be(raw.sin6_port), raw.sin6_flowinfo, *raw.u.sin6_scope_id());
+                Some(canonical_addr(SocketAddr::V6(addr)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Keeps win socket initialized, cleaned up once the listener and all its connections are dropped
+struct WinSock;
+
+impl WinSock {
+    fn start() -> io::Result<Arc<WinSock>> {
+        let mut wsa_data: WSADATA = unsafe { std::mem::zeroed() };
+
+        // If win socket startup failed
+        if unsafe { WSAStartup(0x202, &mut wsa_data) } != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "Failed to initialize win socket"));
+        }
+
+        Ok(Arc::new(WinSock))
+    }
+}
+
+impl Drop for WinSock {
+    fn drop(&mut self) {
+        unsafe {
+            WSACleanup();
+        }
+    }
+}
+
+/// Listener based on the raw Windows Sockets API.
+pub struct WinSockListener {
+    sock_fd: SOCKET,
+    winsock: Arc<WinSock>,
+}
+
+impl Listener for WinSockListener {
+    type Connection = WinSockConnection;
+
+    fn listen(addr: SocketAddr, options: &ListenOptions) -> io::Result<Self> {
+        unsafe {
+            // Initialize win socket, dropping the guard on an error cleans it up again
+            let winsock = WinSock::start()?;
+
+            // Create a socket of the address family
+            let family = if addr.is_ipv6() { AF_INET6 } else { AF_INET };
+            let sock_fd = socket(family, SOCK_STREAM, 0);
+
+            // If it is an invalid socket
+            if sock_fd == INVALID_SOCKET {
+                return Err(last_error());
+            }
+
+            // From here on dropping the listener closes the socket
+            let listener = WinSockListener { sock_fd, winsock };
+
+            // Windows defaults to IPv6 only, turn it off for dual-stack
+            if addr.is_ipv6() {
+                let ipv6_only = options.ipv6_only as u32;
+                let result = setsockopt(
+                    sock_fd,
+                    IPPROTO_IPV6 as c_int,
+                    IPV6_V6ONLY,
+                    &ipv6_only as *const u32 as *const winapi::ctypes::c_char,
+                    std::mem::size_of::<u32>() as c_int,
+                );
+                if result == SOCKET_ERROR {
+                    return Err(last_error());
+                }
+            }
+
+            // Bind the socket file descriptor to the server address
+            let (server_addr, addr_len) = to_raw_addr(&addr);
+            let bind_result = bind(
+                sock_fd,
+                &server_addr as *const SOCKADDR_STORAGE_LH as *const SOCKADDR,
+                addr_len,
+            );
+
+            // If the bind result is a socket error
+            if bind_result == SOCKET_ERROR {
+                return Err(last_error());
+            }
+
+            // Listen for incoming connections
+            if listen(sock_fd, options.backlog) == SOCKET_ERROR {
+                return Err(last_error());
+            }
+
+            Ok(listener)
+        }
+    }
+
+    fn accept(&self) -> io::Result<WinSockConnection> {
+        unsafe {
+            let mut client_addr: SOCKADDR_STORAGE_LH = std::mem::zeroed();
+            let mut addr_len: c_int = std::mem::size_of::<SOCKADDR_STORAGE_LH>() as c_int;
+            let client_fd = accept(
+                self.sock_fd,
+                &mut client_addr as *mut SOCKADDR_STORAGE_LH as *mut SOCKADDR,
+                &mut addr_len as *mut c_int,
+            );
+
+            // If the client file descriptor is invalid
+            if client_fd == INVALID_SOCKET {
+                return Err(last_error());
+            }
+
+            Ok(WinSockConnection {
+                client_fd,
+                peer_addr: from_raw_addr(&client_addr),
+                _winsock: Arc::clone(&self.winsock),
+            })
+        }
+    }
+
+    fn close(self) {
+        // The socket is closed on drop, win socket stays up for accepted connections
+        drop(self);
+    }
+}
+
+impl Drop for WinSockListener {
+    fn drop(&mut self) {
+        unsafe {
+            closesocket(self.sock_fd);
+        }
+    }
+}
+
+/// Client connection accepted by a `WinSockListener`.
+pub struct WinSockConnection {
+    client_fd: SOCKET,
+    peer_addr: Option<SocketAddr>,
+    // Dropped after the socket is closed, connections still draining keep win socket up
+    _winsock: Arc<WinSock>,
+}
+
+impl Read for WinSockConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let bytes_read = unsafe {
+            recv(self.client_fd, buf.as_mut_ptr() as *mut winapi::ctypes::c_char, len, 0)
+        };
+
+        // If recv failed
+        if bytes_read == SOCKET_ERROR {
+            return Err(last_error());
+        }
+
+        Ok(bytes_read as usize)
+    }
+}
+
+impl Write for WinSockConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let bytes_sent = unsafe {
+            send(self.client_fd, buf.as_ptr() as *const winapi::ctypes::c_char, len, 0)
+        };
+
+        // If send failed
+        if bytes_sent == SOCKET_ERROR {
+            return Err(last_error());
+        }
+
+        Ok(bytes_sent as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // send does not buffer, nothing to flush
+        Ok(())
+    }
+}
+
+impl WinSockConnection {
+    /// Sets SO_RCVTIMEO or SO_SNDTIMEO, both take milliseconds as a DWORD and 0 blocks forever
+    fn set_timeout(&mut self, option: c_int, timeout: Option<Duration>) -> io::Result<()> {
+        let millis: u32 = match timeout {
+            Some(timeout) => timeout.as_millis().clamp(1, u32::MAX as u128) as u32,
+            None => 0,
+        };
+
+        let result = unsafe {
+            setsockopt(
+                self.client_fd,
+                SOL_SOCKET,
+                option,
+                &millis as *const u32 as *const winapi::ctypes::c_char,
+                std::mem::size_of::<u32>() as c_int,
+            )
+        };
+
+        if result == SOCKET_ERROR {
+            return Err(last_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Connection for WinSockConnection {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_timeout(SO_RCVTIMEO, timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_timeout(SO_SNDTIMEO, timeout)
+    }
+
+    fn close(self) {
+        // The client socket is closed on drop
+        drop(self);
+    }
+}
+
+impl Drop for WinSockConnection {
+    fn drop(&mut self) {
+        unsafe {
+            closesocket(self.client_fd);
+        }
+    }
+}